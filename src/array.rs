@@ -0,0 +1,183 @@
+use crate::{
+    clipboard::placed,
+    editing::{shape_count_description, AtomicAction, EditEvent, PendingSelection, Selected},
+    grid::Grid,
+    import::Net,
+    index::{IndexedShape, ShapeIndex},
+    shapes::{CellElem, Path, Poly, Rect},
+    InLayer,
+};
+
+use bevy::prelude::*;
+
+use layout21::raw::LayerPurpose;
+use rstar::Envelope;
+
+pub struct ArrayPlugin;
+
+impl Plugin for ArrayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ArraySelectionEvent>()
+            .add_system(array_selection_system);
+    }
+}
+
+/// Arrays creating more shapes than this are refused rather than freezing the editor.
+pub const MAX_ARRAY_SHAPES: usize = 100_000;
+
+/// A step-and-repeat of the selection, `nx` columns by `ny` rows, with the
+/// selection itself in the bottom left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArrayParams {
+    pub nx: u32,
+    pub ny: u32,
+    /// Distance between columns and rows in database units.
+    pub pitch: IVec2,
+    /// Use the size of the selection as the pitch, so the copies abut.
+    pub abut: bool,
+}
+
+impl Default for ArrayParams {
+    fn default() -> Self {
+        Self {
+            nx: 2,
+            ny: 1,
+            pitch: IVec2::new(1000, 1000),
+            abut: false,
+        }
+    }
+}
+
+impl ArrayParams {
+    /// Offset of every copy from the selection, leaving out the selection itself.
+    pub fn offsets(&self, pitch: IVec2) -> Vec<IVec2> {
+        (0..self.ny as i32)
+            .flat_map(|j| (0..self.nx as i32).map(move |i| IVec2::new(i, j) * pitch))
+            .filter(|offset| *offset != IVec2::ZERO)
+            .collect()
+    }
+}
+
+/// Copy the selected shapes into an array.
+#[derive(Debug, Clone, Copy)]
+pub struct ArraySelectionEvent(pub ArrayParams);
+
+pub fn array_selection_system(
+    grid: Res<Grid>,
+    mut shape_index: ResMut<ShapeIndex>,
+    mut pending_selection: ResMut<PendingSelection>,
+    selected_q: Query<
+        (
+            &InLayer,
+            &Net,
+            &Transform,
+            Option<&CellElem>,
+            Option<&Rect>,
+            Option<&Poly>,
+            Option<&Path>,
+        ),
+        With<Selected>,
+    >,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut array_ev: EventReader<ArraySelectionEvent>,
+) {
+    for ArraySelectionEvent(params) in array_ev.iter() {
+        // as with duplicate, shapes of instances become shapes of the loaded cell
+        let shapes = selected_q
+            .iter()
+            .filter_map(|(layer, net, t, elem, rect, poly, path)| {
+                let elem = elem.cloned().unwrap_or(CellElem {
+                    purpose: LayerPurpose::Drawing,
+                });
+                IndexedShape::from_components(**layer, net, Some(&elem), None, rect, poly, path, t)
+            })
+            .collect::<Vec<IndexedShape>>();
+
+        if shapes.is_empty() {
+            continue;
+        }
+
+        let pitch = if params.abut {
+            let envelope = shapes
+                .iter()
+                .map(|shape| shape.geometry.envelope())
+                .reduce(|a, b| a.merged(&b))
+                .unwrap();
+            let ([x0, y0], [x1, y1]) = (envelope.lower(), envelope.upper());
+            IVec2::new((x1 - x0) as i32, (y1 - y0) as i32)
+        } else {
+            // on the grid, so copies of shapes on the grid stay on it
+            IVec2::new(grid.snap_i32(params.pitch.x), grid.snap_i32(params.pitch.y))
+        };
+
+        let offsets = params.offsets(pitch);
+        if offsets.is_empty() {
+            continue;
+        }
+
+        let count = offsets.len() * shapes.len();
+        if count > MAX_ARRAY_SHAPES {
+            warn!("Not creating an array of {count} shapes, the limit is {MAX_ARRAY_SHAPES}");
+            continue;
+        }
+
+        let created = offsets
+            .into_iter()
+            .flat_map(|offset| placed(&shapes, offset, &mut shape_index))
+            .collect::<Vec<_>>();
+
+        info!(
+            "Arraying {} shapes {} x {} at a pitch of {pitch}",
+            shapes.len(),
+            params.nx,
+            params.ny
+        );
+
+        // the selection grows to the whole array
+        pending_selection.extend(created.iter().map(|(id, _)| *id));
+
+        edit_ev.send(EditEvent {
+            description: format!(
+                "{} {} × {}",
+                shape_count_description("Array", shapes.len()),
+                params.nx,
+                params.ny
+            ),
+            action: AtomicAction::Create { shapes: created },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArrayParams;
+    use bevy::prelude::IVec2;
+
+    #[test]
+    fn array_offsets() {
+        let params = ArrayParams {
+            nx: 3,
+            ny: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            params.offsets(IVec2::new(100, -50)),
+            vec![
+                IVec2::new(100, 0),
+                IVec2::new(200, 0),
+                IVec2::new(0, -50),
+                IVec2::new(100, -50),
+                IVec2::new(200, -50),
+            ]
+        );
+
+        // a single column and row is just the selection
+        let single = ArrayParams {
+            nx: 1,
+            ny: 1,
+            ..Default::default()
+        };
+        assert!(single.offsets(IVec2::new(100, 100)).is_empty());
+    }
+}
@@ -0,0 +1,413 @@
+use crate::{
+    editing::{shape_count_description, AtomicAction, EditEvent, PendingSelection, Selected},
+    grid::Grid,
+    import::{LayerColors, Layers, Net, VlsirCell, VlsirLib},
+    index::{IndexedShape, ShapeGeometry, ShapeId, ShapeIndex},
+    shapes::{CellElem, Path, Poly, Rect},
+    tech::{style_layers, LayerMap},
+    ui::LayersUIState,
+    CursorWorldPos, InLayer,
+};
+
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_egui::EguiContext;
+
+use layout21::raw::{self, LayerPurpose};
+use rstar::Envelope;
+
+pub struct ClipboardPlugin;
+
+impl Plugin for ClipboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Clipboard::default())
+            .add_event::<ClipboardEvent>()
+            .add_system(clipboard_key_shortcut_system)
+            .add_system(clipboard_system);
+    }
+}
+
+/// How far duplicated shapes are placed from the originals, in grid pitches.
+pub const DUPLICATE_OFFSET_PITCHES: i32 = 10;
+
+/// Copied shapes, kept as records of their layer, net and geometry rather than as
+/// entities, so they can be pasted into any cell of any opened or new library.
+#[derive(Debug, Clone)]
+pub struct Clipboard {
+    pub shapes: Vec<IndexedShape>,
+    /// The library layers the shapes are on, added to libraries they are pasted into
+    /// that don't have them.
+    pub layers: Vec<raw::Layer>,
+    /// Size of the database unit of the library the shapes were copied from, so they
+    /// keep their size when pasted into a library with other units.
+    pub nm_per_unit: f64,
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        Self {
+            shapes: vec![],
+            layers: vec![],
+            nm_per_unit: 1.0,
+        }
+    }
+}
+
+impl Clipboard {
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardEvent {
+    Copy,
+    Cut,
+    Paste,
+    Duplicate,
+}
+
+pub fn clipboard_key_shortcut_system(
+    keyboard: Res<Input<KeyCode>>,
+    mut egui_ctx: ResMut<EguiContext>,
+    mut clipboard_ev: EventWriter<ClipboardEvent>,
+) {
+    if !keyboard.pressed(KeyCode::LControl) || egui_ctx.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::C) {
+        clipboard_ev.send(ClipboardEvent::Copy);
+    } else if keyboard.just_pressed(KeyCode::X) {
+        clipboard_ev.send(ClipboardEvent::Cut);
+    } else if keyboard.just_pressed(KeyCode::V) {
+        clipboard_ev.send(ClipboardEvent::Paste);
+    } else if keyboard.just_pressed(KeyCode::D) {
+        clipboard_ev.send(ClipboardEvent::Duplicate);
+    }
+}
+
+/// `shapes` moved by `offset`, as new shapes of the loaded cell.
+pub fn placed(
+    shapes: &[IndexedShape],
+    offset: IVec2,
+    shape_index: &mut ShapeIndex,
+) -> Vec<(ShapeId, IndexedShape)> {
+    shapes
+        .iter()
+        .map(|shape| {
+            let shape = IndexedShape {
+                geometry: shape.geometry.translate(offset.x, offset.y),
+                ..shape.clone()
+            };
+            (shape_index.next_id(), shape)
+        })
+        .collect()
+}
+
+/// `shape` with its coordinates and width multiplied by `factor`, rounded to whole units.
+pub fn scaled(shape: &IndexedShape, factor: f64) -> IndexedShape {
+    let scale = |v: i32| (v as f64 * factor).round() as i32;
+
+    let geometry = match shape
+        .geometry
+        .map_points(|p| IVec2::new(scale(p.x), scale(p.y)))
+    {
+        ShapeGeometry::Path(mut p) => {
+            p.width = ((p.width as f64 * factor).round() as usize).max(1);
+            ShapeGeometry::Path(p)
+        }
+        geometry => geometry,
+    };
+
+    IndexedShape {
+        geometry,
+        ..shape.clone()
+    }
+}
+
+/// The centre of the shapes' bounding box, rounded onto the grid.
+fn grid_center(shapes: &[IndexedShape], grid: &Grid) -> IVec2 {
+    let [x, y] = shapes
+        .iter()
+        .map(|shape| shape.geometry.envelope())
+        .reduce(|a, b| a.merged(&b))
+        .map_or([0, 0], |envelope| envelope.center());
+    IVec2::new(grid.snap_i32(x as i32), grid.snap_i32(y as i32))
+}
+
+/// Add the layers of the clipboard that `lib` doesn't have to it, and style them.
+fn add_missing_layers(
+    lib: &raw::Library,
+    clipboard: &Clipboard,
+    layer_map: &LayerMap,
+    layer_colors: &mut LayerColors,
+    layers: &mut Layers,
+    layer_state: &mut LayersUIState,
+) {
+    let mut added = false;
+
+    {
+        let mut lib_layers = lib.layers.write().unwrap();
+        for layer in clipboard.layers.iter() {
+            if !lib_layers
+                .slots
+                .values()
+                .any(|l| l.layernum == layer.layernum)
+            {
+                info!("Adding layer {} to the library", layer.layernum);
+                lib_layers.add(layer.clone());
+                added = true;
+            }
+        }
+    }
+
+    if added {
+        for (num, layer) in style_layers(lib, layer_map, layer_colors).iter() {
+            if !layers.contains_key(num) {
+                layers.insert(*num, layer.clone());
+            }
+        }
+        // rebuilt from the new layers by the layers widget
+        layer_state.layers = vec![];
+    }
+}
+
+pub fn clipboard_system(
+    mut commands: Commands,
+    mut clipboard: ResMut<Clipboard>,
+    mut pending_selection: ResMut<PendingSelection>,
+    vlsir_lib: Res<VlsirLib>,
+    vlsir_cell: Res<VlsirCell>,
+    (layer_map, mut layer_colors, mut layers, mut layer_state): (
+        Res<LayerMap>,
+        ResMut<LayerColors>,
+        ResMut<Layers>,
+        ResMut<LayersUIState>,
+    ),
+    grid: Res<Grid>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    mut shape_index: ResMut<ShapeIndex>,
+    selected_q: Query<
+        (
+            Entity,
+            &ShapeId,
+            &InLayer,
+            &Net,
+            &Transform,
+            Option<&CellElem>,
+            Option<&Rect>,
+            Option<&Poly>,
+            Option<&Path>,
+        ),
+        With<Selected>,
+    >,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut clipboard_ev: EventReader<ClipboardEvent>,
+) {
+    for ev in clipboard_ev.iter() {
+        // every selected shape, shapes of instances become shapes of the cell they are pasted into
+        let selected = || {
+            selected_q
+                .iter()
+                .filter_map(|(_, id, layer, net, t, elem, rect, poly, path)| {
+                    let elem = elem.cloned().unwrap_or(CellElem {
+                        purpose: LayerPurpose::Drawing,
+                    });
+                    IndexedShape::from_components(
+                        **layer,
+                        net,
+                        Some(&elem),
+                        None,
+                        rect,
+                        poly,
+                        path,
+                        t,
+                    )
+                    .map(|shape| (*id, shape))
+                })
+                .collect::<Vec<(ShapeId, IndexedShape)>>()
+        };
+
+        match ev {
+            ClipboardEvent::Copy | ClipboardEvent::Cut => {
+                let shapes = selected();
+                if shapes.is_empty() {
+                    continue;
+                }
+
+                let nums = shapes
+                    .iter()
+                    .map(|(_, shape)| shape.layer as i16)
+                    .collect::<HashSet<i16>>();
+
+                clipboard.layers = vlsir_lib
+                    .lib
+                    .as_ref()
+                    .map(|lib| {
+                        lib.layers
+                            .read()
+                            .unwrap()
+                            .slots
+                            .values()
+                            .filter(|l| nums.contains(&l.layernum))
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                info!("Copied {} shapes", shapes.len());
+
+                if *ev == ClipboardEvent::Cut {
+                    // shapes flattened out of instances belong to other cells, so are only copied
+                    let cut = selected_q
+                        .iter()
+                        .filter(|(.., elem, _, _, _)| elem.is_some())
+                        .filter_map(|(_, id, ..)| shapes.iter().find(|(s, _)| s == id).cloned())
+                        .collect::<Vec<(ShapeId, IndexedShape)>>();
+
+                    if !cut.is_empty() {
+                        edit_ev.send(EditEvent {
+                            description: shape_count_description("Cut", cut.len()),
+                            action: AtomicAction::Delete { shapes: cut },
+                        });
+                    }
+                }
+
+                clipboard.shapes = shapes.into_iter().map(|(_, shape)| shape).collect();
+                clipboard.nm_per_unit = grid.nm_per_unit;
+            }
+            ClipboardEvent::Paste | ClipboardEvent::Duplicate => {
+                let (lib, _) = match (vlsir_lib.lib.as_ref(), vlsir_cell.index) {
+                    (Some(lib), Some(cell_idx)) => (lib, cell_idx),
+                    _ => {
+                        warn!("No cell loaded to paste into, open or create a library first");
+                        continue;
+                    }
+                };
+
+                let (description, shapes) = if *ev == ClipboardEvent::Paste {
+                    if clipboard.is_empty() {
+                        continue;
+                    }
+
+                    add_missing_layers(
+                        lib,
+                        &clipboard,
+                        &layer_map,
+                        &mut layer_colors,
+                        &mut layers,
+                        &mut layer_state,
+                    );
+
+                    // in the units of the library pasted into
+                    let factor = clipboard.nm_per_unit / grid.nm_per_unit;
+                    let shapes = if factor == 1.0 {
+                        clipboard.shapes.clone()
+                    } else {
+                        info!("Scaling pasted shapes by {factor} to the library's units");
+                        clipboard
+                            .shapes
+                            .iter()
+                            .map(|shape| scaled(shape, factor))
+                            .collect()
+                    };
+
+                    // centred on the cursor
+                    let cursor = grid.snap_vec2(**cursor_world_pos).round().as_ivec2();
+                    let offset = cursor - grid_center(&shapes, &grid);
+
+                    (
+                        shape_count_description("Paste", shapes.len()),
+                        placed(&shapes, offset, &mut shape_index),
+                    )
+                } else {
+                    let shapes = selected()
+                        .into_iter()
+                        .map(|(_, shape)| shape)
+                        .collect::<Vec<IndexedShape>>();
+                    if shapes.is_empty() {
+                        continue;
+                    }
+
+                    let offset = IVec2::splat(DUPLICATE_OFFSET_PITCHES * grid.pitch());
+
+                    (
+                        shape_count_description("Duplicate", shapes.len()),
+                        placed(&shapes, offset, &mut shape_index),
+                    )
+                };
+
+                for (e, ..) in selected_q.iter() {
+                    commands.entity(e).remove::<Selected>();
+                }
+
+                **pending_selection = shapes.iter().map(|(id, _)| *id).collect();
+
+                edit_ev.send(EditEvent {
+                    description,
+                    action: AtomicAction::Create { shapes },
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{grid_center, placed, scaled};
+    use crate::grid::Grid;
+    use crate::index::{test_shape, IndexedShape, ShapeGeometry, ShapeIndex};
+    use crate::shapes::GeoRect;
+    use bevy::prelude::IVec2;
+    use geo::coord;
+    use layout21::raw;
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> IndexedShape {
+        test_shape(ShapeGeometry::Rect(GeoRect::new(
+            coord! { x: x0, y: y0 },
+            coord! { x: x1, y: y1 },
+        )))
+    }
+
+    #[test]
+    fn paste_centred_on_cursor() {
+        let shapes = vec![rect(0, 0, 100, 50), rect(200, 0, 300, 100)];
+        let grid = Grid::default();
+
+        let center = grid_center(&shapes, &grid);
+        assert_eq!(center, IVec2::new(150, 50));
+
+        let mut index = ShapeIndex::default();
+        let pasted = placed(&shapes, IVec2::new(1000, 1000) - center, &mut index);
+
+        // new ids for every pasted shape, the records themselves are unchanged
+        assert_ne!(pasted[0].0, pasted[1].0);
+        assert_eq!(pasted[0].1, rect(850, 950, 950, 1000));
+        assert_eq!(pasted[1].1, rect(1050, 950, 1150, 1050));
+        assert_eq!(shapes[0], rect(0, 0, 100, 50));
+    }
+
+    #[test]
+    fn paste_into_other_units() {
+        // copied from a library in micrometres into one in nanometres
+        assert_eq!(scaled(&rect(0, 0, 2, 1), 1000.0), rect(0, 0, 2000, 1000));
+
+        let path = IndexedShape {
+            geometry: ShapeGeometry::Path(raw::Path {
+                points: vec![raw::Point::new(0, 0), raw::Point::new(1000, 0)],
+                width: 150,
+            }),
+            ..rect(0, 0, 1, 1)
+        };
+        match scaled(&path, 0.001).geometry {
+            ShapeGeometry::Path(p) => {
+                assert_eq!(p.points, vec![raw::Point::new(0, 0), raw::Point::new(1, 0)]);
+                // never narrowed to nothing
+                assert_eq!(p.width, 1);
+            }
+            _ => panic!("expected a path"),
+        }
+    }
+}
@@ -1,15 +1,20 @@
 use crate::{
     get_component_names_for_entity,
-    import::Net,
+    grid::Grid,
+    handles::HandleDrag,
+    import::{Layers, LoadCellEvent, Net, OpenVlsirLibEvent, StreamedShapes, VlsirCell},
+    index::{IndexedShape, ShapeGeometry, ShapeId, ShapeIndex},
     screen_to_world_pos,
-    shapes::{GeoRect, Path, Poly, Rect},
+    shapes::{CellElem, GeoRect, Path, Poly, Rect},
+    tools::ActiveTool,
     CursorWorldPos, InLayer, ALPHA,
 };
 use bevy::{
-    ecs::{archetype::Archetypes, component::Components},
+    ecs::{archetype::Archetypes, component::Components, system::SystemParam},
     prelude::*,
 };
 use bevy_egui::EguiContext;
+use bevy_prototype_lyon::entity;
 use bevy_prototype_lyon::plugin::ShapePlugin;
 use bevy_prototype_lyon::prelude::{
     shapes as lyon_shapes, DrawMode, FillMode, FillOptions, FillRule, GeometryBuilder,
@@ -22,6 +27,8 @@ use lyon_geom::Translation;
 
 use sorted_vec::SortedVec;
 
+use std::collections::HashSet;
+
 pub struct EditingPlugin;
 
 impl Plugin for EditingPlugin {
@@ -30,9 +37,12 @@ impl Plugin for EditingPlugin {
             .insert_resource(ShapeStack::default())
             .insert_resource(UndoRedoHistory::default())
             .insert_resource(PointerInitialPos::default())
+            .insert_resource(PendingSelection::default())
             .add_event::<Interaction>()
             .add_event::<UndoRedoEvent>()
-            .add_event::<PreDragPosEvent>()
+            .add_event::<EditEvent>()
+            .add_event::<ChangeLayerEvent>()
+            .add_event::<PreDragTransformEvent>()
             .add_stage_after(CoreStage::Update, "pointer_events", SystemStage::parallel())
             .add_stage_after("pointer_events", "set_hovered", SystemStage::parallel())
             .add_stage_after("set_hovered", "detect_clicked", SystemStage::parallel())
@@ -53,6 +63,8 @@ impl Plugin for EditingPlugin {
                 "undo_redo_debug",
                 SystemStage::parallel(),
             )
+            .add_system_to_stage("reset_world", reset_undo_redo_history_system)
+            .add_system_to_stage("reset_world", reset_pending_selection_system)
             .add_system_to_stage(CoreStage::Update, cursor_hover_detect_system)
             .add_system_to_stage("transform_at_drag_start", dragged_shape_initial_pos_system)
             .add_system_to_stage("undo_redo_track", undo_redo_tracking_system)
@@ -82,6 +94,9 @@ impl Plugin for EditingPlugin {
             .add_system(print_selected_info_system)
             .add_system(undo_redo_key_combo_system)
             .add_system(undo_redo_system)
+            .add_system(delete_selected_system)
+            .add_system(change_layer_system)
+            .add_system(select_pending_system)
             .add_system_to_stage("undo_redo_debug", debug_undo_redo_system)
             // .add_system(debug_selection_box_components)
             .add_system_to_stage("click_and_drag", click_and_drag_shape_system)
@@ -242,7 +257,7 @@ pub fn cursor_hover_detect_system(
     mut shape_stack: ResMut<ShapeStack>,
     rect_q: Query<(Entity, &LyonPath, &Transform, &InLayer, &Visibility), With<Rect>>,
     poly_q: Query<(Entity, &LyonPath, &Transform, &InLayer, &Visibility), With<Poly>>,
-    path_q: Query<(Entity, &LyonPath, &Transform, &InLayer, &Visibility), With<Path>>,
+    path_q: Query<(Entity, &Path, &Transform, &InLayer, &Visibility)>,
     input_mouse: Res<Input<MouseButton>>,
     selection_box_q: Query<Entity, With<SelectionBox>>,
 ) {
@@ -285,18 +300,42 @@ pub fn cursor_hover_detect_system(
         for (entity, path, transform, layer, vis) in path_q.iter() {
             let layer = **layer;
 
-            let path = path.0.clone().transformed(&Translation::new(
-                transform.translation.x,
-                transform.translation.y,
-            ));
+            // paths are open, so hit test against their stroke instead of their outline
+            let offset = transform.translation.truncate();
 
-            if hit_test_path(&point, path.iter(), FillRule::NonZero, 0.00000001) && vis.is_visible {
+            if path_contains(path, **cursor_pos - offset) && vis.is_visible {
                 shape_stack.stack.insert(Shape { layer, entity });
             }
         }
     }
 }
 
+/// Whether `point` lies within half of the path's width of its centerline.
+pub fn path_contains(path: &Path, point: Vec2) -> bool {
+    let half_width = path.width as f32 / 2.0;
+
+    let points = path
+        .points
+        .iter()
+        .map(|p| Vec2::new(p.x as f32, p.y as f32))
+        .collect::<Vec<Vec2>>();
+
+    if let [p] = points.as_slice() {
+        return point.distance(*p) <= half_width;
+    }
+
+    points.windows(2).any(|segment| {
+        let (a, b) = (segment[0], segment[1]);
+        let ab = b - a;
+        let t = if ab.length_squared() > 0.0 {
+            ((point - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        point.distance(a + ab * t) <= half_width
+    })
+}
+
 pub fn set_hovered_system(
     mut commands: Commands,
     shape_stack: Res<ShapeStack>,
@@ -343,8 +382,9 @@ pub fn cycle_shape_stack_hover_system(
 /// Highlight a shape as Hovered by making it more opaque when the mouse hovers over it.
 pub fn highlight_hovered_system(
     mut hovered_q: Query<(Entity, &mut DrawMode), Added<Hovered>>,
-    mut shape_q: Query<(Entity, &mut DrawMode), Without<Hovered>>,
+    mut shape_q: Query<(Entity, &InLayer, &mut DrawMode), Without<Hovered>>,
     selected_q: Query<Entity, With<Selected>>,
+    layers: Res<Layers>,
     removed_hovered: RemovedComponents<Hovered>,
 ) {
     'outer_1: for (hovered_e, mut draw) in hovered_q.iter_mut() {
@@ -362,7 +402,7 @@ pub fn highlight_hovered_system(
     }
 
     'outer_2: for entity in removed_hovered.iter() {
-        if let Ok((shape_e, mut draw)) = shape_q.get_mut(entity) {
+        if let Ok((shape_e, layer, mut draw)) = shape_q.get_mut(entity) {
             if let DrawMode::Outlined {
                 ref mut fill_mode, ..
             } = *draw
@@ -372,7 +412,7 @@ pub fn highlight_hovered_system(
                         continue 'outer_2;
                     }
                 }
-                fill_mode.color = *fill_mode.color.set_a(ALPHA);
+                fill_mode.color = *fill_mode.color.set_a(layer_fill_alpha(&layers, layer));
             }
         }
     }
@@ -384,12 +424,28 @@ pub fn select_clicked_system(
     selected_q: Query<Entity, With<Selected>>,
     dragging_q: Query<Entity, With<Dragging>>,
     keyboard: Res<Input<KeyCode>>,
+    tool: Res<ActiveTool>,
+    handle_drag: Res<HandleDrag>,
+    mut pending_selection: ResMut<PendingSelection>,
     mut interaction_ev: EventReader<Interaction>,
 ) {
     use crate::editing::Interaction::*;
 
+    // clicks and drags belong to the drawing tools while one is active,
+    // and to the handle being dragged while there is one
+    if *tool != ActiveTool::Select || handle_drag.is_some() {
+        interaction_ev.iter().for_each(drop);
+        return;
+    }
+
     for &ev in interaction_ev.iter() {
         info!("EVENT: {ev:?}");
+
+        // a new selection replaces shapes still waiting to be selected
+        if ev == Click && !keyboard.pressed(KeyCode::LAlt) && !pending_selection.is_empty() {
+            pending_selection.clear();
+        }
+
         if hovered_q.is_empty() {
             for selected in selected_q.iter() {
                 info!("Nothing Hovered, removing Selected from: {selected:?}");
@@ -535,13 +591,18 @@ fn spawn_despawn_selection_box_system(
     mut interaction_ev: EventReader<Interaction>,
     selection_box_q: Query<Entity, With<SelectionBox>>,
     selected_q: Query<Entity, With<Selected>>,
+    tool: Res<ActiveTool>,
+    handle_drag: Res<HandleDrag>,
 ) {
     use crate::editing::Interaction::*;
 
     for &ev in interaction_ev.iter() {
         match ev {
             DragStart => {
-                if keyboard.pressed(KeyCode::LAlt) {
+                if keyboard.pressed(KeyCode::LAlt)
+                    && *tool == ActiveTool::Select
+                    && handle_drag.is_none()
+                {
                     info!("Spawn SelectionBox");
                     commands.spawn().insert(SelectionBox);
                     // Remove selected from all currently selected entities when a SelectionBox starts
@@ -562,6 +623,29 @@ fn spawn_despawn_selection_box_system(
     }
 }
 
+/// Rectangle dragged out from `from` to `to` in world coordinates, drawn on top of
+/// every layer, along with its extent rounded to the integer grid.
+pub fn rubber_band_rect(from: Vec2, to: Vec2, draw_mode: DrawMode) -> (Rect, entity::ShapeBundle) {
+    let delta = to - from;
+
+    let lyon_rect = lyon_shapes::Rectangle {
+        origin: lyon_shapes::RectangleOrigin::BottomLeft,
+        extents: (delta.x, delta.y).into(),
+    };
+
+    let transform = Transform::from_translation(Vec3::new(from.x, from.y, 800.0));
+
+    let rect = Rect(GeoRect::new(
+        (to.x as i32, to.y as i32),
+        (from.x as i32, from.y as i32),
+    ));
+
+    (
+        rect,
+        GeometryBuilder::build_as(&lyon_rect, draw_mode, transform),
+    )
+}
+
 pub fn draw_selection_box_system(
     mut commands: Commands,
     windows: Res<Windows>,
@@ -587,45 +671,24 @@ pub fn draw_selection_box_system(
     };
 
     if let Ok(e) = new_selection_box_q.get_single() {
-        let lyon_rect = lyon_shapes::Rectangle {
-            origin: lyon_shapes::RectangleOrigin::BottomLeft,
-            extents: (0.0, 0.0).into(),
-        };
-
         *initial_world_pos = screen_to_world_pos(&windows, &camera_q, pointer_initial_pos.unwrap());
-        let transform =
-            Transform::from_translation(Vec3::new(initial_world_pos.x, initial_world_pos.y, 800.0));
 
-        let selection_box = GeometryBuilder::build_as(&lyon_rect, draw_mode, transform);
+        let (rect, selection_box) =
+            rubber_band_rect(*initial_world_pos, *initial_world_pos, draw_mode);
         commands
             .entity(e)
             .insert_bundle(selection_box)
-            .insert(Rect(GeoRect::new(
-                (initial_world_pos.x as i32, initial_world_pos.y as i32),
-                (initial_world_pos.x as i32, initial_world_pos.y as i32),
-            )))
+            .insert(rect)
             .insert(DeltaWidthHeight((0, 0).into()));
     }
 
     if let Ok((sb_e, mut rect, mut delta_wh)) = selection_box_q.get_single_mut() {
-        let delta = **cursor_world_pos - *initial_world_pos;
-        let new_rect = Rect(GeoRect::new(
-            (cursor_world_pos.x as i32, cursor_world_pos.y as i32),
-            (initial_world_pos.x as i32, initial_world_pos.y as i32),
-        ));
+        let (new_rect, selection_box) =
+            rubber_band_rect(*initial_world_pos, **cursor_world_pos, draw_mode);
         delta_wh.x = new_rect.width() - rect.width();
         delta_wh.y = new_rect.height() - rect.height();
         *rect = new_rect;
 
-        let lyon_rect = lyon_shapes::Rectangle {
-            origin: lyon_shapes::RectangleOrigin::BottomLeft,
-            extents: (delta.x, delta.y).into(),
-        };
-
-        let transform =
-            Transform::from_translation(Vec3::new(initial_world_pos.x, initial_world_pos.y, 800.0));
-
-        let selection_box = GeometryBuilder::build_as(&lyon_rect, draw_mode, transform);
         commands.entity(sb_e).insert_bundle(selection_box);
     }
 }
@@ -699,13 +762,22 @@ pub fn highlight_selected_sytem(mut curr_selected_q: Query<&mut DrawMode, With<S
     }
 }
 
+/// Opacity of the fill of an unhighlighted shape on `layer`.
+fn layer_fill_alpha(layers: &Layers, layer: &InLayer) -> f32 {
+    layers
+        .get(&**layer)
+        .map(|layer| layer.fill_alpha())
+        .unwrap_or(ALPHA)
+}
+
 pub fn unhighlight_deselected_system(
     query: Query<Entity>,
-    mut draw_q: Query<&mut DrawMode>,
+    mut draw_q: Query<(&InLayer, &mut DrawMode)>,
+    layers: Res<Layers>,
     deselected: RemovedComponents<Selected>,
 ) {
     for entity in deselected.iter() {
-        if let Ok(mut draw_mode) = draw_q.get_mut(entity) {
+        if let Ok((layer, mut draw_mode)) = draw_q.get_mut(entity) {
             if let DrawMode::Outlined {
                 ref mut fill_mode, ..
             } = *draw_mode
@@ -713,7 +785,7 @@ pub fn unhighlight_deselected_system(
                 if query.get_component::<Hovered>(entity).is_ok() {
                     fill_mode.color = *fill_mode.color.set_a(0.5);
                 } else {
-                    fill_mode.color = *fill_mode.color.set_a(ALPHA);
+                    fill_mode.color = *fill_mode.color.set_a(layer_fill_alpha(&layers, layer));
                 }
             }
         }
@@ -738,19 +810,24 @@ pub fn print_selected_info_system(query: Query<(Entity, &Net, &InLayer), Added<S
     }
 }
 
+/// Where a shape was when it started being dragged, sent for every dragged shape.
 #[derive(Debug, Clone, Copy)]
-pub struct PreDragPosEvent {
+pub struct PreDragTransformEvent {
     pub entity: Entity,
-    pub pos: Vec2,
+    pub id: ShapeId,
+    pub transform: Transform,
 }
 
 fn dragged_shape_initial_pos_system(
-    transform_q: Query<(Entity, &Transform), Added<Dragging>>,
-    mut pre_drag_pos_ev: EventWriter<PreDragPosEvent>,
+    transform_q: Query<(Entity, &ShapeId, &Transform), Added<Dragging>>,
+    mut pre_drag_transform_ev: EventWriter<PreDragTransformEvent>,
 ) {
-    if let Some((entity, t)) = transform_q.iter().nth(0) {
-        let pos = t.translation.truncate();
-        pre_drag_pos_ev.send(PreDragPosEvent { entity, pos });
+    for (entity, id, transform) in transform_q.iter() {
+        pre_drag_transform_ev.send(PreDragTransformEvent {
+            entity,
+            id: *id,
+            transform: *transform,
+        });
     }
 }
 
@@ -758,6 +835,7 @@ pub fn click_and_drag_shape_system(
     input_mouse: Res<Input<MouseButton>>,
     mut dragging_q: Query<&mut Transform, With<Dragging>>,
     cursor_world_pos: Res<CursorWorldPos>,
+    grid: Res<Grid>,
     mut last_pos: Local<Option<Vec2>>,
 ) {
     if input_mouse.pressed(MouseButton::Left) {
@@ -770,6 +848,9 @@ pub fn click_and_drag_shape_system(
 
         for mut transform in dragging_q.iter_mut() {
             transform.translation += delta;
+            // keep the shapes moving in whole grid steps
+            transform.translation.x = grid.snap(transform.translation.x);
+            transform.translation.y = grid.snap(transform.translation.y);
         }
 
         *last_pos = Some(current_pos);
@@ -781,57 +862,108 @@ pub fn click_and_drag_shape_system(
 #[derive(Debug, Default, Clone)]
 pub struct UndoRedoHistory {
     pub offset: usize,
-    pub actions: Vec<AtomicAction>,
+    pub actions: Vec<HistoryEntry>,
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct AtomicAction {
-    pub action: TranslateAction,
-    pub entities: Vec<Entity>,
+/// An action in the undo/redo history along with what it is called in the Edit menu
+/// and the History window.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub description: String,
+    pub action: AtomicAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum AtomicAction {
+    /// Shapes dragged to a new position, each with its transform before and after.
+    Translate {
+        moves: Vec<(ShapeId, Transform, Transform)>,
+    },
+    /// Shapes added to the cell.
+    Create {
+        shapes: Vec<(ShapeId, IndexedShape)>,
+    },
+    /// Shapes removed from the cell, as they were when they were removed.
+    Delete {
+        shapes: Vec<(ShapeId, IndexedShape)>,
+    },
+    /// Shapes changed in place, each with how it was before and after the change.
+    Modify {
+        shapes: Vec<(ShapeId, IndexedShape, IndexedShape)>,
+    },
+    /// Actions undone and redone together, in order.
+    Compound { actions: Vec<AtomicAction> },
 }
 
-#[derive(Deref, DerefMut, Debug, Default, Clone, Copy)]
-pub struct TranslateAction(pub Vec2);
+impl AtomicAction {
+    /// The action that undoes this one.
+    pub fn inverse(&self) -> AtomicAction {
+        match self {
+            AtomicAction::Translate { moves } => AtomicAction::Translate {
+                moves: moves
+                    .iter()
+                    .map(|(id, before, after)| (*id, *after, *before))
+                    .collect(),
+            },
+            AtomicAction::Create { shapes } => AtomicAction::Delete {
+                shapes: shapes.clone(),
+            },
+            AtomicAction::Delete { shapes } => AtomicAction::Create {
+                shapes: shapes.clone(),
+            },
+            AtomicAction::Modify { shapes } => AtomicAction::Modify {
+                shapes: shapes
+                    .iter()
+                    .map(|(id, before, after)| (*id, after.clone(), before.clone()))
+                    .collect(),
+            },
+            AtomicAction::Compound { actions } => AtomicAction::Compound {
+                actions: actions.iter().rev().map(|a| a.inverse()).collect(),
+            },
+        }
+    }
+}
 
+/// Record a drag as the move of every dragged shape from where it was when the drag
+/// started to where it is when the drag ends, and move the shapes in the index to match.
 pub fn undo_redo_tracking_system(
     mut history: ResMut<UndoRedoHistory>,
-    dragging_q: Query<Entity, With<Dragging>>,
-    transform_q: Query<&Transform, Without<Dragging>>,
+    mut shape_index: ResMut<ShapeIndex>,
+    transform_q: Query<&Transform>,
     mut interaction_ev: EventReader<Interaction>,
-    mut pre_drag_pos_ev: EventReader<PreDragPosEvent>,
-    mut initial_shape_pos: Local<(Vec<Entity>, Vec2)>,
-    mut dragging_entities: Local<Vec<Entity>>,
+    mut pre_drag_transform_ev: EventReader<PreDragTransformEvent>,
+    mut drag_start: Local<Vec<PreDragTransformEvent>>,
 ) {
-    for ev in pre_drag_pos_ev.iter() {
-        *initial_shape_pos = (vec![ev.entity], ev.pos);
-    }
+    drag_start.extend(pre_drag_transform_ev.iter().copied());
+
     for interaction in interaction_ev.iter() {
-        match interaction {
-            Interaction::DragStart => {
-                *dragging_entities = dragging_q.iter().collect::<Vec<Entity>>();
-            }
-            Interaction::DragEnd => {
-                if !dragging_entities.is_empty() {
-                    let entity = initial_shape_pos.0[0];
-                    let pos = initial_shape_pos.1;
-                    let new_t = transform_q.get(entity).unwrap();
-                    let dt = new_t.translation.truncate() - pos;
-
-                    if history.offset < history.actions.len() {
-                        let offset = history.offset;
-                        history.actions.truncate(offset);
-                    }
+        if *interaction != Interaction::DragEnd {
+            continue;
+        }
 
-                    history.actions.push(AtomicAction {
-                        action: TranslateAction(dt),
-                        entities: (*dragging_entities).clone(),
-                    });
-                    history.offset += 1;
+        let moves = drag_start
+            .drain(..)
+            .filter_map(
+                |PreDragTransformEvent {
+                     entity,
+                     id,
+                     transform,
+                 }| {
+                    let after = transform_q.get(entity).ok()?;
+                    (after.translation != transform.translation).then(|| (id, transform, *after))
+                },
+            )
+            .collect::<Vec<(ShapeId, Transform, Transform)>>();
 
-                    *dragging_entities = vec![];
-                }
-            }
-            _ => (),
+        for (id, before, after) in moves.iter() {
+            shape_index.translate(*id, (after.translation - before.translation).truncate());
+        }
+
+        if !moves.is_empty() {
+            history.push(
+                shape_count_description("Move", moves.len()),
+                AtomicAction::Translate { moves },
+            );
         }
     }
 }
@@ -845,6 +977,15 @@ pub fn debug_undo_redo_system(history: Res<UndoRedoHistory>) {
 pub enum UndoRedoEvent {
     Undo,
     Redo,
+    /// Undo or redo actions until `offset` actions are applied.
+    JumpTo(usize),
+}
+
+/// Apply `action` to the loaded cell and record it in the undo/redo history.
+#[derive(Debug, Clone)]
+pub struct EditEvent {
+    pub description: String,
+    pub action: AtomicAction,
 }
 
 pub fn undo_redo_key_combo_system(
@@ -862,61 +1003,531 @@ pub fn undo_redo_key_combo_system(
     }
 }
 
-impl UndoRedoHistory {
-    fn undo_action(&mut self, transform_q: &mut Query<&mut Transform>) {
-        let AtomicAction { action, entities } = &self.actions[self.offset - 1];
-        for e in entities {
-            if let Ok(mut t) = transform_q.get_mut(*e) {
-                t.translation -= (*action).extend(0.0);
+/// Everything needed to apply an `AtomicAction` to the shape index and the shapes
+/// that are streamed in.
+#[derive(SystemParam)]
+pub struct ShapeEditor<'w, 's> {
+    commands: Commands<'w, 's>,
+    shape_index: ResMut<'w, ShapeIndex>,
+    streamed_shapes: ResMut<'w, StreamedShapes>,
+    shape_stack: ResMut<'w, ShapeStack>,
+    vlsir_cell: ResMut<'w, VlsirCell>,
+    layers: Res<'w, Layers>,
+    shape_q: Query<'w, 's, (Entity, &'static ShapeId, Option<&'static Selected>)>,
+    transform_q: Query<'w, 's, &'static mut Transform>,
+}
+
+impl<'w, 's> ShapeEditor<'w, 's> {
+    pub fn apply(&mut self, action: &AtomicAction) {
+        match action {
+            AtomicAction::Translate { moves } => {
+                for (id, from, to) in moves {
+                    self.move_shape(*id, from, to);
+                }
+            }
+            AtomicAction::Create { shapes } => {
+                for (id, shape) in shapes {
+                    self.shape_index.insert_with_id(*id, shape.clone());
+                }
+            }
+            AtomicAction::Delete { shapes } => {
+                for (id, _) in shapes {
+                    self.remove_shape(*id);
+                }
             }
+            AtomicAction::Modify { shapes } => {
+                for (id, _, after) in shapes {
+                    self.shape_index.insert_with_id(*id, after.clone());
+                    self.redraw_shape(*id, after);
+                }
+            }
+            AtomicAction::Compound { actions } => {
+                for action in actions {
+                    self.apply(action);
+                }
+            }
+        }
+
+        // the shape stack may refer to a shape that was just removed
+        *self.shape_stack = ShapeStack::default();
+        if self.vlsir_cell.num_shapes.is_some() {
+            self.vlsir_cell.num_shapes = Some(self.shape_index.len() as u64);
         }
-        self.offset -= 1;
     }
 
-    fn redo_action(&mut self, transform_q: &mut Query<&mut Transform>) {
-        let AtomicAction { action, entities } = &self.actions[self.offset];
-        for e in entities {
-            if let Ok(mut t) = transform_q.get_mut(*e) {
-                t.translation += (*action).extend(0.0);
+    /// Move the shape `id` from `from` to `to`. A shape that was streamed out since has
+    /// its moves folded into its geometry, so it is moved by the difference instead.
+    fn move_shape(&mut self, id: ShapeId, from: &Transform, to: &Transform) {
+        let delta = (to.translation - from.translation).truncate();
+
+        let entity = self
+            .shape_q
+            .iter()
+            .find(|(_, shape_id, _)| **shape_id == id)
+            .map(|(e, _, _)| e);
+
+        if let Some(mut t) = entity.and_then(|e| self.transform_q.get_mut(e).ok()) {
+            if t.translation.truncate() == from.translation.truncate() {
+                t.translation.x = to.translation.x;
+                t.translation.y = to.translation.y;
+            } else {
+                t.translation += delta.extend(0.0);
+            }
+        }
+
+        self.shape_index.translate(id, delta);
+    }
+
+    /// Remove the shape `id` from the index along with its entity, if it is streamed in.
+    fn remove_shape(&mut self, id: ShapeId) {
+        if self.streamed_shapes.spawned.remove(&id) {
+            for (e, shape_id, _) in self.shape_q.iter() {
+                if *shape_id == id {
+                    self.commands.entity(e).despawn_recursive();
+                }
             }
         }
+        self.shape_index.remove(id);
+    }
+
+    /// Redraw the entity of shape `id`, if it is streamed in, as `shape`.
+    fn redraw_shape(&mut self, id: ShapeId, shape: &IndexedShape) {
+        let (e, selected) = match self
+            .shape_q
+            .iter()
+            .find(|(_, shape_id, _)| **shape_id == id)
+        {
+            Some((e, _, selected)) => (e, selected.is_some()),
+            None => return,
+        };
+
+        let layer = match self.layers.get(&shape.layer) {
+            Some(layer) => layer,
+            None => return,
+        };
+
+        let path_width = match &shape.geometry {
+            ShapeGeometry::Path(p) => Some(p.width as f32),
+            _ => None,
+        };
+
+        let mut draw_mode = layer.draw_mode(path_width);
+        if let (true, DrawMode::Outlined { fill_mode, .. }) = (selected, &mut draw_mode) {
+            fill_mode.color = *fill_mode.color.set_a(0.75);
+        }
+
+        let shape_lyon = GeometryBuilder::build_as(
+            &geometry_polygon(&shape.geometry),
+            draw_mode,
+            Transform::from_translation(Vec3::new(0.0, 0.0, layer.z)),
+        );
+
+        let mut entity = self.commands.entity(e);
+        entity
+            .insert_bundle(shape_lyon)
+            .insert(InLayer(shape.layer))
+            .insert(shape.net.clone())
+            .remove::<Rect>()
+            .remove::<Poly>()
+            .remove::<Path>();
+
+        match &shape.geometry {
+            ShapeGeometry::Rect(r) => entity.insert(Rect(*r)),
+            ShapeGeometry::Poly(p) => entity.insert(Poly(p.clone())),
+            ShapeGeometry::Path(p) => entity.insert(Path(p.clone())),
+        };
+
+        match &shape.elem {
+            Some(elem) => entity.insert(elem.clone()),
+            None => entity.remove::<CellElem>(),
+        };
+    }
+}
+
+/// The outline of a rect or polygon, or the centerline of a path, as a lyon polygon.
+pub fn geometry_polygon(geometry: &ShapeGeometry) -> lyon_shapes::Polygon {
+    match geometry {
+        ShapeGeometry::Rect(r) => lyon_shapes::Polygon {
+            points: vec![
+                Vec2::new(r.min().x as f32, r.min().y as f32),
+                Vec2::new(r.max().x as f32, r.min().y as f32),
+                Vec2::new(r.max().x as f32, r.max().y as f32),
+                Vec2::new(r.min().x as f32, r.max().y as f32),
+            ],
+            closed: true,
+        },
+        ShapeGeometry::Poly(p) => lyon_shapes::Polygon {
+            points: p
+                .exterior()
+                .coords()
+                .map(|c| Vec2::new(c.x as f32, c.y as f32))
+                .collect(),
+            closed: true,
+        },
+        ShapeGeometry::Path(p) => lyon_shapes::Polygon {
+            points: p
+                .points
+                .iter()
+                .map(|p| Vec2::new(p.x as f32, p.y as f32))
+                .collect(),
+            closed: false,
+        },
+    }
+}
+
+impl UndoRedoHistory {
+    /// Record an action that was already applied, discarding any actions that were undone.
+    pub fn push(&mut self, description: impl Into<String>, action: AtomicAction) {
+        if self.offset < self.actions.len() {
+            let offset = self.offset;
+            self.actions.truncate(offset);
+        }
+        self.actions.push(HistoryEntry {
+            description: description.into(),
+            action,
+        });
         self.offset += 1;
     }
+
+    /// The action the next undo would undo.
+    pub fn next_undo(&self) -> Option<&HistoryEntry> {
+        self.offset.checked_sub(1).and_then(|i| self.actions.get(i))
+    }
+
+    /// The action the next redo would redo.
+    pub fn next_redo(&self) -> Option<&HistoryEntry> {
+        self.actions.get(self.offset)
+    }
+
+    fn undo_action(&mut self, editor: &mut ShapeEditor) {
+        if let Some(entry) = self.next_undo() {
+            editor.apply(&entry.action.inverse());
+            self.offset -= 1;
+        }
+    }
+
+    fn redo_action(&mut self, editor: &mut ShapeEditor) {
+        if let Some(entry) = self.next_redo() {
+            editor.apply(&entry.action);
+            self.offset += 1;
+        }
+    }
 }
 
 pub fn undo_redo_system(
-    mut undo_redo_ev: EventReader<UndoRedoEvent>,
+    mut editor: ShapeEditor,
     mut undo_redo_history: ResMut<UndoRedoHistory>,
-    mut transform_q: Query<&mut Transform>,
+    mut undo_redo_ev: EventReader<UndoRedoEvent>,
+    mut edit_ev: EventReader<EditEvent>,
 ) {
+    for EditEvent {
+        description,
+        action,
+    } in edit_ev.iter()
+    {
+        info!("{description}");
+        editor.apply(action);
+        undo_redo_history.push(description.clone(), action.clone());
+    }
+
     for ev in undo_redo_ev.iter() {
         use UndoRedoEvent::*;
         match ev {
-            Undo => {
-                if undo_redo_history.offset > 0 && undo_redo_history.actions.len() > 0 {
-                    undo_redo_history.undo_action(&mut transform_q);
+            Undo => undo_redo_history.undo_action(&mut editor),
+            Redo => undo_redo_history.redo_action(&mut editor),
+            JumpTo(offset) => {
+                let offset = (*offset).min(undo_redo_history.actions.len());
+                while undo_redo_history.offset > offset {
+                    undo_redo_history.undo_action(&mut editor);
                 }
-            }
-            Redo => {
-                if undo_redo_history.actions.len() > 0
-                    && undo_redo_history.offset < undo_redo_history.actions.len()
-                {
-                    undo_redo_history.redo_action(&mut transform_q);
+                while undo_redo_history.offset < offset {
+                    undo_redo_history.redo_action(&mut editor);
                 }
             }
         }
     }
 }
 
-// #[derive(Component)]
-// pub enum RectSelection {
-//     BottomLeft,
-//     Left,
-//     TopLeft,
-//     Top,
-//     TopRight,
-//     Right,
-//     BottomRight,
-//     Bottom,
-//     Body,
-// }
+/// The selected shapes of the loaded cell as records, for systems that edit them.
+/// Selected shapes flattened out of instances belong to other cells, so are only counted.
+#[derive(SystemParam)]
+pub struct SelectedShapes<'w, 's> {
+    selected_q: Query<
+        'w,
+        's,
+        (
+            &'static ShapeId,
+            &'static InLayer,
+            &'static Net,
+            &'static Transform,
+            Option<&'static CellElem>,
+            Option<&'static Rect>,
+            Option<&'static Poly>,
+            Option<&'static Path>,
+        ),
+        With<Selected>,
+    >,
+}
+
+impl<'w, 's> SelectedShapes<'w, 's> {
+    /// The selected shapes of the loaded cell, and how many selected shapes of instances
+    /// were left out.
+    pub fn shapes(&self) -> (Vec<(ShapeId, IndexedShape)>, usize) {
+        let mut shapes = vec![];
+        let mut skipped = 0;
+
+        for (id, layer, net, t, elem, rect, poly, path) in self.selected_q.iter() {
+            if elem.is_none() {
+                skipped += 1;
+                continue;
+            }
+
+            // taken from the entity, which has any edits not yet folded into the index
+            if let Some(shape) =
+                IndexedShape::from_components(**layer, net, elem, None, rect, poly, path, t)
+            {
+                shapes.push((*id, shape));
+            }
+        }
+
+        (shapes, skipped)
+    }
+
+    /// [Self::shapes], logging the shapes of instances that are left out of `doing`,
+    /// e.g. "deleting".
+    pub fn editable(&self, doing: &str) -> Vec<(ShapeId, IndexedShape)> {
+        let (shapes, skipped) = self.shapes();
+        if skipped > 0 {
+            info!("Not {doing} {skipped} selected shapes that belong to instances");
+        }
+        shapes
+    }
+}
+
+/// Remove the selected shapes from the cell with the Delete key. Shapes flattened out
+/// of instances belong to other cells, so they are left alone.
+pub fn delete_selected_system(
+    keyboard: Res<Input<KeyCode>>,
+    mut egui_ctx: ResMut<EguiContext>,
+    selected: SelectedShapes,
+    mut edit_ev: EventWriter<EditEvent>,
+) {
+    if !keyboard.just_pressed(KeyCode::Delete) || egui_ctx.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    let deleted = selected.editable("deleting");
+
+    if deleted.is_empty() {
+        return;
+    }
+
+    edit_ev.send(EditEvent {
+        description: shape_count_description("Delete", deleted.len()),
+        action: AtomicAction::Delete { shapes: deleted },
+    });
+}
+
+/// Move the selected shapes of the loaded cell onto another layer.
+#[derive(Debug, Clone, Copy)]
+pub struct ChangeLayerEvent(pub u8);
+
+/// Move the selected shapes onto a layer of the library. Redrawing them restyles and
+/// restacks them, and their elements get the layer's key when the library is saved.
+pub fn change_layer_system(
+    layers: Res<Layers>,
+    selected: SelectedShapes,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut change_layer_ev: EventReader<ChangeLayerEvent>,
+) {
+    for ChangeLayerEvent(layer) in change_layer_ev.iter() {
+        if !layers.contains_key(layer) {
+            warn!("Not moving shapes to layer {layer}, the library has no such layer");
+            continue;
+        }
+
+        let modified = selected
+            .editable("changing the layer of")
+            .into_iter()
+            .filter(|(_, before)| before.layer != *layer)
+            .map(|(id, before)| {
+                let after = IndexedShape {
+                    layer: *layer,
+                    ..before.clone()
+                };
+                (id, before, after)
+            })
+            .collect::<Vec<(ShapeId, IndexedShape, IndexedShape)>>();
+
+        if modified.is_empty() {
+            continue;
+        }
+
+        edit_ev.send(EditEvent {
+            description: format!(
+                "{} to {layer}",
+                shape_count_description("Change layer of", modified.len())
+            ),
+            action: AtomicAction::Modify { shapes: modified },
+        });
+    }
+}
+
+/// e.g. "Delete 3 shapes".
+pub fn shape_count_description(verb: &str, count: usize) -> String {
+    if count == 1 {
+        format!("{verb} 1 shape")
+    } else {
+        format!("{verb} {count} shapes")
+    }
+}
+
+/// The history refers to shapes of the loaded cell, so it starts over with every cell.
+pub fn reset_undo_redo_history_system(
+    mut history: ResMut<UndoRedoHistory>,
+    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut load_cell_event_reader: EventReader<LoadCellEvent>,
+) {
+    let open = open_vlsir_lib_event_reader.iter().count() > 0;
+    let load = load_cell_event_reader.iter().count() > 0;
+
+    if open || load {
+        *history = UndoRedoHistory::default();
+    }
+}
+
+/// Shapes to select once their entities are spawned, such as pasted shapes or the
+/// shapes of an instance that are out of view.
+#[derive(Debug, Default, Clone, Deref, DerefMut)]
+pub struct PendingSelection(pub HashSet<ShapeId>);
+
+/// Select the pending shapes as they are spawned.
+pub fn select_pending_system(
+    mut commands: Commands,
+    mut pending_selection: ResMut<PendingSelection>,
+    spawned_q: Query<(Entity, &ShapeId), Added<ShapeId>>,
+) {
+    if pending_selection.is_empty() {
+        return;
+    }
+
+    for (e, id) in spawned_q.iter() {
+        if pending_selection.remove(id) {
+            commands.entity(e).insert(Selected);
+        }
+    }
+}
+
+pub fn reset_pending_selection_system(
+    mut pending_selection: ResMut<PendingSelection>,
+    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut load_cell_event_reader: EventReader<LoadCellEvent>,
+) {
+    let open = open_vlsir_lib_event_reader.iter().count() > 0;
+    let load = load_cell_event_reader.iter().count() > 0;
+
+    if open || load {
+        pending_selection.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dragged_shape_initial_pos_system, undo_redo_system, undo_redo_tracking_system, Dragging,
+        EditEvent, Interaction, PreDragTransformEvent, ShapeStack, UndoRedoEvent, UndoRedoHistory,
+    };
+    use crate::import::{Layers, StreamedShapes, VlsirCell};
+    use crate::index::{test_shape, ShapeGeometry, ShapeId, ShapeIndex};
+    use crate::shapes::GeoRect;
+    use bevy::ecs::event::Events;
+    use bevy::prelude::*;
+    use geo::coord;
+
+    fn send<T: Send + Sync + 'static>(app: &mut App, ev: T) {
+        app.world.get_resource_mut::<Events<T>>().unwrap().send(ev);
+    }
+
+    fn translations(app: &mut App, entities: &[Entity]) -> Vec<Vec3> {
+        entities
+            .iter()
+            .map(|e| app.world.get::<Transform>(*e).unwrap().translation)
+            .collect()
+    }
+
+    #[test]
+    fn undo_restores_every_dragged_shape() {
+        let rect =
+            ShapeGeometry::Rect(GeoRect::new(coord! { x: 0, y: 0 }, coord! { x: 20, y: 10 }));
+        let indexed = |app: &mut App| {
+            let index = app.world.get_resource::<ShapeIndex>().unwrap();
+            index.get(ShapeId(0)).unwrap().geometry.clone()
+        };
+
+        let mut app = App::new();
+        app.add_event::<Interaction>()
+            .add_event::<PreDragTransformEvent>()
+            .add_event::<UndoRedoEvent>()
+            .add_event::<EditEvent>()
+            .insert_resource(UndoRedoHistory::default())
+            .insert_resource(ShapeIndex::from_shapes(vec![test_shape(rect.clone()); 3]))
+            .insert_resource(StreamedShapes::default())
+            .insert_resource(ShapeStack::default())
+            .insert_resource(VlsirCell::default())
+            .insert_resource(Layers::default())
+            .add_system_to_stage(CoreStage::PreUpdate, dragged_shape_initial_pos_system)
+            .add_system(undo_redo_tracking_system)
+            .add_system_to_stage(CoreStage::PostUpdate, undo_redo_system);
+
+        let start = vec![
+            Vec3::new(0.0, 0.0, 10.0),
+            Vec3::new(100.0, -50.0, 20.0),
+            Vec3::new(-30.0, 70.0, 30.0),
+        ];
+
+        let entities = start
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                app.world
+                    .spawn()
+                    .insert(ShapeId(i as u64))
+                    .insert(Transform::from_translation(*t))
+                    .insert(Dragging)
+                    .id()
+            })
+            .collect::<Vec<Entity>>();
+
+        // the drag starts
+        app.update();
+
+        // every shape moves by its own amount, as when snapping puts each on the grid
+        let offsets = [
+            Vec3::new(40.0, 10.0, 0.0),
+            Vec3::new(42.0, 8.0, 0.0),
+            Vec3::new(39.5, 10.5, 0.0),
+        ];
+        for (e, offset) in entities.iter().zip(offsets) {
+            app.world.get_mut::<Transform>(*e).unwrap().translation += offset;
+        }
+        let end = translations(&mut app, &entities);
+
+        send(&mut app, Interaction::DragEnd);
+        app.update();
+
+        let history = app.world.get_resource::<UndoRedoHistory>().unwrap();
+        assert_eq!(history.actions.len(), 1);
+        assert_eq!(history.actions[0].description, "Move 3 shapes");
+        assert_eq!(indexed(&mut app), rect.translate(40, 10));
+
+        send(&mut app, UndoRedoEvent::Undo);
+        app.update();
+        assert_eq!(translations(&mut app, &entities), start);
+        assert_eq!(indexed(&mut app), rect);
+
+        send(&mut app, UndoRedoEvent::Redo);
+        app.update();
+        assert_eq!(translations(&mut app, &entities), end);
+        assert_eq!(indexed(&mut app), rect.translate(40, 10));
+    }
+}
@@ -0,0 +1,284 @@
+use crate::import::{LibFileFormat, Net, VlsirCell, VlsirLib};
+use crate::index::{IndexedShape, ShapeGeometry, ShapeId, ShapeIndex};
+use crate::shapes::{CellElem, Path, Poly, Rect};
+use crate::InLayer;
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use layout21::raw::{
+    self, gds::GdsExporter, proto::ProtoExporter, Element, LayerKey, Library, Point, Shape,
+};
+
+pub struct Layout21ExportPlugin;
+
+impl Plugin for Layout21ExportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SaveVlsirLibEvent>()
+            .add_event::<SaveVlsirLibCompleteEvent>()
+            .add_system(save_vlsir_lib_system);
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SaveVlsirLibEvent {
+    pub path: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SaveVlsirLibCompleteEvent {
+    pub path: String,
+}
+
+pub fn save_vlsir_lib_system(
+    mut vlsir_lib: ResMut<VlsirLib>,
+    vlsir_cell: Res<VlsirCell>,
+    mut shape_index: ResMut<ShapeIndex>,
+    spawned_q: Query<(
+        &ShapeId,
+        &InLayer,
+        &Net,
+        &Transform,
+        Option<&CellElem>,
+        Option<&Rect>,
+        Option<&Poly>,
+        Option<&Path>,
+    )>,
+    mut save_vlsir_lib_event_reader: EventReader<SaveVlsirLibEvent>,
+    mut save_vlsir_lib_complete_event_writer: EventWriter<SaveVlsirLibCompleteEvent>,
+) {
+    for SaveVlsirLibEvent { path } in save_vlsir_lib_event_reader.iter() {
+        let result = {
+            let (lib, cell_idx) = match (vlsir_lib.lib.as_ref(), vlsir_cell.index) {
+                (Some(lib), Some(cell_idx)) => (lib, cell_idx),
+                _ => {
+                    warn!("No library and cell loaded, nothing to save to '{path}'");
+                    continue;
+                }
+            };
+
+            let t = std::time::Instant::now();
+
+            // fold the edits made to the currently spawned shapes into the index,
+            // shapes that were streamed out already had theirs folded in
+            for (id, layer, net, t, elem, rect, poly, path) in spawned_q.iter() {
+                let instance = shape_index.get(*id).and_then(|s| s.instance);
+                if let Some(shape) =
+                    IndexedShape::from_components(**layer, net, elem, instance, rect, poly, path, t)
+                {
+                    if shape_index.get(*id) != Some(&shape) {
+                        shape_index.update(*id, shape);
+                    }
+                }
+            }
+
+            let elems = cell_elements(&shape_index, &layer_keys(lib));
+
+            let num_elems = elems.len();
+
+            {
+                let cell = &lib.cells[cell_idx];
+                let mut cell = cell.write().unwrap();
+                info!(
+                    "Writing {num_elems} elements back into cell '{}'",
+                    cell.name
+                );
+                cell.layout
+                    .as_mut()
+                    .expect("Loaded cell has no layout to save edits into")
+                    .elems = elems;
+            }
+
+            let result = export_lib(lib, path);
+
+            info!("Save lib file '{path}' duration {:?}", t.elapsed());
+
+            result
+        };
+
+        match result {
+            Ok(()) => {
+                vlsir_lib.path = Some(path.clone());
+                save_vlsir_lib_complete_event_writer
+                    .send(SaveVlsirLibCompleteEvent { path: path.clone() });
+            }
+            Err(e) => error!("Failed to save library to '{path}': {e}"),
+        }
+    }
+}
+
+/// The layer key of every layer of `lib` by its layer number.
+pub fn layer_keys(lib: &Library) -> HashMap<u8, LayerKey> {
+    lib.layers
+        .read()
+        .unwrap()
+        .slots
+        .iter()
+        .map(|(key, layer)| (layer.layernum as u8, key))
+        .collect()
+}
+
+/// The elements of the loaded cell's own shapes. The cell's elements were given their
+/// ids in order when it was loaded, so they are written back in their original order,
+/// with shapes added since at the end.
+pub fn cell_elements(shape_index: &ShapeIndex, layer_keys: &HashMap<u8, LayerKey>) -> Vec<Element> {
+    let mut elems = shape_index
+        .iter()
+        .filter_map(|(id, shape)| {
+            shape
+                .elem
+                .as_ref()
+                .map(|elem| (*id, make_element(shape, elem, layer_keys)))
+        })
+        .collect::<Vec<(ShapeId, Element)>>();
+
+    elems.sort_by_key(|(id, _)| *id);
+
+    elems.into_iter().map(|(_, elem)| elem).collect()
+}
+
+fn make_element(
+    shape: &IndexedShape,
+    elem: &CellElem,
+    layer_keys: &HashMap<u8, LayerKey>,
+) -> Element {
+    let layer = *layer_keys
+        .get(&shape.layer)
+        .expect("This shape's layer num does not exist in this Library's Layers");
+
+    let inner = match &shape.geometry {
+        ShapeGeometry::Rect(rect) => Shape::Rect(raw::Rect {
+            p0: Point::new(rect.min().x as isize, rect.min().y as isize),
+            p1: Point::new(rect.max().x as isize, rect.max().y as isize),
+        }),
+        ShapeGeometry::Poly(poly) => {
+            let mut points = poly
+                .exterior()
+                .coords()
+                .map(|c| Point::new(c.x as isize, c.y as isize))
+                .collect::<Vec<Point>>();
+            // geo closes polygon rings by repeating the first point, layout21 does not
+            if points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+            Shape::Polygon(raw::Polygon { points })
+        }
+        ShapeGeometry::Path(path) => Shape::Path(path.clone()),
+    };
+
+    Element {
+        net: shape.net.0.clone(),
+        layer,
+        purpose: elem.purpose.clone(),
+        inner,
+    }
+}
+
+/// Export `lib` to `path` in the format given by its file extension.
+pub fn export_lib(lib: &Library, path: &str) -> Result<(), String> {
+    match LibFileFormat::from_path(path) {
+        LibFileFormat::Proto => {
+            let plib = ProtoExporter::export(lib).map_err(|e| format!("{e:?}"))?;
+            vlsir::save(&plib, path).map_err(|e| format!("{e:?}"))
+        }
+        LibFileFormat::Gds => {
+            let gds = GdsExporter::export(lib).map_err(|e| format!("{e:?}"))?;
+            gds.save(path).map_err(|e| format!("{e:?}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cell_elements, export_lib, layer_keys};
+    use crate::hierarchy::InstanceTree;
+    use crate::import::{import_cell_shapes, new_lib, open_lib};
+    use crate::index::{test_shape, ShapeGeometry, ShapeIndex};
+    use crate::shapes::GeoRect;
+    use crate::transform::LayoutTransform;
+    use geo::coord;
+
+    #[test]
+    fn unedited_cell_saves_its_elements_unchanged() {
+        let lib = open_lib("libs/dff1_lib.proto").unwrap();
+        let cell = lib.cells[0].clone();
+        let original = cell.read().unwrap().layout.as_ref().unwrap().elems.clone();
+        assert!(!original.is_empty());
+
+        let mut shapes = vec![];
+        import_cell_shapes(
+            &cell,
+            None,
+            &LayoutTransform::identity(),
+            &lib.layers,
+            None,
+            &mut InstanceTree::default(),
+            &mut shapes,
+        );
+        let index = ShapeIndex::from_shapes(shapes);
+
+        // the same elements in the same order, every time
+        let elems = cell_elements(&index, &layer_keys(&lib));
+        assert_eq!(elems, original);
+        assert_eq!(cell_elements(&index, &layer_keys(&lib)), elems);
+
+        cell.write().unwrap().layout.as_mut().unwrap().elems = elems;
+        let path = std::env::temp_dir().join("doug_unedited_dff1_lib.proto");
+        let path = path.to_str().unwrap();
+        export_lib(&lib, path).unwrap();
+
+        let saved = open_lib(path).unwrap();
+        let saved_elems = saved.cells[0]
+            .read()
+            .unwrap()
+            .layout
+            .as_ref()
+            .unwrap()
+            .elems
+            .clone();
+        assert_eq!(saved_elems.len(), original.len());
+        for (saved, original) in saved_elems.iter().zip(original.iter()) {
+            assert_eq!(saved.inner, original.inner);
+            assert_eq!(saved.net, original.net);
+        }
+    }
+
+    #[test]
+    fn shapes_pasted_into_a_new_lib_are_saved() {
+        let lib = new_lib();
+        assert_eq!(lib.cells.len(), 1);
+
+        // the pasted shape's layer, as added to the library when pasting
+        let layer = open_lib("libs/dff1_lib.proto")
+            .unwrap()
+            .layers
+            .read()
+            .unwrap()
+            .slots
+            .values()
+            .find(|layer| layer.layernum == 68)
+            .cloned()
+            .unwrap();
+        lib.layers.write().unwrap().add(layer);
+
+        let rect = ShapeGeometry::Rect(GeoRect::new(
+            coord! { x: 0, y: 0 },
+            coord! { x: 100, y: 50 },
+        ));
+        let index = ShapeIndex::from_shapes(vec![test_shape(rect)]);
+
+        let elems = cell_elements(&index, &layer_keys(&lib));
+        assert_eq!(elems.len(), 1);
+        lib.cells[0].write().unwrap().layout.as_mut().unwrap().elems = elems;
+
+        let path = std::env::temp_dir().join("doug_new_lib.proto");
+        let path = path.to_str().unwrap();
+        export_lib(&lib, path).unwrap();
+
+        let saved = open_lib(path).unwrap();
+        assert_eq!(saved.cells.len(), 1);
+        let cell = saved.cells[0].read().unwrap();
+        assert_eq!(cell.layout.as_ref().unwrap().elems.len(), 1);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::import::{ImportLibCompleteEvent, VlsirLib};
+
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy_prototype_lyon::prelude::{shapes as lyon_shapes, DrawMode, GeometryBuilder, StrokeMode};
+
+use layout21::raw::Units;
+
+pub struct GridPlugin;
+
+impl Plugin for GridPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Grid::default())
+            .add_system(grid_units_system)
+            .add_system(draw_grid_system);
+    }
+}
+
+/// Minimum distance in pixels between two drawn grid lines, coarser grids are drawn
+/// at a multiple of the manufacturing grid when zoomed out.
+pub const MIN_GRID_SPACING_PX: f32 = 8.0;
+
+/// Every tenth line of the drawn grid is a major line.
+pub const MAJOR_GRID_EVERY: i64 = 10;
+
+/// The manufacturing grid that every coordinate of an edit is snapped to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    pub show: bool,
+    pub snap: bool,
+    /// Manufacturing grid in nm, 5nm for SKY130.
+    pub manufacturing_grid_nm: f64,
+    /// Size of the loaded library's database unit in nm.
+    pub nm_per_unit: f64,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self {
+            show: true,
+            snap: true,
+            manufacturing_grid_nm: 5.0,
+            nm_per_unit: 1.0,
+        }
+    }
+}
+
+impl Grid {
+    /// Manufacturing grid pitch in database units, at least one unit.
+    pub fn pitch(&self) -> i32 {
+        ((self.manufacturing_grid_nm / self.nm_per_unit).round() as i32).max(1)
+    }
+
+    /// Pitch that coordinates are snapped to, a single database unit with snapping off.
+    pub fn step(&self) -> i32 {
+        if self.snap {
+            self.pitch()
+        } else {
+            1
+        }
+    }
+
+    pub fn snap(&self, v: f32) -> f32 {
+        let step = self.step() as f32;
+        (v / step).round() * step
+    }
+
+    pub fn snap_vec2(&self, v: Vec2) -> Vec2 {
+        Vec2::new(self.snap(v.x), self.snap(v.y))
+    }
+
+    /// Whether `v` is a point of the grid that coordinates are snapped to.
+    pub fn is_on_grid(&self, v: Vec2) -> bool {
+        self.snap_vec2(v) == v
+    }
+
+    pub fn snap_i32(&self, v: i32) -> i32 {
+        let step = self.step();
+        (v as f32 / step as f32).round() as i32 * step
+    }
+
+    /// A length in nm as a whole number of database units, `None` if it isn't one.
+    pub fn nm_to_units(&self, nm: f64) -> Option<i32> {
+        let units = nm / self.nm_per_unit;
+        ((units - units.round()).abs() < 1e-6 && units.abs() <= i32::MAX as f64)
+            .then(|| units.round() as i32)
+    }
+}
+
+pub fn nm_per_unit(units: &Units) -> f64 {
+    match units {
+        Units::Micro => 1000.0,
+        Units::Nano => 1.0,
+        Units::Angstrom => 0.1,
+        Units::Pico => 0.001,
+    }
+}
+
+pub fn grid_units_system(
+    vlsir_lib: Res<VlsirLib>,
+    mut grid: ResMut<Grid>,
+    mut import_lib_complete_event_reader: EventReader<ImportLibCompleteEvent>,
+) {
+    for _ in import_lib_complete_event_reader.iter() {
+        if let Some(lib) = vlsir_lib.lib.as_ref() {
+            grid.nm_per_unit = nm_per_unit(&lib.units);
+            info!(
+                "Manufacturing grid of {}nm is {} database units",
+                grid.manufacturing_grid_nm,
+                grid.pitch()
+            );
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridLines {
+    Minor,
+    Major,
+}
+
+/// Redraw the grid lines across the camera's view whenever it pans or zooms. The
+/// spacing of the lines grows in powers of ten of the grid pitch as the view zooms
+/// out, so they never get closer than `MIN_GRID_SPACING_PX`.
+pub fn draw_grid_system(
+    mut commands: Commands,
+    grid: Res<Grid>,
+    camera_q: Query<(&Transform, &OrthographicProjection), With<Camera>>,
+    grid_lines_q: Query<Entity, With<GridLines>>,
+    mut last_drawn: Local<Option<([i64; 4], i64, f32)>>,
+) {
+    let (cam_t, proj) = match camera_q.get_single() {
+        Ok(cam) => cam,
+        Err(_) => return,
+    };
+
+    if !grid.show {
+        if last_drawn.take().is_some() {
+            for e in grid_lines_q.iter() {
+                commands.entity(e).despawn();
+            }
+        }
+        return;
+    }
+
+    let mut step = grid.pitch() as i64;
+    while (step as f32) < MIN_GRID_SPACING_PX * proj.scale {
+        step *= 10;
+    }
+
+    let x_min = cam_t.translation.x + proj.left * proj.scale;
+    let x_max = cam_t.translation.x + proj.right * proj.scale;
+    let y_min = cam_t.translation.y + proj.bottom * proj.scale;
+    let y_max = cam_t.translation.y + proj.top * proj.scale;
+
+    // the first and last line on each axis, so panning within a grid cell needs no redraw
+    let bounds = [
+        (x_min / step as f32).floor() as i64,
+        (x_max / step as f32).ceil() as i64,
+        (y_min / step as f32).floor() as i64,
+        (y_max / step as f32).ceil() as i64,
+    ];
+
+    // loading a cell despawns every lyon shape, the grid lines included
+    if *last_drawn == Some((bounds, step, proj.scale)) && !grid_lines_q.is_empty() {
+        return;
+    }
+    *last_drawn = Some((bounds, step, proj.scale));
+
+    for e in grid_lines_q.iter() {
+        commands.entity(e).despawn();
+    }
+
+    let [x0, x1, y0, y1] = bounds;
+    let (x_start, x_end) = ((x0 * step) as f32, (x1 * step) as f32);
+    let (y_start, y_end) = ((y0 * step) as f32, (y1 * step) as f32);
+
+    let mut minor = GeometryBuilder::new();
+    let mut major = GeometryBuilder::new();
+
+    for i in x0..=x1 {
+        let x = (i * step) as f32;
+        let line = lyon_shapes::Line(Vec2::new(x, y_start), Vec2::new(x, y_end));
+        if i % MAJOR_GRID_EVERY == 0 {
+            major.add(&line);
+        } else {
+            minor.add(&line);
+        }
+    }
+
+    for i in y0..=y1 {
+        let y = (i * step) as f32;
+        let line = lyon_shapes::Line(Vec2::new(x_start, y), Vec2::new(x_end, y));
+        if i % MAJOR_GRID_EVERY == 0 {
+            major.add(&line);
+        } else {
+            minor.add(&line);
+        }
+    }
+
+    // one pixel wide, beneath every layer
+    let transform = Transform::from_translation(Vec3::new(0.0, 0.0, 0.0));
+
+    for (lines, kind, alpha) in [
+        (minor, GridLines::Minor, 0.08),
+        (major, GridLines::Major, 0.2),
+    ] {
+        let draw_mode = DrawMode::Stroke(StrokeMode::new(
+            Color::rgba(1.0, 1.0, 1.0, alpha),
+            proj.scale,
+        ));
+        commands
+            .spawn_bundle(lines.build(draw_mode, transform))
+            .insert(kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nm_per_unit, Grid};
+    use layout21::raw::Units;
+
+    #[test]
+    fn snaps_to_manufacturing_grid() {
+        let mut grid = Grid::default();
+        assert_eq!(grid.pitch(), 5);
+        assert_eq!(grid.snap(12.4), 10.0);
+        assert_eq!(grid.snap(-13.0), -15.0);
+        assert_eq!(grid.snap_i32(8), 10);
+
+        // 5nm in angstrom database units
+        grid.nm_per_unit = nm_per_unit(&Units::Angstrom);
+        assert_eq!(grid.pitch(), 50);
+
+        // a grid finer than a database unit snaps to whole units
+        grid.nm_per_unit = nm_per_unit(&Units::Micro);
+        assert_eq!(grid.pitch(), 1);
+
+        grid.snap = false;
+        grid.nm_per_unit = 1.0;
+        assert_eq!(grid.snap(12.4), 12.0);
+    }
+
+    #[test]
+    fn nm_to_whole_units() {
+        let mut grid = Grid::default();
+        assert_eq!(grid.nm_to_units(-25.0), Some(-25));
+        assert_eq!(grid.nm_to_units(2.5), None);
+
+        grid.nm_per_unit = nm_per_unit(&Units::Angstrom);
+        assert_eq!(grid.nm_to_units(2.5), Some(25));
+
+        grid.nm_per_unit = nm_per_unit(&Units::Micro);
+        assert_eq!(grid.nm_to_units(3000.0), Some(3));
+        assert_eq!(grid.nm_to_units(150.0), None);
+    }
+}
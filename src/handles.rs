@@ -0,0 +1,630 @@
+use crate::{
+    editing::{geometry_polygon, AtomicAction, EditEvent, Interaction, Selected},
+    grid::Grid,
+    import::Net,
+    index::{IndexedShape, ShapeGeometry, ShapeId},
+    screen_to_world_pos,
+    shapes::{CellElem, GeoPolygon, GeoRect, Poly, Rect},
+    tools::ActiveTool,
+    CursorWorldPos, InLayer,
+};
+
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy_egui::EguiContext;
+use bevy_prototype_lyon::prelude::{
+    shapes as lyon_shapes, DrawMode, FillMode, GeometryBuilder, Path as LyonPath, StrokeMode,
+};
+
+use geo::{coord, LineString};
+
+pub struct HandlesPlugin;
+
+impl Plugin for HandlesPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(HandleDrag::default())
+            .add_system_to_stage("pointer_events", grab_handle_system)
+            .add_system_to_stage("click_and_drag", drag_handle_system)
+            .add_system_to_stage("highlight", spawn_handles_system);
+    }
+}
+
+/// Half the width in pixels of a drawn handle.
+pub const HANDLE_SIZE_PX: f32 = 4.0;
+
+/// Handles are only drawn while at most this many shapes are selected.
+pub const MAX_HANDLED_SHAPES: usize = 64;
+
+/// The corners and edge midpoints of a rect, where it can be stretched from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RectHandle {
+    BottomLeft,
+    Left,
+    TopLeft,
+    Top,
+    TopRight,
+    Right,
+    BottomRight,
+    Bottom,
+}
+
+impl RectHandle {
+    pub const ALL: [RectHandle; 8] = [
+        RectHandle::BottomLeft,
+        RectHandle::Left,
+        RectHandle::TopLeft,
+        RectHandle::Top,
+        RectHandle::TopRight,
+        RectHandle::Right,
+        RectHandle::BottomRight,
+        RectHandle::Bottom,
+    ];
+
+    pub fn position(&self, rect: &GeoRect) -> Vec2 {
+        let (min, max) = (rect.min(), rect.max());
+        let (x0, y0, x1, y1) = (min.x as f32, min.y as f32, max.x as f32, max.y as f32);
+        let (xm, ym) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+
+        match self {
+            RectHandle::BottomLeft => Vec2::new(x0, y0),
+            RectHandle::Left => Vec2::new(x0, ym),
+            RectHandle::TopLeft => Vec2::new(x0, y1),
+            RectHandle::Top => Vec2::new(xm, y1),
+            RectHandle::TopRight => Vec2::new(x1, y1),
+            RectHandle::Right => Vec2::new(x1, ym),
+            RectHandle::BottomRight => Vec2::new(x1, y0),
+            RectHandle::Bottom => Vec2::new(xm, y0),
+        }
+    }
+
+    /// `rect` with this handle's corner or edge moved to `to`. Dragging an edge past the
+    /// opposite one flips the rect.
+    pub fn drag(&self, rect: &GeoRect, to: IVec2) -> GeoRect {
+        let (mut min, mut max) = (rect.min(), rect.max());
+
+        match self {
+            RectHandle::BottomLeft => {
+                min.x = to.x;
+                min.y = to.y;
+            }
+            RectHandle::Left => min.x = to.x,
+            RectHandle::TopLeft => {
+                min.x = to.x;
+                max.y = to.y;
+            }
+            RectHandle::Top => max.y = to.y,
+            RectHandle::TopRight => {
+                max.x = to.x;
+                max.y = to.y;
+            }
+            RectHandle::Right => max.x = to.x,
+            RectHandle::BottomRight => {
+                max.x = to.x;
+                min.y = to.y;
+            }
+            RectHandle::Bottom => min.y = to.y,
+        }
+
+        GeoRect::new(min, max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleKind {
+    Rect(RectHandle),
+    /// A vertex of a polygon's exterior.
+    Vertex(usize),
+    /// The edge of a polygon's exterior from vertex `i` to the next one.
+    Edge(usize),
+}
+
+impl HandleKind {
+    /// The geometry with this handle dragged from `grab` to `to`, `None` if that would
+    /// leave a rect without area. Edges are moved by a whole number of `grid` steps.
+    pub fn drag(
+        &self,
+        geometry: &ShapeGeometry,
+        grab: IVec2,
+        to: IVec2,
+        grid: &Grid,
+    ) -> Option<ShapeGeometry> {
+        match (self, geometry) {
+            (HandleKind::Rect(handle), ShapeGeometry::Rect(r)) => {
+                let r = handle.drag(r, to);
+                (r.width() != 0 && r.height() != 0).then(|| ShapeGeometry::Rect(r))
+            }
+            (HandleKind::Vertex(i), ShapeGeometry::Poly(p)) => {
+                let mut vertices = poly_vertices(p);
+                *vertices.get_mut(*i)? = to;
+                Some(ShapeGeometry::Poly(with_vertices(p, &vertices)))
+            }
+            (HandleKind::Edge(i), ShapeGeometry::Poly(p)) => {
+                let mut vertices = poly_vertices(p);
+                let n = vertices.len();
+                if *i >= n {
+                    return None;
+                }
+                let (a, b) = (vertices[*i], vertices[(*i + 1) % n]);
+                let delta = edge_normal_delta(b - a, to - grab, grid);
+                vertices[*i] += delta;
+                vertices[(*i + 1) % n] += delta;
+                Some(ShapeGeometry::Poly(with_vertices(p, &vertices)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            HandleKind::Rect(_) => "Resize rect",
+            HandleKind::Vertex(_) => "Move polygon vertex",
+            HandleKind::Edge(_) => "Move polygon edge",
+        }
+    }
+}
+
+/// The part of `delta` across an edge running along `edge`, so dragging an edge
+/// moves it sideways without shearing the polygon. Axis-aligned edges stay exactly
+/// on their axis, others move by the projection onto their normal snapped to the
+/// grid, so their vertices stay on it. 45° edges keep their angle.
+pub fn edge_normal_delta(edge: IVec2, delta: IVec2, grid: &Grid) -> IVec2 {
+    let delta = if edge == IVec2::ZERO {
+        delta.as_vec2()
+    } else if edge.x == 0 {
+        Vec2::new(delta.x as f32, 0.0)
+    } else if edge.y == 0 {
+        Vec2::new(0.0, delta.y as f32)
+    } else {
+        let normal = Vec2::new(-edge.y as f32, edge.x as f32);
+        normal * delta.as_vec2().dot(normal) / normal.length_squared()
+    };
+    grid.snap_vec2(delta).as_ivec2()
+}
+
+/// The vertices of a polygon's exterior, without the one closing the ring.
+pub fn poly_vertices(poly: &GeoPolygon) -> Vec<IVec2> {
+    let mut vertices = poly
+        .exterior()
+        .coords()
+        .map(|c| IVec2::new(c.x, c.y))
+        .collect::<Vec<IVec2>>();
+    if vertices.len() > 1 && vertices.first() == vertices.last() {
+        vertices.pop();
+    }
+    vertices
+}
+
+/// `poly` with its exterior replaced by `vertices`, keeping its holes.
+pub fn with_vertices(poly: &GeoPolygon, vertices: &[IVec2]) -> GeoPolygon {
+    let exterior = vertices
+        .iter()
+        .map(|v| coord! { x: v.x, y: v.y })
+        .collect::<LineString<i32>>();
+    GeoPolygon::new(exterior, poly.interiors().to_vec())
+}
+
+/// Every handle of a rect or polygon along with where it is, paths have none.
+pub fn geometry_handles(geometry: &ShapeGeometry) -> Vec<(HandleKind, Vec2)> {
+    match geometry {
+        ShapeGeometry::Rect(r) => RectHandle::ALL
+            .iter()
+            .map(|h| (HandleKind::Rect(*h), h.position(r)))
+            .collect(),
+        ShapeGeometry::Poly(p) => {
+            let vertices = poly_vertices(p)
+                .iter()
+                .map(|v| v.as_vec2())
+                .collect::<Vec<Vec2>>();
+            let n = vertices.len();
+            let edges = (0..n).map(|i| {
+                (
+                    HandleKind::Edge(i),
+                    (vertices[i] + vertices[(i + 1) % n]) / 2.0,
+                )
+            });
+            vertices
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (HandleKind::Vertex(i), *v))
+                .chain(edges)
+                .collect()
+        }
+        ShapeGeometry::Path(_) => vec![],
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy)]
+pub struct ShapeHandle {
+    pub shape: Entity,
+    pub kind: HandleKind,
+}
+
+/// A handle being dragged along with the shape as it was when the drag started.
+#[derive(Debug, Clone)]
+pub struct ActiveHandle {
+    pub entity: Entity,
+    pub id: ShapeId,
+    pub kind: HandleKind,
+    pub grab: IVec2,
+    pub before: IndexedShape,
+    pub after: Option<IndexedShape>,
+}
+
+#[derive(Debug, Default, Clone, Deref, DerefMut)]
+pub struct HandleDrag(pub Option<ActiveHandle>);
+
+/// Start dragging the handle under the cursor when the mouse is pressed. This runs
+/// before clicks are resolved so pressing on a handle never changes the selection.
+pub fn grab_handle_system(
+    tool: Res<ActiveTool>,
+    input_mouse: Res<Input<MouseButton>>,
+    mut egui_ctx: ResMut<EguiContext>,
+    windows: Res<Windows>,
+    camera_q: Query<(&Transform, &Camera)>,
+    proj_q: Query<&OrthographicProjection, With<Camera>>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    handle_q: Query<(&ShapeHandle, &Transform)>,
+    shape_q: Query<
+        (
+            &ShapeId,
+            &InLayer,
+            &Net,
+            &Transform,
+            Option<&CellElem>,
+            Option<&Rect>,
+            Option<&Poly>,
+        ),
+        With<Selected>,
+    >,
+    mut handle_drag: ResMut<HandleDrag>,
+) {
+    if *tool != ActiveTool::Select
+        || !input_mouse.just_pressed(MouseButton::Left)
+        || egui_ctx.ctx_mut().wants_pointer_input()
+    {
+        return;
+    }
+
+    let cursor = match windows.get_primary().and_then(|w| w.cursor_position()) {
+        Some(pos) => screen_to_world_pos(&windows, &camera_q, pos),
+        None => return,
+    };
+
+    let scale = proj_q.get_single().map_or(1.0, |proj| proj.scale);
+    let reach = HANDLE_SIZE_PX * scale;
+
+    let handle = handle_q
+        .iter()
+        .map(|(handle, t)| {
+            (
+                handle,
+                (t.translation.truncate() - cursor).abs().max_element(),
+            )
+        })
+        .filter(|(_, d)| *d <= reach)
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(handle, _)| *handle);
+
+    let ShapeHandle { shape, kind } = match handle {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    if let Ok((id, layer, net, t, elem, rect, poly)) = shape_q.get(shape) {
+        if let Some(before) =
+            IndexedShape::from_components(**layer, net, elem, None, rect, poly, None, t)
+        {
+            info!("Grabbed {kind:?} handle of {id:?}");
+            **handle_drag = Some(ActiveHandle {
+                entity: shape,
+                id: *id,
+                kind,
+                grab: cursor_world_pos.round().as_ivec2(),
+                before,
+                after: None,
+            });
+        }
+    }
+}
+
+/// Reshape the shape of the dragged handle as the cursor moves, and record the change
+/// in the undo history when the drag ends.
+pub fn drag_handle_system(
+    grid: Res<Grid>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    mut handle_drag: ResMut<HandleDrag>,
+    mut shape_q: Query<(
+        &mut LyonPath,
+        &DrawMode,
+        &Transform,
+        Option<&mut Rect>,
+        Option<&mut Poly>,
+    )>,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut interaction_ev: EventReader<Interaction>,
+) {
+    let active = match handle_drag.0.as_mut() {
+        Some(active) => active,
+        None => {
+            interaction_ev.iter().for_each(drop);
+            return;
+        }
+    };
+
+    if cursor_world_pos.is_changed() {
+        let to = cursor_world_pos.round().as_ivec2();
+
+        if let Some(geometry) = active
+            .kind
+            .drag(&active.before.geometry, active.grab, to, &grid)
+        {
+            if let Ok((mut lyon_path, draw_mode, t, rect, poly)) = shape_q.get_mut(active.entity) {
+                // the entity's geometry doesn't include the translation it has been dragged by
+                let local = geometry.translate(
+                    -t.translation.x.round() as i32,
+                    -t.translation.y.round() as i32,
+                );
+
+                *lyon_path = GeometryBuilder::build_as(
+                    &geometry_polygon(&local),
+                    *draw_mode,
+                    Transform::default(),
+                )
+                .path;
+
+                match (local, rect, poly) {
+                    (ShapeGeometry::Rect(r), Some(mut rect), _) => rect.0 = r,
+                    (ShapeGeometry::Poly(p), _, Some(mut poly)) => poly.0 = p,
+                    _ => (),
+                }
+            }
+
+            active.after = Some(IndexedShape {
+                geometry,
+                ..active.before.clone()
+            });
+        }
+    }
+
+    let ended = interaction_ev
+        .iter()
+        .any(|ev| matches!(ev, Interaction::DragEnd | Interaction::Click));
+
+    if !ended {
+        return;
+    }
+
+    if let Some(ActiveHandle {
+        id,
+        kind,
+        before,
+        after: Some(after),
+        ..
+    }) = handle_drag.take()
+    {
+        if after != before {
+            edit_ev.send(EditEvent {
+                description: kind.description().to_owned(),
+                action: AtomicAction::Modify {
+                    shapes: vec![(id, before, after)],
+                },
+            });
+        }
+    }
+}
+
+/// Draw the handles of the selected shapes of the loaded cell whenever the selection,
+/// one of the selected shapes or the zoom changes.
+pub fn spawn_handles_system(
+    mut commands: Commands,
+    tool: Res<ActiveTool>,
+    selected_q: Query<
+        (Entity, &Transform, Option<&Rect>, Option<&Poly>),
+        (With<Selected>, With<CellElem>),
+    >,
+    changed_q: Query<
+        (),
+        (
+            With<Selected>,
+            Or<(
+                Added<Selected>,
+                Changed<Transform>,
+                Changed<Rect>,
+                Changed<Poly>,
+            )>,
+        ),
+    >,
+    deselected: RemovedComponents<Selected>,
+    camera_q: Query<&OrthographicProjection, (With<Camera>, Changed<OrthographicProjection>)>,
+    handle_q: Query<Entity, With<ShapeHandle>>,
+    mut scale: Local<f32>,
+) {
+    let zoomed = match camera_q.get_single() {
+        Ok(proj) => {
+            *scale = proj.scale;
+            true
+        }
+        Err(_) => false,
+    };
+
+    if !(zoomed || tool.is_changed() || !changed_q.is_empty() || deselected.iter().next().is_some())
+    {
+        return;
+    }
+
+    for e in handle_q.iter() {
+        commands.entity(e).despawn();
+    }
+
+    if *tool != ActiveTool::Select || selected_q.iter().count() > MAX_HANDLED_SHAPES {
+        return;
+    }
+
+    let s = HANDLE_SIZE_PX * *scale;
+    let draw_mode = DrawMode::Outlined {
+        fill_mode: FillMode::color(Color::WHITE),
+        outline_mode: StrokeMode::new(Color::BLACK, *scale),
+    };
+
+    for (shape, t, rect, poly) in selected_q.iter() {
+        let geometry = match (rect, poly) {
+            (Some(rect), _) => ShapeGeometry::Rect(rect.0),
+            (_, Some(poly)) => ShapeGeometry::Poly(poly.0.clone()),
+            _ => continue,
+        };
+
+        for (kind, pos) in geometry_handles(&geometry) {
+            let pos = pos + t.translation.truncate();
+            commands
+                .spawn_bundle(GeometryBuilder::build_as(
+                    &lyon_shapes::Rectangle {
+                        extents: Vec2::splat(2.0 * s),
+                        origin: lyon_shapes::RectangleOrigin::Center,
+                    },
+                    draw_mode,
+                    Transform::from_translation(pos.extend(850.0)),
+                ))
+                .insert(ShapeHandle { shape, kind });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edge_normal_delta, geometry_handles, poly_vertices, HandleKind, RectHandle};
+    use crate::grid::Grid;
+    use crate::index::ShapeGeometry;
+    use crate::shapes::{GeoPolygon, GeoRect};
+    use bevy::prelude::IVec2;
+    use geo::{coord, LineString};
+
+    #[test]
+    fn stretch_rect() {
+        let rect = GeoRect::new(coord! { x: 0, y: 0 }, coord! { x: 100, y: 50 });
+
+        let r = RectHandle::TopRight.drag(&rect, IVec2::new(120, 80));
+        assert_eq!(
+            (r.min(), r.max()),
+            (coord! { x: 0, y: 0 }, coord! { x: 120, y: 80 })
+        );
+
+        // dragging the left edge past the right one flips the rect
+        let r = RectHandle::Left.drag(&rect, IVec2::new(150, 10));
+        assert_eq!(
+            (r.min(), r.max()),
+            (coord! { x: 100, y: 0 }, coord! { x: 150, y: 50 })
+        );
+
+        // a rect can't be squashed flat
+        let kind = HandleKind::Rect(RectHandle::Top);
+        let geometry = ShapeGeometry::Rect(rect);
+        assert!(kind
+            .drag(&geometry, IVec2::ZERO, IVec2::new(0, 0), &Grid::default())
+            .is_none());
+    }
+
+    #[test]
+    fn reshape_polygon() {
+        let exterior = LineString::from(vec![(0, 0), (100, 0), (100, 100), (0, 100)]);
+        let geometry = ShapeGeometry::Poly(GeoPolygon::new(exterior, vec![]));
+
+        // four vertices and four edges
+        assert_eq!(geometry_handles(&geometry).len(), 8);
+
+        let moved = HandleKind::Vertex(2)
+            .drag(
+                &geometry,
+                IVec2::ZERO,
+                IVec2::new(150, 120),
+                &Grid::default(),
+            )
+            .unwrap();
+        match &moved {
+            ShapeGeometry::Poly(p) => assert_eq!(
+                poly_vertices(p),
+                vec![
+                    IVec2::new(0, 0),
+                    IVec2::new(100, 0),
+                    IVec2::new(150, 120),
+                    IVec2::new(0, 100)
+                ]
+            ),
+            _ => panic!("expected a polygon"),
+        }
+
+        // the last edge wraps around to the first vertex
+        let moved = HandleKind::Edge(3)
+            .drag(
+                &geometry,
+                IVec2::new(0, 50),
+                IVec2::new(-20, 50),
+                &Grid::default(),
+            )
+            .unwrap();
+        match &moved {
+            ShapeGeometry::Poly(p) => assert_eq!(
+                poly_vertices(p),
+                vec![
+                    IVec2::new(-20, 0),
+                    IVec2::new(100, 0),
+                    IVec2::new(100, 100),
+                    IVec2::new(-20, 100)
+                ]
+            ),
+            _ => panic!("expected a polygon"),
+        }
+    }
+
+    #[test]
+    fn drag_edge_along_normal() {
+        let grid = Grid::default();
+        let exterior = LineString::from(vec![
+            (0, 0),
+            (100, 0),
+            (100, 50),
+            (50, 50),
+            (50, 100),
+            (0, 100),
+        ]);
+        let geometry = ShapeGeometry::Poly(GeoPolygon::new(exterior, vec![]));
+
+        // dragging the right edge diagonally only moves it right, the polygon stays Manhattan
+        let moved = HandleKind::Edge(1)
+            .drag(&geometry, IVec2::new(100, 25), IVec2::new(130, 45), &grid)
+            .unwrap();
+        match &moved {
+            ShapeGeometry::Poly(p) => assert_eq!(
+                poly_vertices(p),
+                vec![
+                    IVec2::new(0, 0),
+                    IVec2::new(130, 0),
+                    IVec2::new(130, 50),
+                    IVec2::new(50, 50),
+                    IVec2::new(50, 100),
+                    IVec2::new(0, 100)
+                ]
+            ),
+            _ => panic!("expected a polygon"),
+        }
+
+        // a diagonal edge moves across itself, not along
+        assert_eq!(
+            edge_normal_delta(IVec2::new(10, 10), IVec2::new(20, 0), &grid),
+            IVec2::new(10, -10)
+        );
+        assert_eq!(
+            edge_normal_delta(IVec2::new(10, 10), IVec2::new(5, 5), &grid),
+            IVec2::ZERO
+        );
+
+        // half a pitch across a 45° edge is snapped to a whole pitch along both axes,
+        // rather than rounded to units off the grid
+        let delta = edge_normal_delta(IVec2::new(10, 10), IVec2::new(5, 0), &grid);
+        assert_eq!(delta, IVec2::new(5, -5));
+        assert!(grid.is_on_grid(delta.as_vec2()));
+        assert_eq!(
+            edge_normal_delta(IVec2::new(10, -10), IVec2::new(0, -5), &grid),
+            IVec2::new(-5, -5)
+        );
+    }
+}
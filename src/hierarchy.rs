@@ -0,0 +1,515 @@
+use crate::editing::{PendingSelection, Selected};
+use crate::import::{
+    apply_display_depth, import_instance_shapes, load_cell_system, LoadCellEvent,
+    OpenVlsirLibEvent, VlsirCell, VlsirLib,
+};
+use crate::index::{Envelope, ShapeId, ShapeIndex};
+use crate::transform::LayoutTransform;
+use crate::WIDTH;
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use bevy_prototype_lyon::prelude::{shapes as lyon_shapes, DrawMode, GeometryBuilder, StrokeMode};
+use layout21::{
+    raw::{BoundBoxTrait, Cell},
+    utils::Ptr,
+};
+use rstar::{Envelope as _, AABB};
+
+pub struct HierarchyPlugin;
+
+impl Plugin for HierarchyPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InstanceTree::default())
+            .insert_resource(DisplayDepth::default())
+            .add_event::<HierarchyEvent>()
+            .add_system_to_stage("reset_world", reset_instance_tree_system)
+            .add_system_to_stage(
+                "import",
+                spawn_instance_entities_system.after(load_cell_system),
+            )
+            .add_system(hierarchy_event_system)
+            .add_system(highlight_instances_system)
+            .add_system(instance_placeholder_system);
+    }
+}
+
+/// Index of an instance in the loaded cell's [InstanceTree].
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InstanceId(pub usize);
+
+/// The name of the cell an instance entity is an instance of.
+#[derive(Component, Debug, Default, Clone, PartialEq, Eq, Deref, DerefMut)]
+pub struct InstanceOf(pub String);
+
+/// Marker component for shapes drawn highlighted because their instance is.
+#[derive(Component)]
+pub struct Highlighted;
+
+/// Outline drawn in place of the shapes of a collapsed instance.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct InstancePlaceholder(pub InstanceId);
+
+/// How many levels of instances are flattened when a cell is loaded, instances
+/// any deeper are collapsed into placeholders. `None` flattens all the way to the leaves.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct DisplayDepth(pub Option<usize>);
+
+#[derive(Bundle)]
+pub struct InstanceBundle {
+    pub id: InstanceId,
+    pub instance_of: InstanceOf,
+    pub name: Name,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceNode {
+    pub inst_name: String,
+    pub cell_name: String,
+    pub cell: Ptr<Cell>,
+    /// Places the instance's cell into the loaded cell's coordinates.
+    pub transform: LayoutTransform,
+    /// Bounding box of the instance in the loaded cell's coordinates.
+    pub bbox: Option<Envelope>,
+    pub parent: Option<InstanceId>,
+    pub children: Vec<InstanceId>,
+    pub depth: usize,
+    /// Whether the instance's shapes have been flattened into the [ShapeIndex] yet.
+    pub flattened: bool,
+}
+
+impl InstanceNode {
+    /// A node for an instance of `cell`, its depth and children are filled in
+    /// when it is pushed into an [InstanceTree].
+    pub fn new(
+        inst_name: String,
+        cell: Ptr<Cell>,
+        transform: LayoutTransform,
+        parent: Option<InstanceId>,
+    ) -> Self {
+        let (cell_name, bbox) = {
+            let read_cell = cell.read().unwrap();
+            let bbox = read_cell
+                .layout
+                .as_ref()
+                .map(|layout| layout.bbox())
+                .filter(|bbox| !bbox.is_empty())
+                .map(|bbox| {
+                    let corners = [
+                        transform.apply_xy(bbox.p0.x as f64, bbox.p0.y as f64),
+                        transform.apply_xy(bbox.p1.x as f64, bbox.p0.y as f64),
+                        transform.apply_xy(bbox.p1.x as f64, bbox.p1.y as f64),
+                        transform.apply_xy(bbox.p0.x as f64, bbox.p1.y as f64),
+                    ]
+                    .map(|(x, y)| [x, y]);
+                    AABB::from_points(corners.iter())
+                });
+            (read_cell.name.clone(), bbox)
+        };
+
+        Self {
+            inst_name,
+            cell_name,
+            cell,
+            transform,
+            bbox,
+            parent,
+            children: vec![],
+            depth: 0,
+            flattened: false,
+        }
+    }
+}
+
+/// Every instance in the loaded cell's hierarchy, instances of the loaded cell itself
+/// are the roots. Flattened shapes refer back to the instance they came from.
+#[derive(Debug, Default, Clone)]
+pub struct InstanceTree {
+    pub nodes: Vec<InstanceNode>,
+    pub roots: Vec<InstanceId>,
+    pub entities: HashMap<InstanceId, Entity>,
+    pub hidden: HashSet<InstanceId>,
+    pub highlighted: HashSet<InstanceId>,
+    /// Instances drawn as a placeholder bounding box instead of their shapes.
+    pub collapsed: HashSet<InstanceId>,
+}
+
+impl InstanceTree {
+    pub fn push(&mut self, mut node: InstanceNode) -> InstanceId {
+        let id = InstanceId(self.nodes.len());
+
+        node.depth = match node.parent {
+            Some(parent) => {
+                self.nodes[parent.0].children.push(id);
+                self.nodes[parent.0].depth + 1
+            }
+            None => {
+                self.roots.push(id);
+                0
+            }
+        };
+
+        self.nodes.push(node);
+
+        id
+    }
+
+    pub fn get(&self, id: InstanceId) -> &InstanceNode {
+        &self.nodes[id.0]
+    }
+
+    /// Whether `id` or any of its ancestors is in `set`.
+    fn any_ancestor_in(&self, id: Option<InstanceId>, set: &HashSet<InstanceId>) -> bool {
+        let mut id = id;
+        while let Some(inst) = id {
+            if set.contains(&inst) {
+                return true;
+            }
+            id = self.nodes[inst.0].parent;
+        }
+        false
+    }
+
+    /// Whether instance `id` is `ancestor` or one of its descendants.
+    pub fn is_within(&self, id: Option<InstanceId>, ancestor: InstanceId) -> bool {
+        let mut id = id;
+        while let Some(inst) = id {
+            if inst == ancestor {
+                return true;
+            }
+            id = self.nodes[inst.0].parent;
+        }
+        false
+    }
+
+    /// Whether the shapes of instance `id` are hidden, by it or one of its ancestors.
+    /// Shapes of the loaded cell itself (`None`) are never hidden.
+    pub fn is_hidden(&self, id: Option<InstanceId>) -> bool {
+        !self.hidden.is_empty() && self.any_ancestor_in(id, &self.hidden)
+    }
+
+    pub fn is_highlighted(&self, id: Option<InstanceId>) -> bool {
+        !self.highlighted.is_empty() && self.any_ancestor_in(id, &self.highlighted)
+    }
+
+    /// Whether the shapes of instance `id` are drawn as part of a placeholder,
+    /// because it or one of its ancestors is collapsed.
+    pub fn is_collapsed(&self, id: Option<InstanceId>) -> bool {
+        !self.collapsed.is_empty() && self.any_ancestor_in(id, &self.collapsed)
+    }
+
+    /// The collapsed instances that are currently drawn as placeholders.
+    pub fn placeholders(&self) -> impl Iterator<Item = (InstanceId, &InstanceNode)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (InstanceId(idx), node))
+            .filter(|(id, node)| {
+                self.collapsed.contains(id)
+                    && !self.is_collapsed(node.parent)
+                    && !self.is_hidden(Some(*id))
+            })
+    }
+
+    /// Bounding box of every placeholder.
+    pub fn placeholder_envelope(&self) -> Option<Envelope> {
+        self.placeholders()
+            .filter_map(|(_, node)| node.bbox)
+            .reduce(|acc, bbox| acc.merged(&bbox))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HierarchyEvent {
+    Select(InstanceId),
+    SetHidden(InstanceId, bool),
+    SetHighlighted(InstanceId, bool),
+    Expand(InstanceId),
+    Collapse(InstanceId),
+    /// Collapse or expand every instance in the tree to a new [DisplayDepth].
+    SetDisplayDepth(Option<usize>),
+}
+
+pub fn reset_instance_tree_system(
+    mut commands: Commands,
+    mut instance_tree: ResMut<InstanceTree>,
+    instance_q: Query<Entity, With<InstanceId>>,
+    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut load_cell_event_reader: EventReader<LoadCellEvent>,
+) {
+    let open = open_vlsir_lib_event_reader.iter().count() > 0;
+    let load = load_cell_event_reader.iter().count() > 0;
+
+    if open || load {
+        // the shapes parented to these are despawned with the rest of the lyon shapes
+        for e in instance_q.iter() {
+            commands.entity(e).despawn();
+        }
+        *instance_tree = InstanceTree::default();
+    }
+}
+
+/// Spawn an entity for every instance in the tree that doesn't have one yet, both
+/// when a cell is loaded and when a collapsed instance is expanded.
+pub fn spawn_instance_entities_system(
+    mut commands: Commands,
+    mut instance_tree: ResMut<InstanceTree>,
+) {
+    // check through the Deref so the tree isn't marked changed every frame
+    if instance_tree.entities.len() == instance_tree.nodes.len() {
+        return;
+    }
+
+    let mut entities = std::mem::take(&mut instance_tree.entities);
+    let already_spawned = entities.len();
+
+    // parents are always pushed into the tree before their children
+    for (idx, node) in instance_tree.nodes.iter().enumerate() {
+        let id = InstanceId(idx);
+
+        if entities.contains_key(&id) {
+            continue;
+        }
+
+        // shapes are flattened into the loaded cell's coordinates,
+        // so the instance entities themselves are never transformed
+        let entity = commands
+            .spawn_bundle(InstanceBundle {
+                id,
+                instance_of: InstanceOf(node.cell_name.clone()),
+                name: Name::new(node.inst_name.clone()),
+                transform: Transform::identity(),
+                global_transform: GlobalTransform::identity(),
+            })
+            .id();
+
+        if let Some(parent) = node.parent {
+            commands.entity(entities[&parent]).add_child(entity);
+        }
+
+        entities.insert(id, entity);
+    }
+
+    info!(
+        "Spawned {} instance entities",
+        entities.len() - already_spawned
+    );
+
+    instance_tree.entities = entities;
+}
+
+pub fn hierarchy_event_system(
+    mut commands: Commands,
+    vlsir_lib: Res<VlsirLib>,
+    mut vlsir_cell: ResMut<VlsirCell>,
+    mut shape_index: ResMut<ShapeIndex>,
+    mut instance_tree: ResMut<InstanceTree>,
+    mut display_depth: ResMut<DisplayDepth>,
+    mut pending_selection: ResMut<PendingSelection>,
+    shape_q: Query<(Entity, &ShapeId)>,
+    selected_q: Query<Entity, With<Selected>>,
+    mut hierarchy_event_reader: EventReader<HierarchyEvent>,
+) {
+    for ev in hierarchy_event_reader.iter() {
+        match *ev {
+            HierarchyEvent::Select(id) => {
+                for selected in selected_q.iter() {
+                    commands.entity(selected).remove::<Selected>();
+                }
+
+                // from the index, so shapes of the instance that are out of view are
+                // selected too, as they are streamed in
+                let mut pending = shape_index
+                    .iter()
+                    .filter(|(_, shape)| {
+                        instance_tree.is_within(shape.instance, id)
+                            && !instance_tree.is_hidden(shape.instance)
+                            && !instance_tree.is_collapsed(shape.instance)
+                    })
+                    .map(|(shape_id, _)| *shape_id)
+                    .collect::<HashSet<ShapeId>>();
+
+                info!(
+                    "Selecting {} shapes of instance '{}'",
+                    pending.len(),
+                    instance_tree.get(id).inst_name
+                );
+
+                for (e, shape_id) in shape_q.iter() {
+                    if pending.remove(shape_id) {
+                        commands.entity(e).insert(Selected);
+                    }
+                }
+
+                **pending_selection = pending;
+            }
+            HierarchyEvent::SetHidden(id, hidden) => {
+                if hidden {
+                    instance_tree.hidden.insert(id);
+                } else {
+                    instance_tree.hidden.remove(&id);
+                }
+            }
+            HierarchyEvent::SetHighlighted(id, highlighted) => {
+                if highlighted {
+                    instance_tree.highlighted.insert(id);
+                } else {
+                    instance_tree.highlighted.remove(&id);
+                }
+            }
+            HierarchyEvent::Expand(id) => {
+                let lib = match vlsir_lib.lib.as_ref() {
+                    Some(lib) => lib,
+                    None => continue,
+                };
+
+                if !instance_tree.get(id).flattened {
+                    let t = std::time::Instant::now();
+
+                    // only flatten one more level, the instance's own instances stay collapsed
+                    let depth = instance_tree.get(id).depth + 1;
+                    let mut shapes = vec![];
+                    import_instance_shapes(
+                        id,
+                        &lib.layers,
+                        Some(depth),
+                        &mut instance_tree,
+                        &mut shapes,
+                    );
+
+                    info!(
+                        "Expanded instance '{}' into {} shapes in {:?}",
+                        instance_tree.get(id).inst_name,
+                        shapes.len(),
+                        t.elapsed()
+                    );
+
+                    for shape in shapes {
+                        shape_index.insert(shape);
+                    }
+                    vlsir_cell.num_shapes = Some(shape_index.len() as u64);
+                }
+
+                instance_tree.collapsed.remove(&id);
+            }
+            HierarchyEvent::Collapse(id) => {
+                instance_tree.collapsed.insert(id);
+            }
+            HierarchyEvent::SetDisplayDepth(depth) => {
+                **display_depth = depth;
+
+                let lib = match vlsir_lib.lib.as_ref() {
+                    Some(lib) => lib,
+                    None => continue,
+                };
+
+                // in place rather than loading the cell again, which would lose its edits
+                let mut shapes = vec![];
+                apply_display_depth(depth, &lib.layers, &mut instance_tree, &mut shapes);
+
+                info!(
+                    "Display depth {depth:?} flattened {} more shapes, {} instances collapsed",
+                    shapes.len(),
+                    instance_tree.collapsed.len()
+                );
+
+                for shape in shapes {
+                    shape_index.insert(shape);
+                }
+                vlsir_cell.num_shapes = Some(shape_index.len() as u64);
+            }
+        }
+    }
+}
+
+/// Respawn the placeholder outlines of collapsed instances whenever the tree changes.
+pub fn instance_placeholder_system(
+    mut commands: Commands,
+    instance_tree: Res<InstanceTree>,
+    placeholder_q: Query<Entity, With<InstancePlaceholder>>,
+) {
+    if !instance_tree.is_changed() {
+        return;
+    }
+
+    for e in placeholder_q.iter() {
+        commands.entity(e).despawn();
+    }
+
+    for (id, node) in instance_tree.placeholders() {
+        let bbox = match node.bbox {
+            Some(bbox) => bbox,
+            None => continue,
+        };
+
+        let [x_min, y_min] = bbox.lower();
+        let [x_max, y_max] = bbox.upper();
+
+        let lyon_poly = lyon_shapes::Polygon {
+            points: vec![
+                (x_min as f32, y_min as f32).into(),
+                (x_max as f32, y_min as f32).into(),
+                (x_max as f32, y_max as f32).into(),
+                (x_min as f32, y_max as f32).into(),
+            ],
+            closed: true,
+        };
+
+        // draw on top of every layer, layer z indices go up to 255
+        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, 256.0));
+
+        commands
+            .spawn_bundle(GeometryBuilder::build_as(
+                &lyon_poly,
+                DrawMode::Stroke(StrokeMode::new(Color::GRAY, WIDTH)),
+                transform,
+            ))
+            .insert(InstancePlaceholder(id));
+    }
+}
+
+/// Draw the outlines of the shapes of highlighted instances in white.
+pub fn highlight_instances_system(
+    mut commands: Commands,
+    instance_tree: Res<InstanceTree>,
+    new_shape_q: Query<Entity, Added<ShapeId>>,
+    mut shape_q: Query<(Entity, Option<&Parent>, &mut DrawMode, Option<&Highlighted>)>,
+    instance_q: Query<&InstanceId>,
+    mut original_colors: Local<HashMap<Entity, Color>>,
+) {
+    if !instance_tree.is_changed() && new_shape_q.is_empty() {
+        return;
+    }
+
+    if instance_tree.highlighted.is_empty() && original_colors.is_empty() {
+        return;
+    }
+
+    for (entity, parent, mut draw, highlighted) in shape_q.iter_mut() {
+        let instance = parent.and_then(|p| instance_q.get(p.0).ok()).copied();
+        let should_highlight = instance_tree.is_highlighted(instance);
+
+        if let DrawMode::Outlined {
+            ref mut outline_mode,
+            ..
+        } = *draw
+        {
+            if should_highlight && highlighted.is_none() {
+                original_colors.insert(entity, outline_mode.color);
+                outline_mode.color = Color::WHITE;
+                commands.entity(entity).insert(Highlighted);
+            } else if !should_highlight && highlighted.is_some() {
+                if let Some(color) = original_colors.remove(&entity) {
+                    outline_mode.color = color;
+                }
+                commands.entity(entity).remove::<Highlighted>();
+            }
+        }
+    }
+
+    // forget about the colors of highlighted shapes that have since been despawned
+    original_colors.retain(|e, _| shape_q.get(*e).is_ok());
+}
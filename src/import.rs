@@ -1,11 +1,18 @@
-use crate::editing::ShapeStack;
+use crate::editing::{Selected, ShapeStack};
+use crate::hierarchy::{
+    spawn_instance_entities_system, DisplayDepth, InstanceId, InstanceNode, InstanceTree,
+};
+use crate::index::{Envelope, IndexedShape, ShapeGeometry, ShapeId, ShapeIndex};
 use crate::shapes::{
-    GeoPolygon, GeoRect, Path, PathBundle, Poly, PolyBundle, Rect, RectBundle, ShapeBundle,
+    CellElem, GeoPolygon, GeoRect, Path, PathBundle, Poly, PolyBundle, Rect, RectBundle,
+    ShapeBundle,
 };
+use crate::tech::{style_layers, FillPattern, LayerMap};
+use crate::transform::LayoutTransform;
 use crate::ui::{LayersUIState, LibInfoUIDropdownState};
-use crate::{InLayer, UpdateViewportEvent, ViewportDimensions, ALPHA, WIDTH};
+use crate::{InLayer, UpdateViewportEvent, ViewportDimensions, WIDTH};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use bevy::prelude::*;
 
@@ -18,10 +25,12 @@ use bevy_prototype_lyon::prelude::{
 
 use futures_lite::future;
 
+use rstar::Envelope as _;
+
 use layout21::{
     raw::{
-        self, proto::proto, proto::ProtoImporter, Abstract, BoundBox, BoundBoxTrait, Cell, Element,
-        Instance, Layout, Library, Point, Shape,
+        self, gds::gds21::GdsLibrary, gds::GdsImporter, proto::proto, proto::ProtoImporter,
+        Abstract, BoundBox, BoundBoxTrait, Cell, Element, Instance, Layout, Library, Point, Shape,
     },
     utils::Ptr,
 };
@@ -60,17 +69,121 @@ pub fn get_shapes(cell: &Cell) -> Iter<Element> {
     cell.layout.as_ref().unwrap().elems.iter()
 }
 
+/// File extensions (lowercase) that are opened with layout21's `GdsImporter`.
+pub const GDS_EXTENSIONS: [&str; 3] = ["gds", "gds2", "gdsii"];
+
+/// File extensions (lowercase) that are opened with layout21's `ProtoImporter`.
+pub const PROTO_EXTENSIONS: [&str; 1] = ["proto"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibFileFormat {
+    Proto,
+    Gds,
+}
+
+impl LibFileFormat {
+    /// Determine the library file format from the extension of `path`,
+    /// defaulting to VLSIR proto for anything that isn't a known GDSII extension.
+    pub fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        match ext {
+            Some(ext) if GDS_EXTENSIONS.contains(&ext.as_str()) => LibFileFormat::Gds,
+            _ => LibFileFormat::Proto,
+        }
+    }
+}
+
+/// The file name of a library path without its directory or extension, for display.
+pub fn lib_file_stem(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(path)
+        .to_owned()
+}
+
 #[derive(Debug, Default)]
 pub struct VlsirLib {
     pub path: Option<String>,
     pub lib: Option<Library>,
     pub cell_names: Option<Vec<String>>,
+    /// Contents of each cell, in the same order as `cell_names`.
+    pub cell_contents: Option<Vec<CellContentsInfo>>,
 }
 
 #[derive(Debug, Default, PartialEq, Clone)]
 pub struct Layer {
     pub name: Option<String>,
     pub color: Color,
+    pub fill: FillPattern,
+    /// Whether the layer is visible when a library is first loaded.
+    pub visible: bool,
+    /// Z translation of the layer's shapes, higher is drawn on top.
+    pub z: f32,
+    pub fill_alpha: f32,
+    pub outline_width: f32,
+    pub show_fill: bool,
+    pub show_outline: bool,
+    /// Width of the wires drawn on the layer with the path tool.
+    pub path_width: Option<usize>,
+}
+
+impl Layer {
+    pub fn new(
+        name: Option<String>,
+        color: Color,
+        fill: FillPattern,
+        visible: bool,
+        z: f32,
+    ) -> Self {
+        Self {
+            name,
+            color,
+            fill,
+            visible,
+            z,
+            fill_alpha: fill.alpha(),
+            outline_width: WIDTH,
+            show_fill: fill != FillPattern::Hollow,
+            show_outline: true,
+            path_width: None,
+        }
+    }
+
+    /// Opacity of the fill of the layer's unhighlighted shapes.
+    pub fn fill_alpha(&self) -> f32 {
+        if self.show_fill {
+            self.fill_alpha
+        } else {
+            0.0
+        }
+    }
+
+    /// How to draw the layer's shapes. Paths are drawn as a stroke of their own width,
+    /// which is treated as their fill when the layer's outlines are hidden.
+    pub fn draw_mode(&self, path_width: Option<f32>) -> DrawMode {
+        let (outline_width, outline_alpha) = match path_width {
+            Some(width) if self.show_outline => (width, 1.0),
+            Some(width) => (width, self.fill_alpha()),
+            None if self.show_outline => (self.outline_width, 1.0),
+            None => (self.outline_width, 0.0),
+        };
+
+        DrawMode::Outlined {
+            fill_mode: FillMode {
+                color: *self.color.clone().set_a(self.fill_alpha()),
+                options: FillOptions::default(),
+            },
+            outline_mode: StrokeMode {
+                options: StrokeOptions::default().with_line_width(outline_width),
+                color: *self.color.clone().set_a(outline_alpha),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Deref, DerefMut)]
@@ -87,6 +200,10 @@ pub struct CellContentsInfo {
     pub cell_name: String,
     pub layout: Option<LayoutInfo>,
     pub abstrakt: Option<AbstraktInfo>,
+    /// Indices of the cells this cell instantiates, each only once.
+    pub children: Vec<usize>,
+    /// Indices of the cells that instantiate this cell.
+    pub parents: Vec<usize>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -95,6 +212,7 @@ pub struct LayoutInfo {
     pub elems: usize,
     pub insts: usize,
     pub annotations: usize,
+    pub bbox: BoundBox,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -113,8 +231,14 @@ impl Plugin for Layout21ImportPlugin {
             .insert_resource(Layers::default())
             .insert_resource(VlsirLib::default())
             .insert_resource(VlsirCell::default())
+            .insert_resource(ShapeIndex::default())
+            .insert_resource(StreamedShapes::default())
+            .insert_resource(PendingVlsirLib::default())
+            .add_event::<ReadVlsirLibEvent>()
+            .add_event::<NewVlsirLibEvent>()
             .add_event::<OpenVlsirLibEvent>()
             .add_event::<OpenVlsirLibCompleteEvent>()
+            .add_event::<OpenVlsirLibFailedEvent>()
             .add_event::<ImportLibCompleteEvent>()
             .add_event::<LoadCellEvent>()
             .add_event::<LoadCellCompleteEvent>()
@@ -136,10 +260,12 @@ impl Plugin for Layout21ImportPlugin {
                 SystemSet::new()
                     .with_system(spawn_vlsir_open_task_sytem)
                     .with_system(handle_vlsir_open_task_system)
+                    .with_system(new_lib_system)
                     .with_system(vlsir_open_task_duration_system)
                     .with_system(import_lib_system)
                     .with_system(load_cell_system)
                     .with_system(load_cell_complete_system)
+                    .with_system(stream_visible_shapes_system.after(spawn_instance_entities_system))
                     .with_system(import_path_system)
                     .with_system(import_rect_system)
                     .with_system(import_poly_system),
@@ -150,14 +276,39 @@ impl Plugin for Layout21ImportPlugin {
 #[derive(Component, Debug, Default, Clone, PartialEq, PartialOrd, Deref, DerefMut)]
 pub struct Net(pub Option<String>);
 
+/// Read the library at `path` in the background, the loaded library stays loaded until
+/// it has been read successfully.
 #[derive(Debug, Default, Clone)]
-pub struct OpenVlsirLibEvent {
+pub struct ReadVlsirLibEvent {
     pub path: String,
 }
 
+/// Replace the loaded library with a new one holding a single empty cell.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NewVlsirLibEvent;
+
+/// The library at `path` was read and replaces the loaded one, everything about the
+/// loaded library and cell is reset. `path` is `None` for a new library that hasn't
+/// been saved yet.
+#[derive(Debug, Default, Clone)]
+pub struct OpenVlsirLibEvent {
+    pub path: Option<String>,
+}
+
+/// A library that was read successfully or newly created, installed as the loaded
+/// library once the world has been reset for it.
+#[derive(Default)]
+pub struct PendingVlsirLib(pub Option<(Option<String>, Library)>);
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct OpenVlsirLibCompleteEvent;
 
+#[derive(Debug, Default, Clone)]
+pub struct OpenVlsirLibFailedEvent {
+    pub path: String,
+    pub error: String,
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct ImportLibCompleteEvent;
 
@@ -168,24 +319,36 @@ pub struct LoadCellEvent(pub usize);
 pub struct LoadCellCompleteEvent;
 
 pub struct ImportRectEvent {
+    pub id: ShapeId,
     pub rect: Rect,
     pub net: Net,
     pub layer: u8,
-    pub color: Color,
+    pub draw_mode: DrawMode,
+    pub z: f32,
+    pub elem: Option<CellElem>,
+    pub parent: Option<Entity>,
 }
 
 pub struct ImportPolyEvent {
+    pub id: ShapeId,
     pub poly: Poly,
     pub net: Net,
     pub layer: u8,
-    pub color: Color,
+    pub draw_mode: DrawMode,
+    pub z: f32,
+    pub elem: Option<CellElem>,
+    pub parent: Option<Entity>,
 }
 
 pub struct ImportPathEvent {
+    pub id: ShapeId,
     pub path: Path,
     pub net: Net,
     pub layer: u8,
-    pub color: Color,
+    pub draw_mode: DrawMode,
+    pub z: f32,
+    pub elem: Option<CellElem>,
+    pub parent: Option<Entity>,
 }
 
 pub fn load_cell_complete_system(
@@ -195,48 +358,125 @@ pub fn load_cell_complete_system(
 
 pub fn spawn_vlsir_open_task_sytem(
     mut commands: Commands,
-    mut vlsir_lib: ResMut<VlsirLib>,
-    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut read_vlsir_lib_event_reader: EventReader<ReadVlsirLibEvent>,
     thread_pool: Res<AsyncComputeTaskPool>,
 ) {
-    for OpenVlsirLibEvent { path } in open_vlsir_lib_event_reader.iter() {
-        vlsir_lib.path = Some(path.clone());
-
-        let path = vlsir_lib.path.clone().unwrap();
+    for ReadVlsirLibEvent { path } in read_vlsir_lib_event_reader.iter() {
+        let path = path.clone();
 
-        let task: Task<Library> = thread_pool.spawn(async move {
+        let task: Task<OpenVlsirLibResult> = thread_pool.spawn(async move {
             // enable to test UI Lib Info "Library:" loading spinner animation
             // std::thread::sleep(std::time::Duration::from_secs(5));
-            let plib: proto::Library = proto::open(path).unwrap();
-            ProtoImporter::import(&plib, None).unwrap()
+
+            // layout21's importers can also panic on malformed input, catch that here so
+            // the task always completes and the UI can report the failure
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| open_lib(&path)))
+                .unwrap_or_else(|panic| {
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown error".to_owned());
+                    Err(format!("importer panicked: {msg}"))
+                });
+
+            OpenVlsirLibResult { path, result }
         });
         commands.spawn().insert(task);
     }
 }
 
+pub struct OpenVlsirLibResult {
+    pub path: String,
+    pub result: Result<Library, String>,
+}
+
+/// Read and import the library at `path` in the format given by its file extension.
+pub fn open_lib(path: &str) -> Result<Library, String> {
+    match LibFileFormat::from_path(path) {
+        LibFileFormat::Proto => {
+            let plib: proto::Library =
+                proto::open(path).map_err(|e| format!("failed to read VLSIR proto file: {e:?}"))?;
+            ProtoImporter::import(&plib, None)
+                .map_err(|e| format!("failed to import VLSIR proto library: {e:?}"))
+        }
+        LibFileFormat::Gds => {
+            let gds =
+                GdsLibrary::load(path).map_err(|e| format!("failed to read GDSII file: {e:?}"))?;
+            GdsImporter::import(&gds, None)
+                .map_err(|e| format!("failed to import GDSII library: {e:?}"))
+        }
+    }
+}
+
 pub fn handle_vlsir_open_task_system(
     mut commands: Commands,
-    mut lib: ResMut<VlsirLib>,
-    mut vlsir_open_task_q: Query<(Entity, &mut Task<Library>)>,
-    mut vlsir_open_lib_complete_event_writer: EventWriter<OpenVlsirLibCompleteEvent>,
+    mut pending_lib: ResMut<PendingVlsirLib>,
+    mut vlsir_open_task_q: Query<(Entity, &mut Task<OpenVlsirLibResult>)>,
+    mut open_vlsir_lib_event_writer: EventWriter<OpenVlsirLibEvent>,
+    mut vlsir_open_lib_failed_event_writer: EventWriter<OpenVlsirLibFailedEvent>,
 ) {
     for (entity, mut task) in vlsir_open_task_q.iter_mut() {
-        if let Some(vlsir_lib) = future::block_on(future::poll_once(&mut *task)) {
-            lib.lib = Some(vlsir_lib);
-            vlsir_open_lib_complete_event_writer.send(OpenVlsirLibCompleteEvent);
+        if let Some(OpenVlsirLibResult { path, result }) =
+            future::block_on(future::poll_once(&mut *task))
+        {
+            match result {
+                Ok(vlsir_lib) => {
+                    // installed by reset_state_on_new_lib_import after it resets the world
+                    pending_lib.0 = Some((Some(path.clone()), vlsir_lib));
+                    open_vlsir_lib_event_writer.send(OpenVlsirLibEvent { path: Some(path) });
+                }
+                Err(error) => {
+                    // the loaded library, if any, is left as it was
+                    error!("Failed to open lib file '{path}': {error}");
+                    vlsir_open_lib_failed_event_writer
+                        .send(OpenVlsirLibFailedEvent { path, error });
+                }
+            }
             commands.entity(entity).despawn();
         }
     }
 }
 
+/// Name of a new library and of its cell.
+pub const NEW_LIB_NAME: &str = "untitled";
+
+/// A library in nanometer units with a single cell with an empty layout.
+pub fn new_lib() -> Library {
+    let mut lib = Library::new(NEW_LIB_NAME, raw::Units::Nano);
+    lib.cells.add(Cell {
+        name: NEW_LIB_NAME.to_owned(),
+        abs: None,
+        layout: Some(Layout {
+            name: NEW_LIB_NAME.to_owned(),
+            insts: vec![],
+            elems: vec![],
+            annotations: vec![],
+        }),
+    });
+    lib
+}
+
+pub fn new_lib_system(
+    mut pending_lib: ResMut<PendingVlsirLib>,
+    mut new_vlsir_lib_event_reader: EventReader<NewVlsirLibEvent>,
+    mut open_vlsir_lib_event_writer: EventWriter<OpenVlsirLibEvent>,
+) {
+    for _ in new_vlsir_lib_event_reader.iter() {
+        // installed by reset_state_on_new_lib_import after it resets the world
+        pending_lib.0 = Some((None, new_lib()));
+        open_vlsir_lib_event_writer.send(OpenVlsirLibEvent { path: None });
+    }
+}
+
 pub fn vlsir_open_task_duration_system(
     time: Res<Time>,
     mut duration: Local<f64>,
     mut path: Local<Option<String>>,
-    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut read_vlsir_lib_event_reader: EventReader<ReadVlsirLibEvent>,
     mut open_vlsir_lib_complete_event_reader: EventReader<OpenVlsirLibCompleteEvent>,
 ) {
-    for OpenVlsirLibEvent { path: p } in open_vlsir_lib_event_reader.iter() {
+    for ReadVlsirLibEvent { path: p } in read_vlsir_lib_event_reader.iter() {
         *duration = time.seconds_since_startup();
         *path = Some(p.clone());
     }
@@ -251,6 +491,7 @@ pub fn vlsir_open_task_duration_system(
 
 pub fn import_lib_system(
     mut vlsir_lib: ResMut<VlsirLib>,
+    layer_map: Res<LayerMap>,
     mut layer_colors: ResMut<LayerColors>,
     mut layers: ResMut<Layers>,
     mut vlsir_open_lib_complete_event_reader: EventReader<OpenVlsirLibCompleteEvent>,
@@ -259,25 +500,8 @@ pub fn import_lib_system(
 ) {
     for _ in vlsir_open_lib_complete_event_reader.iter() {
         let lib = vlsir_lib.lib.as_ref().unwrap();
-        {
-            let lib_layers = &lib.layers.read().unwrap().slots;
-
-            for raw::Layer { layernum, name, .. } in lib_layers.values() {
-                let num = *layernum as u8;
-                if let Some(_) = layers.insert(
-                    num,
-                    Layer {
-                        name: name.clone(),
-                        color: layer_colors.get_color(),
-                    },
-                ) {
-                    panic!(
-                        "Library layers corrupted multiple definitions for layer number {}",
-                        num
-                    );
-                }
-            }
-        }
+
+        *layers = style_layers(lib, &layer_map, &mut layer_colors);
 
         let cell_names = lib
             .cells
@@ -318,7 +542,13 @@ pub fn import_lib_system(
         //     })
         //     .collect::<Vec<(isize, isize)>>();
 
-        let dbg_cell_contents_info = lib
+        let cell_indices = cell_names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.as_str(), idx))
+            .collect::<HashMap<&str, usize>>();
+
+        let mut cell_contents = lib
             .cells
             .iter()
             .map(|c| {
@@ -326,18 +556,31 @@ pub fn import_lib_system(
 
                 let name = c.name.clone();
 
-                let layout = if let Some(Layout {
-                    annotations,
-                    elems,
-                    insts,
-                    name,
-                }) = c.layout.as_ref()
-                {
+                let mut children = vec![];
+
+                let layout = if let Some(layout) = c.layout.as_ref() {
+                    let Layout {
+                        annotations,
+                        elems,
+                        insts,
+                        name,
+                    } = layout;
+
+                    for inst in insts.iter() {
+                        let inst_cell = inst.cell.read().unwrap();
+                        if let Some(&idx) = cell_indices.get(inst_cell.name.as_str()) {
+                            if !children.contains(&idx) {
+                                children.push(idx);
+                            }
+                        }
+                    }
+
                     Some(LayoutInfo {
                         layout_name: name.clone(),
                         elems: elems.len(),
                         insts: insts.len(),
                         annotations: annotations.len(),
+                        bbox: layout.bbox(),
                     })
                 } else {
                     None
@@ -366,13 +609,21 @@ pub fn import_lib_system(
                     cell_name: name.clone(),
                     layout,
                     abstrakt,
+                    children,
+                    parents: vec![],
                 }
             })
             .collect::<Vec<CellContentsInfo>>();
 
+        for idx in 0..cell_contents.len() {
+            for child in cell_contents[idx].children.clone() {
+                cell_contents[child].parents.push(idx);
+            }
+        }
+
         // let mut f = std::fs::File::create(format!("{}_cell_contents.dbg", lib.name)).unwrap();
         // use std::io::Write;
-        // f.write(format!("{dbg_cell_contents_info:#?}").as_bytes())
+        // f.write(format!("{cell_contents:#?}").as_bytes())
         //     .unwrap();
 
         // let max_magnitudes =
@@ -390,6 +641,7 @@ pub fn import_lib_system(
         // info!("Largest cell extents in this library: [ x: {max_x}, y: {max_y} ]");
 
         vlsir_lib.cell_names = Some(cell_names);
+        vlsir_lib.cell_contents = Some(cell_contents);
 
         import_lib_complete_event_writer.send(ImportLibCompleteEvent);
         load_cell_event_writer.send(LoadCellEvent(0));
@@ -403,9 +655,13 @@ pub fn reset_state_on_new_lib_import(
     mut layers: ResMut<Layers>,
     mut vlsir_lib: ResMut<VlsirLib>,
     mut shape_stack: ResMut<ShapeStack>,
+    mut shape_index: ResMut<ShapeIndex>,
+    mut streamed_shapes: ResMut<StreamedShapes>,
     mut ui_dropdown_state: ResMut<LibInfoUIDropdownState>,
     mut ui_layer_state: ResMut<LayersUIState>,
+    mut pending_lib: ResMut<PendingVlsirLib>,
     mut vlsir_open_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut vlsir_open_lib_complete_event_writer: EventWriter<OpenVlsirLibCompleteEvent>,
 ) {
     for _ in vlsir_open_lib_event_reader.iter() {
         info!("All state reset on new lib import!");
@@ -414,12 +670,20 @@ pub fn reset_state_on_new_lib_import(
         *layers = Layers::default();
         *vlsir_lib = VlsirLib::default();
         *shape_stack = ShapeStack::default();
+        *shape_index = ShapeIndex::default();
+        *streamed_shapes = StreamedShapes::default();
         ui_dropdown_state.selected = 0;
         ui_layer_state.layers = vec![];
 
         for e in query.iter() {
             commands.entity(e).despawn();
         }
+
+        if let Some((path, lib)) = pending_lib.0.take() {
+            vlsir_lib.path = path;
+            vlsir_lib.lib = Some(lib);
+            vlsir_open_lib_complete_event_writer.send(OpenVlsirLibCompleteEvent);
+        }
     }
 }
 
@@ -428,9 +692,11 @@ pub fn reset_state_on_new_cell_import(
     query: Query<Entity, With<entity::Path>>,
     mut load_cell_event_reader: EventReader<LoadCellEvent>,
     mut shape_stack: ResMut<ShapeStack>,
+    mut streamed_shapes: ResMut<StreamedShapes>,
 ) {
     for _ in load_cell_event_reader.iter() {
         *shape_stack = ShapeStack::default();
+        *streamed_shapes = StreamedShapes::default();
         for e in query.iter() {
             commands.entity(e).despawn();
         }
@@ -440,16 +706,15 @@ pub fn reset_state_on_new_cell_import(
 pub fn load_cell_system(
     vlsir_lib: Res<VlsirLib>,
     mut cell_info: ResMut<VlsirCell>,
-    layers: Res<Layers>,
+    mut shape_index: ResMut<ShapeIndex>,
+    mut instance_tree: ResMut<InstanceTree>,
+    display_depth: Res<DisplayDepth>,
     mut update_viewport_event_writer: EventWriter<UpdateViewportEvent>,
     mut load_cell_event_reader: EventReader<LoadCellEvent>,
     mut load_cell_complete_event_writer: EventWriter<LoadCellCompleteEvent>,
-    mut import_rect_event_writer: EventWriter<ImportRectEvent>,
-    mut import_poly_event_writer: EventWriter<ImportPolyEvent>,
-    mut import_path_event_writer: EventWriter<ImportPathEvent>,
 ) {
     for &cell_idx in load_cell_event_reader.iter() {
-        if let (Some(lib), Some(_)) = (vlsir_lib.lib.as_ref(), layers.iter().nth(0)) {
+        if let Some(lib) = vlsir_lib.lib.as_ref() {
             let t = std::time::Instant::now();
 
             cell_info.index = Some(*cell_idx);
@@ -461,40 +726,57 @@ pub fn load_cell_system(
             let len_elems = cell.read().unwrap().layout.as_ref().unwrap().elems.len();
             let len_insts = cell.read().unwrap().layout.as_ref().unwrap().insts.len();
 
+            *shape_index = ShapeIndex::default();
+
             if len_elems == 0 && len_insts == 0 {
+                cell_info.num_shapes = Some(0);
                 continue;
             }
 
-            // let mut f = std::fs::File::create("cell_insts_debug").unwrap();
-            // use std::io::Write;
-            // f.write(
-            //     format!(
-            //         "Cell '{}' num_el: {} instances: {:#?}",
-            //         cell.read().unwrap().layout.as_ref().unwrap().name,
-            //         cell.read().unwrap().layout.as_ref().unwrap().elems.len(),
-            //         cell.read().unwrap().layout.as_ref().unwrap().insts
-            //     )
-            //     .as_bytes(),
-            // )
-            // .unwrap();
-
-            let mut shape_count: u64 = 0;
+            let mut shapes = vec![];
 
             import_cell_shapes(
                 &cell,
-                false,
-                &mut shape_count,
-                &Point::default(),
-                &mut cell_info,
+                None,
+                &LayoutTransform::identity(),
                 &lib_layers,
-                &layers,
-                &mut update_viewport_event_writer,
-                &mut import_rect_event_writer,
-                &mut import_poly_event_writer,
-                &mut import_path_event_writer,
+                **display_depth,
+                &mut instance_tree,
+                &mut shapes,
+            );
+
+            info!(
+                "Cell hierarchy has {} instances, {} collapsed",
+                instance_tree.nodes.len(),
+                instance_tree.collapsed.len()
             );
 
-            cell_info.num_shapes = Some(shape_count);
+            cell_info.num_shapes = Some(shapes.len() as u64);
+
+            *shape_index = ShapeIndex::from_shapes(shapes);
+
+            let envelope = [shape_index.envelope(), instance_tree.placeholder_envelope()]
+                .into_iter()
+                .flatten()
+                .reduce(|acc, envelope| acc.merged(&envelope));
+
+            if let Some(bbox) = envelope {
+                let [x_min, y_min] = bbox.lower();
+                let [x_max, y_max] = bbox.upper();
+
+                update_viewport_event_writer.send(UpdateViewportEvent {
+                    viewport: ViewportDimensions {
+                        x_min,
+                        x_max,
+                        y_min,
+                        y_max,
+                        center: Point::new(
+                            ((x_min + x_max) / 2) as isize,
+                            ((y_min + y_max) / 2) as isize,
+                        ),
+                    },
+                });
+            }
 
             load_cell_complete_event_writer.send(LoadCellCompleteEvent);
 
@@ -504,117 +786,97 @@ pub fn load_cell_system(
     }
 }
 
+/// Flatten `cell` and all of its instances into `shapes`, placing each of them
+/// into the loaded cell's coordinates with `transform`. `instance` is the instance
+/// of `cell` being flattened, `None` for the loaded cell itself. Instances at or
+/// below `display_depth` are added to the tree collapsed, without their shapes.
 pub fn import_cell_shapes(
     cell: &Ptr<Cell>,
-    mut bbox_set: bool,
-    shape_count: &mut u64,
-    offset: &Point,
-    cell_info: &mut ResMut<VlsirCell>,
+    instance: Option<InstanceId>,
+    transform: &LayoutTransform,
     lib_layers: &Ptr<raw::Layers>,
-    layers: &Res<Layers>,
-    update_viewport_event_writer: &mut EventWriter<UpdateViewportEvent>,
-    import_rect_event_writer: &mut EventWriter<ImportRectEvent>,
-    import_poly_event_writer: &mut EventWriter<ImportPolyEvent>,
-    import_path_event_writer: &mut EventWriter<ImportPathEvent>,
+    display_depth: Option<usize>,
+    instance_tree: &mut InstanceTree,
+    shapes: &mut Vec<IndexedShape>,
 ) {
     let read_cell = cell.read().unwrap();
-    let read_lib_layers = lib_layers.read().unwrap();
 
     let layout = read_cell.layout.as_ref().unwrap();
 
-    let bbox = layout.bbox();
-
-    if !bbox_set {
-        if !bbox.is_empty() {
-            let center = bbox.center();
-            let Point { x: x_min, y: y_min } = bbox.p0;
-            let Point { x: x_max, y: y_max } = bbox.p1;
-
-            update_viewport_event_writer.send(UpdateViewportEvent {
-                viewport: ViewportDimensions {
-                    x_min: x_min as i64,
-                    x_max: x_max as i64,
-                    y_min: y_min as i64,
-                    y_max: y_max as i64,
-                    center,
-                },
-            });
-            bbox_set = true;
-        }
-    }
-
-    for Element {
-        net, layer, inner, ..
-    } in layout.elems.iter()
     {
-        if *shape_count % 1_000 == 0 {
-            info!("Shapes spawned: {}", shape_count);
-        }
-
-        if *shape_count > 90_000 {
-            return;
-        }
-
-        let net = Net(net.clone());
-
-        let layer = read_lib_layers
-            .get(*layer)
-            .expect("This Element's LayerKey does not exist in this Library's Layers")
-            .layernum as u8;
-
-        let color = layers
-            .get(&layer)
-            .expect(&format!("This Element's layer num: {layer} does not exist in our Layers Resource: {layers:?}"))
-            .color;
-
-        match inner {
-            Shape::Rect(r) => {
-                let BoundBox { p0, p1 } = r.bbox();
-                let p0 = p0.shift(offset);
-                let p1 = p1.shift(offset);
-
-                let p0 = (p0.x as i32, p0.y as i32);
-                let p1 = (p1.x as i32, p1.y as i32);
-
-                let rect = GeoRect::new(p0, p1);
-                import_rect_event_writer.send(ImportRectEvent {
-                    rect: Rect(rect),
-                    net,
-                    layer,
-                    color,
-                });
+        let read_lib_layers = lib_layers.read().unwrap();
+
+        for Element {
+            net,
+            layer,
+            purpose,
+            inner,
+        } in layout.elems.iter()
+        {
+            if shapes.len() % 100_000 == 0 {
+                info!("Shapes indexed: {}", shapes.len());
             }
-            Shape::Polygon(p) => {
-                let poly = GeoPolygon::new(
-                    p.points
-                        .iter()
-                        .map(|p| {
-                            let p = p.shift(offset);
-                            (p.x as i32, p.y as i32)
-                        })
-                        .collect(),
+
+            let net = Net(net.clone());
+
+            // only the loaded cell's own elements are written back to the library on save,
+            // shapes flattened out of its instances belong to other cells
+            let elem = if instance.is_none() {
+                Some(CellElem {
+                    purpose: purpose.clone(),
+                })
+            } else {
+                None
+            };
+
+            let layer = read_lib_layers
+                .get(*layer)
+                .expect("This Element's LayerKey does not exist in this Library's Layers")
+                .layernum as u8;
+
+            let to_coord = |p: &Point| {
+                let p = transform.apply(p);
+                (p.x as i32, p.y as i32)
+            };
+
+            let geometry = match inner {
+                Shape::Rect(r) => {
+                    let BoundBox { p0, p1 } = r.bbox();
+                    if transform.is_manhattan() {
+                        ShapeGeometry::Rect(GeoRect::new(to_coord(&p0), to_coord(&p1)))
+                    } else {
+                        // a rect rotated by an arbitrary angle is no longer axis aligned
+                        let corners = [
+                            p0.clone(),
+                            Point::new(p1.x, p0.y),
+                            p1.clone(),
+                            Point::new(p0.x, p1.y),
+                        ];
+                        ShapeGeometry::Poly(GeoPolygon::new(
+                            corners.iter().map(to_coord).collect(),
+                            vec![],
+                        ))
+                    }
+                }
+                Shape::Polygon(p) => ShapeGeometry::Poly(GeoPolygon::new(
+                    p.points.iter().map(to_coord).collect(),
                     vec![],
-                );
-                import_poly_event_writer.send(ImportPolyEvent {
-                    poly: Poly(poly),
-                    net,
-                    layer,
-                    color,
-                });
-            }
-            Shape::Path(p) => {
-                let mut p = p.clone();
-                p.points = p.points.iter().map(|p| p.shift(offset)).collect();
-                import_path_event_writer.send(ImportPathEvent {
-                    path: Path(p.clone()),
-                    net,
-                    layer,
-                    color,
-                });
-            }
+                )),
+                Shape::Path(p) => {
+                    let mut p = p.clone();
+                    p.points = p.points.iter().map(|p| transform.apply(p)).collect();
+                    ShapeGeometry::Path(p)
+                }
+            };
+
+            shapes.push(IndexedShape {
+                layer,
+                net,
+                geometry,
+                elem,
+                instance,
+            });
         }
-
-        *shape_count += 1;
     }
 
     for Instance {
@@ -625,18 +887,270 @@ pub fn import_cell_shapes(
         angle,
     } in layout.insts.iter()
     {
-        import_cell_shapes(
-            cell,
-            bbox_set,
-            shape_count,
-            loc,
-            cell_info,
-            lib_layers,
-            layers,
-            update_viewport_event_writer,
-            import_rect_event_writer,
-            import_poly_event_writer,
-            import_path_event_writer,
+        let inst_transform =
+            transform.cascade(&LayoutTransform::from_instance(loc, *reflect_vert, *angle));
+
+        let inst_id = instance_tree.push(InstanceNode::new(
+            inst_name.clone(),
+            cell.clone(),
+            inst_transform,
+            instance,
+        ));
+
+        if display_depth.map_or(true, |depth| instance_tree.get(inst_id).depth < depth) {
+            import_instance_shapes(inst_id, lib_layers, display_depth, instance_tree, shapes);
+        } else {
+            instance_tree.collapsed.insert(inst_id);
+        }
+    }
+}
+
+/// Flatten the cell of instance `id` into `shapes`, see [import_cell_shapes].
+pub fn import_instance_shapes(
+    id: InstanceId,
+    lib_layers: &Ptr<raw::Layers>,
+    display_depth: Option<usize>,
+    instance_tree: &mut InstanceTree,
+    shapes: &mut Vec<IndexedShape>,
+) {
+    let node = &mut instance_tree.nodes[id.0];
+    node.flattened = true;
+
+    let cell = node.cell.clone();
+    let transform = node.transform;
+
+    import_cell_shapes(
+        &cell,
+        Some(id),
+        &transform,
+        lib_layers,
+        display_depth,
+        instance_tree,
+        shapes,
+    );
+}
+
+/// Collapse the instances of an already flattened cell at or below `display_depth`
+/// and expand the ones above it, flattening those that weren't yet into `shapes`.
+pub fn apply_display_depth(
+    display_depth: Option<usize>,
+    lib_layers: &Ptr<raw::Layers>,
+    instance_tree: &mut InstanceTree,
+    shapes: &mut Vec<IndexedShape>,
+) {
+    // flattening pushes the new instances onto the end of the tree, after their parents
+    let mut idx = 0;
+    while idx < instance_tree.nodes.len() {
+        let id = InstanceId(idx);
+        let node = instance_tree.get(id);
+
+        if display_depth.map_or(true, |depth| node.depth < depth) {
+            if !node.flattened {
+                import_instance_shapes(id, lib_layers, display_depth, instance_tree, shapes);
+            }
+            instance_tree.collapsed.remove(&id);
+        } else {
+            instance_tree.collapsed.insert(id);
+        }
+
+        idx += 1;
+    }
+}
+
+/// Maximum number of shapes spawned per frame while streaming, so zooming out over
+/// a large design stays responsive while the rest of the shapes stream in.
+pub const STREAM_SPAWN_BUDGET: usize = 20_000;
+
+/// Fraction of the view's width/height added on each side of the streaming query,
+/// so small pans don't immediately need to stream in new shapes.
+pub const STREAM_VIEW_MARGIN: f32 = 0.25;
+
+/// Shapes of the [ShapeIndex] that currently have (or are about to have) an entity.
+#[derive(Debug, Default, Clone)]
+pub struct StreamedShapes {
+    pub spawned: HashSet<ShapeId>,
+}
+
+/// Spawn the indexed shapes on visible layers that intersect the camera's view, and
+/// despawn those that no longer do, writing any edits made to them back into the index.
+pub fn stream_visible_shapes_system(
+    mut commands: Commands,
+    mut shape_index: ResMut<ShapeIndex>,
+    mut streamed_shapes: ResMut<StreamedShapes>,
+    mut shape_stack: ResMut<ShapeStack>,
+    layers: Res<Layers>,
+    layer_state: Res<LayersUIState>,
+    instance_tree: Res<InstanceTree>,
+    camera_q: Query<(&Transform, &OrthographicProjection), With<Camera>>,
+    spawned_q: Query<(
+        Entity,
+        &ShapeId,
+        &InLayer,
+        &Net,
+        &Transform,
+        Option<&CellElem>,
+        Option<&Rect>,
+        Option<&Poly>,
+        Option<&Path>,
+        Option<&Selected>,
+    )>,
+    mut last_view: Local<Option<Envelope>>,
+    mut pending: Local<bool>,
+    mut import_rect_event_writer: EventWriter<ImportRectEvent>,
+    mut import_poly_event_writer: EventWriter<ImportPolyEvent>,
+    mut import_path_event_writer: EventWriter<ImportPathEvent>,
+) {
+    let (cam_t, proj) = match camera_q.get_single() {
+        Ok(cam) => cam,
+        Err(_) => return,
+    };
+
+    let x_min = cam_t.translation.x + proj.left * proj.scale;
+    let x_max = cam_t.translation.x + proj.right * proj.scale;
+    let y_min = cam_t.translation.y + proj.bottom * proj.scale;
+    let y_max = cam_t.translation.y + proj.top * proj.scale;
+
+    let margin_x = (x_max - x_min) * STREAM_VIEW_MARGIN;
+    let margin_y = (y_max - y_min) * STREAM_VIEW_MARGIN;
+
+    let view = Envelope::from_corners(
+        [(x_min - margin_x) as i64, (y_min - margin_y) as i64],
+        [(x_max + margin_x) as i64, (y_max + margin_y) as i64],
+    );
+
+    if *last_view == Some(view)
+        && !*pending
+        && !shape_index.is_changed()
+        && !layer_state.is_changed()
+        && !instance_tree.is_changed()
+    {
+        return;
+    }
+
+    *last_view = Some(view);
+
+    let hidden_layers = layer_state
+        .layers
+        .iter()
+        .filter(|(vis, _, _)| !vis)
+        .map(|(_, layer, _)| *layer)
+        .collect::<HashSet<u8>>();
+
+    let in_view = shape_index
+        .locate_in_envelope_intersecting(&view, |layer| !hidden_layers.contains(&layer))
+        .into_iter()
+        .filter(|id| {
+            shape_index
+                .get(*id)
+                .map(|shape| {
+                    !instance_tree.is_hidden(shape.instance)
+                        && !instance_tree.is_collapsed(shape.instance)
+                })
+                .unwrap_or(false)
+        })
+        .collect::<HashSet<ShapeId>>();
+
+    let mut despawned = 0;
+
+    for (entity, id, layer, net, t, elem, rect, poly, path, selected) in spawned_q.iter() {
+        // keep the selection alive even when it is scrolled out of view
+        if in_view.contains(id) || selected.is_some() {
+            continue;
+        }
+
+        let instance = shape_index.get(*id).and_then(|s| s.instance);
+        if let Some(shape) =
+            IndexedShape::from_components(**layer, net, elem, instance, rect, poly, path, t)
+        {
+            if shape_index.get(*id) != Some(&shape) {
+                shape_index.update(*id, shape);
+            }
+        }
+
+        // recursive despawn also removes the shape from its instance's children
+        commands.entity(entity).despawn_recursive();
+        streamed_shapes.spawned.remove(id);
+        despawned += 1;
+    }
+
+    if despawned > 0 {
+        // the shape stack may refer to entities that were just despawned
+        *shape_stack = ShapeStack::default();
+    }
+
+    let mut spawned = 0;
+
+    for id in in_view.iter() {
+        if spawned >= STREAM_SPAWN_BUDGET {
+            break;
+        }
+
+        if streamed_shapes.spawned.contains(id) {
+            continue;
+        }
+
+        let IndexedShape {
+            layer,
+            net,
+            geometry,
+            elem,
+            instance,
+        } = match shape_index.get(*id) {
+            Some(shape) => shape.clone(),
+            None => continue,
+        };
+
+        let layer_style = layers.get(&layer).expect(&format!(
+            "This shape's layer num: {layer} does not exist in our Layers Resource: {layers:?}"
+        ));
+        let z = layer_style.z;
+
+        let parent = instance.and_then(|inst| instance_tree.entities.get(&inst).copied());
+
+        match geometry {
+            ShapeGeometry::Rect(rect) => import_rect_event_writer.send(ImportRectEvent {
+                id: *id,
+                rect: Rect(rect),
+                net,
+                layer,
+                draw_mode: layer_style.draw_mode(None),
+                z,
+                elem,
+                parent,
+            }),
+            ShapeGeometry::Poly(poly) => import_poly_event_writer.send(ImportPolyEvent {
+                id: *id,
+                poly: Poly(poly),
+                net,
+                layer,
+                draw_mode: layer_style.draw_mode(None),
+                z,
+                elem,
+                parent,
+            }),
+            ShapeGeometry::Path(path) => import_path_event_writer.send(ImportPathEvent {
+                id: *id,
+                draw_mode: layer_style.draw_mode(Some(path.width as f32)),
+                path: Path(path),
+                net,
+                layer,
+                z,
+                elem,
+                parent,
+            }),
+        }
+
+        streamed_shapes.spawned.insert(*id);
+        spawned += 1;
+    }
+
+    *pending = spawned >= STREAM_SPAWN_BUDGET;
+
+    if spawned > 0 || despawned > 0 {
+        info!(
+            "Streamed in {spawned} shapes, streamed out {despawned} shapes, {} of {} shapes spawned",
+            streamed_shapes.spawned.len(),
+            shape_index.len()
         );
     }
 }
@@ -646,10 +1160,14 @@ pub fn import_rect_system(
     mut import_rect_event_reader: EventReader<ImportRectEvent>,
 ) {
     for ImportRectEvent {
+        id,
         rect,
         net,
         layer,
-        color,
+        draw_mode,
+        z,
+        elem,
+        parent,
     } in import_rect_event_reader.iter()
     {
         let x_min = rect.min().x as f32;
@@ -668,22 +1186,9 @@ pub fn import_rect_system(
             closed: true,
         };
 
-        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, *layer as f32));
-
-        let shape_lyon = GeometryBuilder::build_as(
-            &lyon_poly,
-            DrawMode::Outlined {
-                fill_mode: FillMode {
-                    color: *color.clone().set_a(ALPHA),
-                    options: FillOptions::default(),
-                },
-                outline_mode: StrokeMode {
-                    options: StrokeOptions::default().with_line_width(WIDTH),
-                    color: *color,
-                },
-            },
-            transform,
-        );
+        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, *z));
+
+        let shape_lyon = GeometryBuilder::build_as(&lyon_poly, *draw_mode, transform);
 
         let shape = ShapeBundle {
             net: net.to_owned(),
@@ -691,10 +1196,22 @@ pub fn import_rect_system(
             layer: InLayer(*layer),
         };
 
-        commands.spawn_bundle(RectBundle {
+        let mut rect_e = commands.spawn_bundle(RectBundle {
             rect: rect.clone(),
             shape,
         });
+
+        rect_e.insert(*id);
+
+        if let Some(elem) = elem {
+            rect_e.insert(elem.clone());
+        }
+
+        let rect_e = rect_e.id();
+
+        if let Some(parent) = parent {
+            commands.entity(*parent).add_child(rect_e);
+        }
     }
 }
 
@@ -703,10 +1220,14 @@ pub fn import_poly_system(
     mut import_poly_event_reader: EventReader<ImportPolyEvent>,
 ) {
     for ImportPolyEvent {
+        id,
         net,
         poly,
         layer,
-        color,
+        draw_mode,
+        z,
+        elem,
+        parent,
     } in import_poly_event_reader.iter()
     {
         let lyon_poly = lyon_shapes::Polygon {
@@ -718,22 +1239,9 @@ pub fn import_poly_system(
             closed: true,
         };
 
-        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, *layer as f32));
-
-        let shape_lyon = GeometryBuilder::build_as(
-            &lyon_poly,
-            DrawMode::Outlined {
-                fill_mode: FillMode {
-                    color: *color.clone().set_a(ALPHA),
-                    options: FillOptions::default(),
-                },
-                outline_mode: StrokeMode {
-                    options: StrokeOptions::default().with_line_width(WIDTH),
-                    color: *color,
-                },
-            },
-            transform,
-        );
+        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, *z));
+
+        let shape_lyon = GeometryBuilder::build_as(&lyon_poly, *draw_mode, transform);
 
         let shape = ShapeBundle {
             net: net.to_owned(),
@@ -741,10 +1249,22 @@ pub fn import_poly_system(
             shape_lyon,
         };
 
-        commands.spawn_bundle(PolyBundle {
+        let mut poly_e = commands.spawn_bundle(PolyBundle {
             poly: poly.clone(),
             shape,
         });
+
+        poly_e.insert(*id);
+
+        if let Some(elem) = elem {
+            poly_e.insert(elem.clone());
+        }
+
+        let poly_e = poly_e.id();
+
+        if let Some(parent) = parent {
+            commands.entity(*parent).add_child(poly_e);
+        }
     }
 }
 
@@ -753,10 +1273,14 @@ pub fn import_path_system(
     mut import_path_event_reader: EventReader<ImportPathEvent>,
 ) {
     for ImportPathEvent {
+        id,
         net,
         path,
         layer,
-        color,
+        draw_mode,
+        z,
+        elem,
+        parent,
     } in import_path_event_reader.iter()
     {
         let lyon_path = lyon_shapes::Polygon {
@@ -768,22 +1292,9 @@ pub fn import_path_system(
             closed: false,
         };
 
-        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, *layer as f32));
-
-        let shape_lyon = GeometryBuilder::build_as(
-            &lyon_path,
-            DrawMode::Outlined {
-                fill_mode: FillMode {
-                    color: *color.clone().set_a(ALPHA),
-                    options: FillOptions::default(),
-                },
-                outline_mode: StrokeMode {
-                    options: StrokeOptions::default().with_line_width(path.width as f32),
-                    color: *color,
-                },
-            },
-            transform,
-        );
+        let transform = Transform::from_translation(Vec3::new(0.0, 0.0, *z));
+
+        let shape_lyon = GeometryBuilder::build_as(&lyon_path, *draw_mode, transform);
 
         let shape = ShapeBundle {
             net: net.clone(),
@@ -791,20 +1302,303 @@ pub fn import_path_system(
             shape_lyon,
         };
 
-        commands.spawn_bundle(PathBundle {
+        let mut path_e = commands.spawn_bundle(PathBundle {
             path: path.clone(),
             shape,
         });
+
+        path_e.insert(*id);
+
+        if let Some(elem) = elem {
+            path_e.insert(elem.clone());
+        }
+
+        let path_e = path_e.id();
+
+        if let Some(parent) = parent {
+            commands.entity(*parent).add_child(path_e);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{apply_display_depth, import_cell_shapes, open_lib, LibFileFormat};
+    use crate::hierarchy::{InstanceId, InstanceTree};
+    use crate::index::{IndexedShape, ShapeGeometry};
+    use crate::shapes::{GeoPolygon, GeoRect};
+    use crate::transform::LayoutTransform;
     use layout21::raw::{
-        gds::gds21::GdsLibrary, gds::GdsImporter, proto::ProtoExporter, LayoutResult,
+        gds::gds21::GdsLibrary, gds::GdsImporter, proto::ProtoExporter, BoundBoxTrait, Cell,
+        Instance, Layout, LayoutResult, Library, Point,
     };
+    use layout21::utils::Ptr;
     use vlsir::save;
 
+    fn dff1_lib() -> Library {
+        open_lib("libs/dff1_lib.proto").unwrap()
+    }
+
+    fn flatten_with_tree(
+        lib: &Library,
+        cell: &Ptr<Cell>,
+        display_depth: Option<usize>,
+    ) -> (Vec<IndexedShape>, InstanceTree) {
+        let mut shapes = vec![];
+        let mut tree = InstanceTree::default();
+        import_cell_shapes(
+            cell,
+            None,
+            &LayoutTransform::identity(),
+            &lib.layers,
+            display_depth,
+            &mut tree,
+            &mut shapes,
+        );
+        (shapes, tree)
+    }
+
+    /// The flattened shapes of `cell`, ignoring which instance they came from.
+    fn flatten(lib: &Library, cell: &Ptr<Cell>) -> Vec<IndexedShape> {
+        flatten_with_tree(lib, cell, None)
+            .0
+            .into_iter()
+            .map(|shape| IndexedShape {
+                elem: None,
+                instance: None,
+                ..shape
+            })
+            .collect()
+    }
+
+    /// A cell containing nothing but `insts`.
+    fn wrapper_cell(name: &str, insts: Vec<Instance>) -> Ptr<Cell> {
+        Ptr::new(Cell {
+            name: name.to_owned(),
+            abs: None,
+            layout: Some(Layout {
+                name: name.to_owned(),
+                insts,
+                elems: vec![],
+                annotations: vec![],
+            }),
+        })
+    }
+
+    fn inst(
+        cell: &Ptr<Cell>,
+        loc: (isize, isize),
+        reflect_vert: bool,
+        angle: Option<f64>,
+    ) -> Instance {
+        Instance {
+            inst_name: "inst".to_owned(),
+            cell: cell.clone(),
+            loc: Point::new(loc.0, loc.1),
+            reflect_vert,
+            angle,
+        }
+    }
+
+    /// Apply `f` to every coordinate of the reference shapes.
+    fn map_shapes(
+        shapes: &[IndexedShape],
+        f: impl Fn(i32, i32) -> (i32, i32),
+    ) -> Vec<IndexedShape> {
+        shapes
+            .iter()
+            .map(|shape| {
+                let geometry = match &shape.geometry {
+                    ShapeGeometry::Rect(r) => ShapeGeometry::Rect(GeoRect::new(
+                        f(r.min().x, r.min().y),
+                        f(r.max().x, r.max().y),
+                    )),
+                    ShapeGeometry::Poly(p) => ShapeGeometry::Poly(GeoPolygon::new(
+                        p.exterior().coords().map(|c| f(c.x, c.y).into()).collect(),
+                        vec![],
+                    )),
+                    ShapeGeometry::Path(p) => {
+                        let mut p = p.clone();
+                        p.points = p
+                            .points
+                            .iter()
+                            .map(|p| {
+                                let (x, y) = f(p.x as i32, p.y as i32);
+                                Point::new(x as isize, y as isize)
+                            })
+                            .collect();
+                        ShapeGeometry::Path(p)
+                    }
+                };
+                IndexedShape {
+                    geometry,
+                    ..shape.clone()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flatten_translated_instance() {
+        let lib = dff1_lib();
+        let dff1 = lib.cells[0].clone();
+        let reference = flatten(&lib, &dff1);
+        assert!(!reference.is_empty());
+
+        let top = wrapper_cell("top", vec![inst(&dff1, (1000, -2000), false, None)]);
+
+        assert_eq!(
+            flatten(&lib, &top),
+            map_shapes(&reference, |x, y| (x + 1000, y - 2000))
+        );
+    }
+
+    #[test]
+    fn flatten_rotated_instances() {
+        let lib = dff1_lib();
+        let dff1 = lib.cells[0].clone();
+        let reference = flatten(&lib, &dff1);
+
+        let top = wrapper_cell("top", vec![inst(&dff1, (500, 700), false, Some(90.0))]);
+        assert_eq!(
+            flatten(&lib, &top),
+            map_shapes(&reference, |x, y| (-y + 500, x + 700))
+        );
+
+        let top = wrapper_cell("top", vec![inst(&dff1, (0, 0), false, Some(180.0))]);
+        assert_eq!(flatten(&lib, &top), map_shapes(&reference, |x, y| (-x, -y)));
+
+        let top = wrapper_cell("top", vec![inst(&dff1, (0, 0), false, Some(270.0))]);
+        assert_eq!(flatten(&lib, &top), map_shapes(&reference, |x, y| (y, -x)));
+    }
+
+    #[test]
+    fn flatten_reflected_instances() {
+        let lib = dff1_lib();
+        let dff1 = lib.cells[0].clone();
+        let reference = flatten(&lib, &dff1);
+
+        let top = wrapper_cell("top", vec![inst(&dff1, (10, 20), true, None)]);
+        assert_eq!(
+            flatten(&lib, &top),
+            map_shapes(&reference, |x, y| (x + 10, -y + 20))
+        );
+
+        // reflection is applied before rotation
+        let top = wrapper_cell("top", vec![inst(&dff1, (0, 0), true, Some(90.0))]);
+        assert_eq!(flatten(&lib, &top), map_shapes(&reference, |x, y| (y, x)));
+    }
+
+    #[test]
+    fn flatten_nested_instances() {
+        let lib = dff1_lib();
+        let dff1 = lib.cells[0].clone();
+        let reference = flatten(&lib, &dff1);
+
+        let mid = wrapper_cell("mid", vec![inst(&dff1, (10, 20), true, None)]);
+        let top = wrapper_cell("top", vec![inst(&mid, (100, 0), false, Some(180.0))]);
+
+        // dff1 -> mid: (x, -y) + (10, 20), mid -> top: rotate 180 then + (100, 0)
+        assert_eq!(
+            flatten(&lib, &top),
+            map_shapes(&reference, |x, y| (-(x + 10) + 100, -(-y + 20)))
+        );
+
+        let (shapes, tree) = flatten_with_tree(&lib, &top, None);
+        assert_eq!(tree.roots, vec![InstanceId(0)]);
+        assert_eq!(tree.get(InstanceId(0)).cell_name, "mid");
+        assert_eq!(tree.get(InstanceId(0)).children, vec![InstanceId(1)]);
+        assert_eq!(tree.get(InstanceId(1)).cell_name, "dff1");
+        assert_eq!(tree.get(InstanceId(1)).depth, 1);
+        assert!(shapes.iter().all(|s| s.instance == Some(InstanceId(1))));
+    }
+
+    #[test]
+    fn display_depth_collapses_deep_instances() {
+        let lib = dff1_lib();
+        let dff1 = lib.cells[0].clone();
+        let reference = flatten(&lib, &dff1);
+
+        let mid = wrapper_cell("mid", vec![inst(&dff1, (10, 20), false, None)]);
+        let top = wrapper_cell("top", vec![inst(&mid, (100, 0), false, None)]);
+
+        // mid is flattened, its instance of dff1 is left as a placeholder
+        let (shapes, tree) = flatten_with_tree(&lib, &top, Some(1));
+        assert!(shapes.is_empty());
+        assert!(tree.get(InstanceId(0)).flattened);
+        assert!(!tree.get(InstanceId(1)).flattened);
+        assert_eq!(tree.collapsed, [InstanceId(1)].into_iter().collect());
+
+        let placeholders = tree.placeholders().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(placeholders, vec![InstanceId(1)]);
+
+        let dff1_bbox = dff1.read().unwrap().layout.as_ref().unwrap().bbox();
+        let bbox = tree.get(InstanceId(1)).bbox.unwrap();
+        assert_eq!(
+            bbox.lower(),
+            [dff1_bbox.p0.x as i64 + 110, dff1_bbox.p0.y as i64 + 20]
+        );
+        assert_eq!(
+            bbox.upper(),
+            [dff1_bbox.p1.x as i64 + 110, dff1_bbox.p1.y as i64 + 20]
+        );
+
+        // collapsing the top level instance hides everything below it
+        let (shapes, tree) = flatten_with_tree(&lib, &top, Some(0));
+        assert!(shapes.is_empty());
+        assert_eq!(tree.nodes.len(), 1);
+        assert!(tree.is_collapsed(Some(InstanceId(0))));
+
+        // without a limit everything is flattened as before
+        let (shapes, tree) = flatten_with_tree(&lib, &top, None);
+        assert_eq!(shapes.len(), reference.len());
+        assert!(tree.collapsed.is_empty());
+    }
+
+    #[test]
+    fn apply_display_depth_in_place() {
+        let lib = dff1_lib();
+        let dff1 = lib.cells[0].clone();
+        let reference = flatten(&lib, &dff1);
+
+        let mid = wrapper_cell("mid", vec![inst(&dff1, (10, 20), false, None)]);
+        let top = wrapper_cell("top", vec![inst(&mid, (100, 0), false, None)]);
+
+        // expanding flattens the placeholders that were never flattened
+        let (mut shapes, mut tree) = flatten_with_tree(&lib, &top, Some(0));
+        apply_display_depth(None, &lib.layers, &mut tree, &mut shapes);
+        assert_eq!(shapes.len(), reference.len());
+        assert_eq!(tree.nodes.len(), 2);
+        assert!(tree.collapsed.is_empty());
+
+        // collapsing keeps the shapes, they are just not drawn
+        apply_display_depth(Some(1), &lib.layers, &mut tree, &mut shapes);
+        assert_eq!(shapes.len(), reference.len());
+        assert_eq!(tree.collapsed, [InstanceId(1)].into_iter().collect());
+        assert!(tree.is_collapsed(Some(InstanceId(1))));
+        assert!(!tree.is_collapsed(Some(InstanceId(0))));
+
+        // and expanding them again doesn't flatten them twice
+        apply_display_depth(None, &lib.layers, &mut tree, &mut shapes);
+        assert_eq!(shapes.len(), reference.len());
+        assert!(tree.collapsed.is_empty());
+    }
+
+    #[test]
+    fn lib_file_format_from_extension() {
+        assert_eq!(
+            LibFileFormat::from_path("libs/dff1_lib.proto"),
+            LibFileFormat::Proto
+        );
+        assert_eq!(LibFileFormat::from_path("a/b/top.gds"), LibFileFormat::Gds);
+        assert_eq!(LibFileFormat::from_path("top.GDS2"), LibFileFormat::Gds);
+        assert_eq!(LibFileFormat::from_path("top.gdsii"), LibFileFormat::Gds);
+        assert_eq!(
+            LibFileFormat::from_path("no_extension"),
+            LibFileFormat::Proto
+        );
+    }
+
     #[test]
     fn make_oscibear_proto() -> LayoutResult<()> {
         let gds = GdsLibrary::load("./user_analog_project_wrapper.gds").unwrap();
@@ -0,0 +1,341 @@
+use crate::hierarchy::InstanceId;
+use crate::import::Net;
+use crate::shapes::{CellElem, GeoPolygon, GeoRect, Path, Poly, Rect};
+
+use std::collections::HashMap;
+
+use bevy::prelude::{Component, IVec2, Transform, Vec2};
+use geo::{coord, prelude::BoundingRect, translate::Translate, LineString};
+use layout21::raw::{self, Point};
+use rstar::{Envelope as _, RTree, RTreeObject, AABB};
+
+/// Axis aligned bounding box used by the spatial index. Coordinates are widened
+/// to i64 as rstar computes envelope areas, which overflow i32 for full chip designs.
+pub type Envelope = AABB<[i64; 2]>;
+
+/// Identifies a shape of the loaded cell in the [ShapeIndex], stays the same for the
+/// lifetime of the shape no matter how many times its entity is streamed in and out.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ShapeId(pub u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShapeGeometry {
+    Rect(GeoRect),
+    Poly(GeoPolygon),
+    Path(raw::Path),
+}
+
+impl ShapeGeometry {
+    pub fn envelope(&self) -> Envelope {
+        match self {
+            ShapeGeometry::Rect(r) => AABB::from_corners(
+                [r.min().x as i64, r.min().y as i64],
+                [r.max().x as i64, r.max().y as i64],
+            ),
+            ShapeGeometry::Poly(p) => match p.bounding_rect() {
+                Some(r) => AABB::from_corners(
+                    [r.min().x as i64, r.min().y as i64],
+                    [r.max().x as i64, r.max().y as i64],
+                ),
+                None => AABB::from_point([0, 0]),
+            },
+            ShapeGeometry::Path(p) => {
+                // a path's stroke extends half its width past its centerline
+                let half_width = (p.width / 2) as i64;
+                let bbox = p
+                    .points
+                    .iter()
+                    .map(|Point { x, y }| [*x as i64, *y as i64])
+                    .collect::<Vec<[i64; 2]>>();
+                let bbox = AABB::from_points(bbox.iter());
+                AABB::from_corners(
+                    [bbox.lower()[0] - half_width, bbox.lower()[1] - half_width],
+                    [bbox.upper()[0] + half_width, bbox.upper()[1] + half_width],
+                )
+            }
+        }
+    }
+
+    pub fn translate(&self, dx: i32, dy: i32) -> Self {
+        match self {
+            ShapeGeometry::Rect(r) => ShapeGeometry::Rect(r.translate(dx, dy)),
+            ShapeGeometry::Poly(p) => ShapeGeometry::Poly(p.translate(dx, dy)),
+            ShapeGeometry::Path(p) => {
+                let mut p = p.clone();
+                let offset = Point::new(dx as isize, dy as isize);
+                p.points = p.points.iter().map(|p| p.shift(&offset)).collect();
+                ShapeGeometry::Path(p)
+            }
+        }
+    }
+
+    /// The geometry with every point moved by `f`. Rects stay normalized, so `f` has to
+    /// keep them axis aligned.
+    pub fn map_points(&self, f: impl Fn(IVec2) -> IVec2) -> Self {
+        let map_ring = |ring: &LineString<i32>| {
+            ring.coords()
+                .map(|c| {
+                    let p = f(IVec2::new(c.x, c.y));
+                    coord! { x: p.x, y: p.y }
+                })
+                .collect::<LineString<i32>>()
+        };
+
+        match self {
+            ShapeGeometry::Rect(r) => {
+                let (min, max) = (r.min(), r.max());
+                let (p0, p1) = (f(IVec2::new(min.x, min.y)), f(IVec2::new(max.x, max.y)));
+                ShapeGeometry::Rect(GeoRect::new(
+                    coord! { x: p0.x, y: p0.y },
+                    coord! { x: p1.x, y: p1.y },
+                ))
+            }
+            ShapeGeometry::Poly(p) => ShapeGeometry::Poly(GeoPolygon::new(
+                map_ring(p.exterior()),
+                p.interiors().iter().map(map_ring).collect(),
+            )),
+            ShapeGeometry::Path(p) => {
+                let mut p = p.clone();
+                p.points = p
+                    .points
+                    .iter()
+                    .map(|pt| {
+                        let q = f(IVec2::new(pt.x as i32, pt.y as i32));
+                        Point::new(q.x as isize, q.y as isize)
+                    })
+                    .collect();
+                ShapeGeometry::Path(p)
+            }
+        }
+    }
+}
+
+/// A shape of the loaded cell, flattened into cell coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedShape {
+    pub layer: u8,
+    pub net: Net,
+    pub geometry: ShapeGeometry,
+    pub elem: Option<CellElem>,
+    /// The instance the shape was flattened out of, `None` for the loaded cell's own shapes.
+    pub instance: Option<InstanceId>,
+}
+
+impl IndexedShape {
+    /// Rebuild the indexed shape from a spawned shape entity's components, folding in
+    /// any xy translation it has accumulated from editing. `None` when the entity has
+    /// no geometry component.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_components(
+        layer: u8,
+        net: &Net,
+        elem: Option<&CellElem>,
+        instance: Option<InstanceId>,
+        rect: Option<&Rect>,
+        poly: Option<&Poly>,
+        path: Option<&Path>,
+        transform: &Transform,
+    ) -> Option<Self> {
+        let geometry = if let Some(rect) = rect {
+            ShapeGeometry::Rect(rect.0)
+        } else if let Some(poly) = poly {
+            ShapeGeometry::Poly(poly.0.clone())
+        } else if let Some(path) = path {
+            ShapeGeometry::Path(path.0.clone())
+        } else {
+            return None;
+        };
+
+        let dx = transform.translation.x.round() as i32;
+        let dy = transform.translation.y.round() as i32;
+
+        let geometry = if dx != 0 || dy != 0 {
+            geometry.translate(dx, dy)
+        } else {
+            geometry
+        };
+
+        Some(IndexedShape {
+            layer,
+            net: net.clone(),
+            geometry,
+            elem: elem.cloned(),
+            instance,
+        })
+    }
+}
+
+/// A shape of the loaded cell on layer 68, for tests.
+#[cfg(test)]
+pub fn test_shape(geometry: ShapeGeometry) -> IndexedShape {
+    IndexedShape {
+        layer: 68,
+        net: Net::default(),
+        geometry,
+        elem: Some(CellElem {
+            purpose: raw::LayerPurpose::Drawing,
+        }),
+        instance: None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IndexEntry {
+    id: ShapeId,
+    envelope: Envelope,
+}
+
+impl RTreeObject for IndexEntry {
+    type Envelope = Envelope;
+
+    fn envelope(&self) -> Self::Envelope {
+        self.envelope
+    }
+}
+
+/// Every shape of the loaded cell, with an R-tree per layer so only the shapes
+/// on visible layers intersecting the camera's view need to be spawned.
+#[derive(Debug, Default)]
+pub struct ShapeIndex {
+    next_id: u64,
+    shapes: HashMap<ShapeId, IndexedShape>,
+    layers: HashMap<u8, RTree<IndexEntry>>,
+}
+
+impl ShapeIndex {
+    pub fn from_shapes(shapes: Vec<IndexedShape>) -> Self {
+        let mut index = ShapeIndex::default();
+        let mut entries: HashMap<u8, Vec<IndexEntry>> = HashMap::new();
+
+        for shape in shapes {
+            let id = index.next_id();
+            entries.entry(shape.layer).or_default().push(IndexEntry {
+                id,
+                envelope: shape.geometry.envelope(),
+            });
+            index.shapes.insert(id, shape);
+        }
+
+        index.layers = entries
+            .into_iter()
+            .map(|(layer, entries)| (layer, RTree::bulk_load(entries)))
+            .collect();
+
+        index
+    }
+
+    /// Allocate an id for a shape that will be added with `insert_with_id`.
+    pub fn next_id(&mut self) -> ShapeId {
+        let id = ShapeId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    /// Bounding box of every shape in the index.
+    pub fn envelope(&self) -> Option<Envelope> {
+        self.layers
+            .values()
+            .filter(|tree| tree.size() > 0)
+            .map(|tree| tree.root().envelope())
+            .reduce(|acc, envelope| acc.merged(&envelope))
+    }
+
+    pub fn get(&self, id: ShapeId) -> Option<&IndexedShape> {
+        self.shapes.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ShapeId, &IndexedShape)> {
+        self.shapes.iter()
+    }
+
+    pub fn insert(&mut self, shape: IndexedShape) -> ShapeId {
+        let id = self.next_id();
+        self.insert_entry(id, &shape);
+        self.shapes.insert(id, shape);
+        id
+    }
+
+    /// Insert `shape` under an id it was previously removed with, e.g. when undoing its removal.
+    pub fn insert_with_id(&mut self, id: ShapeId, shape: IndexedShape) {
+        if let Some(old) = self.shapes.remove(&id) {
+            self.remove_entry(id, &old);
+        }
+        self.next_id = self.next_id.max(id.0 + 1);
+        self.insert_entry(id, &shape);
+        self.shapes.insert(id, shape);
+    }
+
+    pub fn remove(&mut self, id: ShapeId) -> Option<IndexedShape> {
+        let shape = self.shapes.remove(&id)?;
+        self.remove_entry(id, &shape);
+        Some(shape)
+    }
+
+    /// Replace the shape stored for `id`, returns false if there is no such shape.
+    pub fn update(&mut self, id: ShapeId, shape: IndexedShape) -> bool {
+        match self.shapes.get(&id) {
+            Some(old) if *old == shape => true,
+            Some(old) => {
+                let old = old.clone();
+                self.remove_entry(id, &old);
+                self.insert_entry(id, &shape);
+                self.shapes.insert(id, shape);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move the shape `id` by `delta`, rounded to whole units.
+    pub fn translate(&mut self, id: ShapeId, delta: Vec2) {
+        if let Some(shape) = self.shapes.get(&id) {
+            let mut shape = shape.clone();
+            shape.geometry = shape
+                .geometry
+                .translate(delta.x.round() as i32, delta.y.round() as i32);
+            self.update(id, shape);
+        }
+    }
+
+    /// Ids of all shapes on a layer for which `layer_filter` returns true
+    /// whose bounding box intersects `envelope`.
+    pub fn locate_in_envelope_intersecting(
+        &self,
+        envelope: &Envelope,
+        layer_filter: impl Fn(u8) -> bool,
+    ) -> Vec<ShapeId> {
+        self.layers
+            .iter()
+            .filter(|(layer, _)| layer_filter(**layer))
+            .flat_map(|(_, tree)| tree.locate_in_envelope_intersecting(envelope))
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    fn insert_entry(&mut self, id: ShapeId, shape: &IndexedShape) {
+        self.layers
+            .entry(shape.layer)
+            .or_default()
+            .insert(IndexEntry {
+                id,
+                envelope: shape.geometry.envelope(),
+            });
+    }
+
+    fn remove_entry(&mut self, id: ShapeId, shape: &IndexedShape) {
+        if let Some(tree) = self.layers.get_mut(&shape.layer) {
+            tree.remove(&IndexEntry {
+                id,
+                envelope: shape.geometry.envelope(),
+            });
+        }
+    }
+}
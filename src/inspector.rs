@@ -0,0 +1,200 @@
+use crate::{
+    handles::{poly_vertices, with_vertices},
+    import::Net,
+    index::{IndexedShape, ShapeGeometry},
+    shapes::GeoRect,
+};
+
+use bevy::prelude::IVec2;
+use geo::coord;
+use layout21::raw;
+
+/// What the properties inspector shows of a single shape, kept as text while it is
+/// edited so invalid input can be pointed out instead of being lost.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShapeFields {
+    pub layer: u8,
+    pub net: String,
+    /// The two corners of a rect, the vertices of a polygon or the points of a path.
+    pub points: Vec<[String; 2]>,
+    /// Width of a path, unused for rects and polygons.
+    pub width: String,
+}
+
+impl ShapeFields {
+    pub fn new(shape: &IndexedShape) -> Self {
+        let points = match &shape.geometry {
+            ShapeGeometry::Rect(r) => vec![
+                IVec2::new(r.min().x, r.min().y),
+                IVec2::new(r.max().x, r.max().y),
+            ],
+            ShapeGeometry::Poly(p) => poly_vertices(p),
+            ShapeGeometry::Path(p) => p
+                .points
+                .iter()
+                .map(|p| IVec2::new(p.x as i32, p.y as i32))
+                .collect(),
+        };
+
+        let width = match &shape.geometry {
+            ShapeGeometry::Path(p) => p.width.to_string(),
+            _ => String::new(),
+        };
+
+        Self {
+            layer: shape.layer,
+            net: shape.net.0.clone().unwrap_or_default(),
+            points: points
+                .iter()
+                .map(|p| [p.x.to_string(), p.y.to_string()])
+                .collect(),
+            width,
+        }
+    }
+
+    /// `shape` with the edited properties, or what is wrong with them.
+    pub fn to_shape(&self, shape: &IndexedShape) -> Result<IndexedShape, String> {
+        let points = self
+            .points
+            .iter()
+            .enumerate()
+            .map(|(i, [x, y])| {
+                let parse = |axis: &str, v: &str| {
+                    v.trim()
+                        .parse::<i32>()
+                        .map_err(|_| format!("{axis} of point {} is not an integer: {v:?}", i + 1))
+                };
+                Ok(IVec2::new(parse("x", x)?, parse("y", y)?))
+            })
+            .collect::<Result<Vec<IVec2>, String>>()?;
+
+        let geometry = match &shape.geometry {
+            ShapeGeometry::Rect(_) => {
+                let (a, b) = match points[..] {
+                    [a, b] => (a, b),
+                    _ => return Err("A rect needs exactly 2 corners".to_owned()),
+                };
+                if a.x == b.x || a.y == b.y {
+                    return Err("A rect needs a non-zero width and height".to_owned());
+                }
+                ShapeGeometry::Rect(GeoRect::new(
+                    coord! { x: a.x, y: a.y },
+                    coord! { x: b.x, y: b.y },
+                ))
+            }
+            ShapeGeometry::Poly(p) => {
+                let mut vertices = points;
+                vertices.dedup();
+                if vertices.len() < 3 {
+                    return Err("A polygon needs at least 3 distinct vertices".to_owned());
+                }
+                ShapeGeometry::Poly(with_vertices(p, &vertices))
+            }
+            ShapeGeometry::Path(_) => {
+                let mut points = points;
+                points.dedup();
+                if points.len() < 2 {
+                    return Err("A path needs at least 2 distinct points".to_owned());
+                }
+                let width = match self.width.trim().parse::<usize>() {
+                    Ok(width) if width > 0 => width,
+                    _ => {
+                        return Err(format!(
+                            "The width must be a positive integer: {:?}",
+                            self.width
+                        ))
+                    }
+                };
+                ShapeGeometry::Path(raw::Path {
+                    points: points
+                        .iter()
+                        .map(|p| raw::Point::new(p.x as isize, p.y as isize))
+                        .collect(),
+                    width,
+                })
+            }
+        };
+
+        let net = self.net.trim();
+
+        Ok(IndexedShape {
+            layer: self.layer,
+            net: Net((!net.is_empty()).then(|| net.to_owned())),
+            geometry,
+            ..shape.clone()
+        })
+    }
+
+    /// The fewest points the shape can be left with when removing some.
+    pub fn min_points(shape: &IndexedShape) -> usize {
+        match shape.geometry {
+            ShapeGeometry::Rect(_) => 2,
+            ShapeGeometry::Poly(_) => 3,
+            ShapeGeometry::Path(_) => 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShapeFields;
+    use crate::import::Net;
+    use crate::index::{test_shape as shape, IndexedShape, ShapeGeometry};
+    use crate::shapes::GeoRect;
+    use geo::coord;
+    use layout21::raw;
+
+    #[test]
+    fn edit_rect_fields() {
+        let rect = shape(ShapeGeometry::Rect(GeoRect::new(
+            coord! { x: 0, y: 0 },
+            coord! { x: 100, y: 50 },
+        )));
+
+        let mut fields = ShapeFields::new(&rect);
+        assert_eq!(fields.to_shape(&rect), Ok(rect.clone()));
+
+        // corners given the wrong way round are normalized, an empty net is no net
+        fields.points = vec![
+            ["200".to_owned(), " 80".to_owned()],
+            ["-10".to_owned(), "0".to_owned()],
+        ];
+        fields.net = String::new();
+        fields.layer = 69;
+        assert_eq!(
+            fields.to_shape(&rect),
+            Ok(IndexedShape {
+                layer: 69,
+                net: Net(None),
+                geometry: ShapeGeometry::Rect(GeoRect::new(
+                    coord! { x: -10, y: 0 },
+                    coord! { x: 200, y: 80 },
+                )),
+                ..rect.clone()
+            })
+        );
+
+        fields.points[1][1] = "80".to_owned();
+        assert!(fields.to_shape(&rect).is_err());
+        fields.points[1][1] = "1.5".to_owned();
+        assert!(fields.to_shape(&rect).is_err());
+    }
+
+    #[test]
+    fn edit_path_fields() {
+        let path = shape(ShapeGeometry::Path(raw::Path {
+            points: vec![raw::Point::new(0, 0), raw::Point::new(100, 0)],
+            width: 10,
+        }));
+
+        let mut fields = ShapeFields::new(&path);
+        assert_eq!(fields.width, "10");
+
+        fields.width = "0".to_owned();
+        assert!(fields.to_shape(&path).is_err());
+
+        fields.width = "20".to_owned();
+        fields.points[1] = ["0".to_owned(), "0".to_owned()];
+        assert!(fields.to_shape(&path).is_err());
+    }
+}
@@ -1,6 +1,20 @@
+pub mod array;
+pub mod clipboard;
 pub mod editing;
+pub mod export;
+pub mod grid;
+pub mod handles;
+pub mod hierarchy;
 pub mod import;
+pub mod index;
+pub mod inspector;
+pub mod nudge;
+pub mod orient;
 pub mod shapes;
+pub mod snap;
+pub mod tech;
+pub mod tools;
+pub mod transform;
 pub mod ui;
 
 use bevy::ecs::archetype::Archetypes;
@@ -16,8 +30,19 @@ use layout21::raw;
 // use bevy_framepace::{FramepacePlugin, FramerateLimit};
 // use bevy_inspector_egui::WorldInspectorPlugin;
 
+use array::ArrayPlugin;
+use clipboard::ClipboardPlugin;
 use editing::EditingPlugin;
+use export::Layout21ExportPlugin;
+use grid::{Grid, GridPlugin};
+use handles::HandlesPlugin;
+use hierarchy::HierarchyPlugin;
 use import::Layout21ImportPlugin;
+use nudge::NudgePlugin;
+use orient::OrientPlugin;
+use snap::SnapPlugin;
+use tech::TechPlugin;
+use tools::ToolsPlugin;
 use ui::UIPlugin;
 
 // Set a default alpha-value for most shapes
@@ -77,7 +102,18 @@ fn main() {
         .insert_resource(CursorWorldPos::default())
         .add_plugins(DefaultPlugins)
         .add_plugin(Layout21ImportPlugin)
+        .add_plugin(TechPlugin)
+        .add_plugin(Layout21ExportPlugin)
+        .add_plugin(HierarchyPlugin)
+        .add_plugin(GridPlugin)
         .add_plugin(EditingPlugin)
+        .add_plugin(HandlesPlugin)
+        .add_plugin(OrientPlugin)
+        .add_plugin(ClipboardPlugin)
+        .add_plugin(ArrayPlugin)
+        .add_plugin(NudgePlugin)
+        .add_plugin(ToolsPlugin)
+        .add_plugin(SnapPlugin)
         .add_plugin(UIPlugin)
         // .add_plugin(FramepacePlugin::default())
         // .add_plugin(WorldInspectorPlugin::default())
@@ -227,6 +263,7 @@ pub fn cursor_world_pos_system(
     mut cursor_moved_events: EventReader<CursorMoved>,
     mut cursor_world_pos: ResMut<CursorWorldPos>,
     windows: Res<Windows>,
+    grid: Res<Grid>,
     camera_q: Query<(&Transform, &Camera)>,
 ) {
     let (cam_t, cam) = camera_q.single();
@@ -240,7 +277,9 @@ pub fn cursor_world_pos_system(
     if let Some(&CursorMoved { position, .. }) = cursor_moved_events.iter().last() {
         let ndc = (Vec2::new(position.x, position.y) / window_size) * 2.0 - Vec2::ONE;
         let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
-        world_pos.truncate();
+
+        // everything placed at the cursor lands on the manufacturing grid
+        let world_pos = grid.snap_vec2(world_pos.truncate());
 
         cursor_world_pos.x = world_pos.x;
         cursor_world_pos.y = world_pos.y;
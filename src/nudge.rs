@@ -0,0 +1,157 @@
+use crate::{
+    editing::{shape_count_description, AtomicAction, EditEvent, SelectedShapes},
+    grid::{Grid, MAJOR_GRID_EVERY},
+    index::{IndexedShape, ShapeId},
+    tools::ActiveTool,
+};
+
+use bevy::prelude::*;
+use bevy_egui::EguiContext;
+
+use rstar::Envelope;
+
+pub struct NudgePlugin;
+
+impl Plugin for NudgePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(NudgeStep::default())
+            .add_event::<MoveSelectionEvent>()
+            .add_system(nudge_key_system)
+            .add_system(move_selection_system);
+    }
+}
+
+/// How far the arrow keys move the selection, in grid steps. Holding shift moves it
+/// a major grid line instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct NudgeStep(pub i32);
+
+impl Default for NudgeStep {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+/// Move the selected shapes of the loaded cell by an exact amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveSelectionEvent {
+    /// Move by an offset in database units.
+    By(IVec2),
+    /// Move so the lower left corner of the selection's bounding box is at a point.
+    To(IVec2),
+}
+
+impl MoveSelectionEvent {
+    /// The offset that moves shapes with their lower left corner at `lower_left`.
+    pub fn offset(&self, lower_left: IVec2) -> IVec2 {
+        match self {
+            MoveSelectionEvent::By(offset) => *offset,
+            MoveSelectionEvent::To(point) => *point - lower_left,
+        }
+    }
+}
+
+pub fn nudge_key_system(
+    keyboard: Res<Input<KeyCode>>,
+    tool: Res<ActiveTool>,
+    grid: Res<Grid>,
+    step: Res<NudgeStep>,
+    mut egui_ctx: ResMut<EguiContext>,
+    mut move_ev: EventWriter<MoveSelectionEvent>,
+) {
+    if *tool != ActiveTool::Select || egui_ctx.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    let direction = if keyboard.just_pressed(KeyCode::Left) {
+        IVec2::new(-1, 0)
+    } else if keyboard.just_pressed(KeyCode::Right) {
+        IVec2::new(1, 0)
+    } else if keyboard.just_pressed(KeyCode::Down) {
+        IVec2::new(0, -1)
+    } else if keyboard.just_pressed(KeyCode::Up) {
+        IVec2::new(0, 1)
+    } else {
+        return;
+    };
+
+    let mut distance = **step * grid.step();
+    if keyboard.pressed(KeyCode::LShift) {
+        distance *= MAJOR_GRID_EVERY as i32;
+    }
+
+    move_ev.send(MoveSelectionEvent::By(direction * distance));
+}
+
+pub fn move_selection_system(
+    selected: SelectedShapes,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut move_ev: EventReader<MoveSelectionEvent>,
+) {
+    for ev in move_ev.iter() {
+        // the geometry with any drag folded in, so every coordinate ends up an integer
+        let shapes = selected.editable("moving");
+
+        if shapes.is_empty() {
+            continue;
+        }
+
+        let [x, y] = shapes
+            .iter()
+            .map(|(_, shape)| shape.geometry.envelope())
+            .reduce(|a, b| a.merged(&b))
+            .unwrap()
+            .lower();
+        let offset = ev.offset(IVec2::new(x as i32, y as i32));
+
+        if offset == IVec2::ZERO {
+            continue;
+        }
+
+        let modified = shapes
+            .into_iter()
+            .map(|(id, before)| {
+                let after = IndexedShape {
+                    geometry: before.geometry.translate(offset.x, offset.y),
+                    ..before.clone()
+                };
+                (id, before, after)
+            })
+            .collect::<Vec<(ShapeId, IndexedShape, IndexedShape)>>();
+
+        let description = shape_count_description("Move", modified.len());
+        let description = match ev {
+            MoveSelectionEvent::By(_) => format!("{description} by ({}, {})", offset.x, offset.y),
+            MoveSelectionEvent::To(p) => format!("{description} to ({}, {})", p.x, p.y),
+        };
+
+        edit_ev.send(EditEvent {
+            description,
+            action: AtomicAction::Modify { shapes: modified },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MoveSelectionEvent;
+    use bevy::prelude::IVec2;
+
+    #[test]
+    fn move_offset() {
+        let lower_left = IVec2::new(15, -40);
+
+        assert_eq!(
+            MoveSelectionEvent::By(IVec2::new(5, 0)).offset(lower_left),
+            IVec2::new(5, 0)
+        );
+        assert_eq!(
+            MoveSelectionEvent::To(IVec2::new(100, 100)).offset(lower_left),
+            IVec2::new(85, 140)
+        );
+        assert_eq!(
+            MoveSelectionEvent::To(lower_left).offset(lower_left),
+            IVec2::ZERO
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{
+    editing::{shape_count_description, AtomicAction, EditEvent, SelectedShapes},
+    grid::Grid,
+    import::{LoadCellEvent, OpenVlsirLibEvent},
+    index::{IndexedShape, ShapeId},
+    tools::ActiveTool,
+    CursorWorldPos,
+};
+
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy_egui::EguiContext;
+use bevy_prototype_lyon::prelude::{shapes as lyon_shapes, DrawMode, GeometryBuilder, StrokeMode};
+
+use rstar::Envelope;
+
+pub struct OrientPlugin;
+
+impl Plugin for OrientPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Pivot::default())
+            .add_event::<OrientSelectionEvent>()
+            .add_system_to_stage("reset_world", reset_pivot_system)
+            .add_system(orient_key_shortcut_system)
+            .add_system(set_pivot_system)
+            .add_system(orient_selection_system)
+            .add_system_to_stage("highlight", draw_pivot_system);
+    }
+}
+
+/// Half the width in pixels of the drawn pivot marker.
+pub const PIVOT_SIZE_PX: f32 = 8.0;
+
+/// A rotation counter-clockwise or a mirror about a pivot. Mirror X reflects about the
+/// horizontal axis through the pivot and mirror Y about the vertical one, as in GDSII.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    R90,
+    R180,
+    R270,
+    MirrorX,
+    MirrorY,
+}
+
+impl Orientation {
+    pub const ALL: [Orientation; 5] = [
+        Orientation::R90,
+        Orientation::R180,
+        Orientation::R270,
+        Orientation::MirrorX,
+        Orientation::MirrorY,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Orientation::R90 => "Rotate 90°",
+            Orientation::R180 => "Rotate 180°",
+            Orientation::R270 => "Rotate 270°",
+            Orientation::MirrorX => "Mirror X",
+            Orientation::MirrorY => "Mirror Y",
+        }
+    }
+
+    pub fn shortcut(&self) -> &'static str {
+        match self {
+            Orientation::R90 => "Ctrl+R",
+            Orientation::R180 => "Ctrl+Alt+R",
+            Orientation::R270 => "Ctrl+Shift+R",
+            Orientation::MirrorX => "X",
+            Orientation::MirrorY => "Y",
+        }
+    }
+
+    /// e.g. "Rotate 3 shapes by 90°".
+    pub fn description(&self, count: usize) -> String {
+        match self {
+            Orientation::R90 => format!("{} by 90°", shape_count_description("Rotate", count)),
+            Orientation::R180 => format!("{} by 180°", shape_count_description("Rotate", count)),
+            Orientation::R270 => format!("{} by 270°", shape_count_description("Rotate", count)),
+            Orientation::MirrorX => format!("{} about X", shape_count_description("Mirror", count)),
+            Orientation::MirrorY => format!("{} about Y", shape_count_description("Mirror", count)),
+        }
+    }
+
+    pub fn apply(&self, p: IVec2, pivot: IVec2) -> IVec2 {
+        let d = p - pivot;
+        let d = match self {
+            Orientation::R90 => IVec2::new(-d.y, d.x),
+            Orientation::R180 => -d,
+            Orientation::R270 => IVec2::new(d.y, -d.x),
+            Orientation::MirrorX => IVec2::new(d.x, -d.y),
+            Orientation::MirrorY => IVec2::new(-d.x, d.y),
+        };
+        pivot + d
+    }
+}
+
+/// Point the selection is rotated and mirrored about, set with a middle click.
+/// Without one the centre of the selection is used.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct Pivot(pub Option<IVec2>);
+
+/// Rotate or mirror the selected shapes of the loaded cell.
+#[derive(Debug, Clone, Copy)]
+pub struct OrientSelectionEvent(pub Orientation);
+
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct PivotMarker;
+
+pub fn reset_pivot_system(
+    mut pivot: ResMut<Pivot>,
+    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut load_cell_event_reader: EventReader<LoadCellEvent>,
+) {
+    let open = open_vlsir_lib_event_reader.iter().count() > 0;
+    let load = load_cell_event_reader.iter().count() > 0;
+
+    if (open || load) && pivot.is_some() {
+        *pivot = Pivot(None);
+    }
+}
+
+pub fn orient_key_shortcut_system(
+    keyboard: Res<Input<KeyCode>>,
+    tool: Res<ActiveTool>,
+    mut egui_ctx: ResMut<EguiContext>,
+    mut orient_ev: EventWriter<OrientSelectionEvent>,
+) {
+    if *tool != ActiveTool::Select || egui_ctx.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    let ctrl = keyboard.pressed(KeyCode::LControl);
+
+    let orientation = if ctrl && keyboard.just_pressed(KeyCode::R) {
+        if keyboard.pressed(KeyCode::LShift) {
+            Orientation::R270
+        } else if keyboard.pressed(KeyCode::LAlt) {
+            Orientation::R180
+        } else {
+            Orientation::R90
+        }
+    } else if !ctrl && keyboard.just_pressed(KeyCode::X) {
+        Orientation::MirrorX
+    } else if !ctrl && keyboard.just_pressed(KeyCode::Y) {
+        Orientation::MirrorY
+    } else {
+        return;
+    };
+
+    orient_ev.send(OrientSelectionEvent(orientation));
+}
+
+/// Place the pivot at the cursor with a middle click, or clear it by middle clicking
+/// on it again.
+pub fn set_pivot_system(
+    input_mouse: Res<Input<MouseButton>>,
+    mut egui_ctx: ResMut<EguiContext>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    mut pivot: ResMut<Pivot>,
+) {
+    if !input_mouse.just_pressed(MouseButton::Middle) || egui_ctx.ctx_mut().wants_pointer_input() {
+        return;
+    }
+
+    let point = cursor_world_pos.round().as_ivec2();
+
+    if **pivot == Some(point) {
+        info!("Cleared pivot, rotating about the selection centre");
+        *pivot = Pivot(None);
+    } else {
+        info!("Set pivot to {point}");
+        *pivot = Pivot(Some(point));
+    }
+}
+
+pub fn orient_selection_system(
+    pivot: Res<Pivot>,
+    grid: Res<Grid>,
+    selected: SelectedShapes,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut orient_ev: EventReader<OrientSelectionEvent>,
+) {
+    for OrientSelectionEvent(orientation) in orient_ev.iter() {
+        let shapes = selected.editable("orienting");
+
+        if shapes.is_empty() {
+            continue;
+        }
+
+        // on the grid, so shapes on the grid stay on it
+        let pivot = pivot.unwrap_or_else(|| {
+            let envelope = shapes
+                .iter()
+                .map(|(_, shape)| shape.geometry.envelope())
+                .reduce(|a, b| a.merged(&b))
+                .unwrap();
+            let [x, y] = envelope.center();
+            IVec2::new(grid.snap_i32(x as i32), grid.snap_i32(y as i32))
+        });
+
+        let modified = shapes
+            .into_iter()
+            .map(|(id, before)| {
+                let after = IndexedShape {
+                    geometry: before.geometry.map_points(|p| orientation.apply(p, pivot)),
+                    ..before.clone()
+                };
+                (id, before, after)
+            })
+            .collect::<Vec<(ShapeId, IndexedShape, IndexedShape)>>();
+
+        edit_ev.send(EditEvent {
+            description: orientation.description(modified.len()),
+            action: AtomicAction::Modify { shapes: modified },
+        });
+    }
+}
+
+/// Mark the pivot with a cross while one is set.
+pub fn draw_pivot_system(
+    mut commands: Commands,
+    pivot: Res<Pivot>,
+    camera_q: Query<&OrthographicProjection, (With<Camera>, Changed<OrthographicProjection>)>,
+    marker_q: Query<Entity, With<PivotMarker>>,
+    mut scale: Local<f32>,
+) {
+    if let Ok(proj) = camera_q.get_single() {
+        *scale = proj.scale;
+    } else if !pivot.is_changed() {
+        return;
+    }
+
+    for e in marker_q.iter() {
+        commands.entity(e).despawn();
+    }
+
+    let point = match **pivot {
+        Some(point) => point.as_vec2(),
+        None => return,
+    };
+
+    let s = PIVOT_SIZE_PX * *scale;
+
+    let mut cross = GeometryBuilder::new();
+    cross.add(&lyon_shapes::Line(Vec2::new(-s, -s), Vec2::new(s, s)));
+    cross.add(&lyon_shapes::Line(Vec2::new(-s, s), Vec2::new(s, -s)));
+
+    commands
+        .spawn_bundle(cross.build(
+            DrawMode::Stroke(StrokeMode::new(Color::CYAN, 2.0 * *scale)),
+            Transform::from_translation(point.extend(900.0)),
+        ))
+        .insert(PivotMarker);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Orientation;
+    use crate::index::ShapeGeometry;
+    use crate::shapes::GeoRect;
+    use bevy::prelude::IVec2;
+    use geo::coord;
+
+    #[test]
+    fn orient_about_pivot() {
+        let pivot = IVec2::new(10, 10);
+        let p = IVec2::new(30, 15);
+
+        assert_eq!(Orientation::R90.apply(p, pivot), IVec2::new(5, 30));
+        assert_eq!(Orientation::R180.apply(p, pivot), IVec2::new(-10, 5));
+        assert_eq!(Orientation::R270.apply(p, pivot), IVec2::new(15, -10));
+        assert_eq!(Orientation::MirrorX.apply(p, pivot), IVec2::new(30, 5));
+        assert_eq!(Orientation::MirrorY.apply(p, pivot), IVec2::new(-10, 15));
+
+        // four quarter turns and two mirrors are the identity
+        let r = (0..4).fold(p, |p, _| Orientation::R90.apply(p, pivot));
+        assert_eq!(r, p);
+        let m = Orientation::MirrorY.apply(Orientation::MirrorY.apply(p, pivot), pivot);
+        assert_eq!(m, p);
+    }
+
+    #[test]
+    fn rotated_rect_stays_normalized() {
+        let rect = GeoRect::new(coord! { x: 0, y: 0 }, coord! { x: 40, y: 10 });
+        let rotated =
+            ShapeGeometry::Rect(rect).map_points(|p| Orientation::R90.apply(p, IVec2::ZERO));
+
+        assert_eq!(
+            rotated,
+            ShapeGeometry::Rect(GeoRect::new(
+                coord! { x: -10, y: 0 },
+                coord! { x: 0, y: 40 }
+            ))
+        );
+    }
+}
@@ -45,3 +45,11 @@ pub struct PathBundle {
     #[bundle]
     pub shape: ShapeBundle,
 }
+
+/// Marker component for shapes that belong to the loaded cell's own `Layout::elems`,
+/// as opposed to shapes flattened out of one of its instances. Only these are
+/// written back into the cell when the library is saved.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct CellElem {
+    pub purpose: raw::LayerPurpose,
+}
@@ -0,0 +1,566 @@
+use crate::{
+    cursor_world_pos_system,
+    editing::{click_and_drag_shape_system, Dragging},
+    grid::Grid,
+    hierarchy::InstanceTree,
+    import::{LoadCellEvent, OpenVlsirLibEvent, VlsirLib},
+    index::{Envelope, ShapeGeometry, ShapeId, ShapeIndex},
+    shapes::{Path, Poly, Rect},
+    tools::ActiveTool,
+    ui::LayersUIState,
+    CursorWorldPos,
+};
+
+use std::collections::HashSet;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy_prototype_lyon::prelude::{shapes as lyon_shapes, DrawMode, GeometryBuilder, StrokeMode};
+
+use layout21::raw::{BoundBox, BoundBoxTrait};
+
+pub struct SnapPlugin;
+
+impl Plugin for SnapPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ObjectSnap::default())
+            .insert_resource(SnapTarget::default())
+            .insert_resource(CellPorts::default())
+            .add_system_to_stage("import", cell_ports_system)
+            .add_system(object_snap_cursor_system.after(cursor_world_pos_system))
+            .add_system_to_stage(
+                "click_and_drag",
+                object_snap_drag_system.after(click_and_drag_shape_system),
+            )
+            .add_system_to_stage("highlight", draw_snap_indicator_system);
+    }
+}
+
+/// Size in pixels of the drawn snap indicator.
+pub const SNAP_INDICATOR_SIZE_PX: f32 = 6.0;
+
+/// Snapping to the vertices, edge midpoints and centres of nearby shapes and to the
+/// loaded cell's abstract ports, on top of the grid snapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjectSnap {
+    pub enabled: bool,
+    /// How close in pixels the cursor has to get to a point to snap to it.
+    pub radius_px: f32,
+}
+
+impl Default for ObjectSnap {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius_px: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapKind {
+    Vertex,
+    Midpoint,
+    Center,
+    Port,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapPoint {
+    pub point: Vec2,
+    pub kind: SnapKind,
+}
+
+/// The point the cursor or the dragged shapes are currently snapped to, marked by
+/// the snap indicator.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deref, DerefMut)]
+pub struct SnapTarget(pub Option<SnapPoint>);
+
+/// Centres of the shapes of the loaded cell's abstract ports.
+#[derive(Debug, Default, Clone, Deref, DerefMut)]
+pub struct CellPorts(pub Vec<Vec2>);
+
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct SnapIndicator;
+
+/// Finds the snap points near a position among the shapes of the [ShapeIndex] on
+/// visible layers and instances. Dragged shapes are never snapped to.
+#[derive(SystemParam)]
+pub struct ObjectSnapper<'w, 's> {
+    pub settings: Res<'w, ObjectSnap>,
+    pub grid: Res<'w, Grid>,
+    ports: Res<'w, CellPorts>,
+    shape_index: Res<'w, ShapeIndex>,
+    layer_state: Res<'w, LayersUIState>,
+    instance_tree: Res<'w, InstanceTree>,
+    camera_q: Query<'w, 's, &'static OrthographicProjection, With<Camera>>,
+    dragging_q: Query<'w, 's, &'static ShapeId, With<Dragging>>,
+}
+
+impl<'w, 's> ObjectSnapper<'w, 's> {
+    /// The snap radius in world units at the current zoom.
+    pub fn radius(&self) -> f32 {
+        let scale = self.camera_q.get_single().map_or(1.0, |proj| proj.scale);
+        self.settings.radius_px * scale
+    }
+
+    /// Every snap point within the box from `min` to `max`.
+    pub fn candidates(&self, min: Vec2, max: Vec2) -> Vec<SnapPoint> {
+        let hidden_layers = self
+            .layer_state
+            .layers
+            .iter()
+            .filter(|(vis, _, _)| !vis)
+            .map(|(_, layer, _)| *layer)
+            .collect::<HashSet<u8>>();
+
+        let dragging = self
+            .dragging_q
+            .iter()
+            .copied()
+            .collect::<HashSet<ShapeId>>();
+
+        let envelope = Envelope::from_corners(
+            [min.x.floor() as i64, min.y.floor() as i64],
+            [max.x.ceil() as i64, max.y.ceil() as i64],
+        );
+
+        let mut points = vec![];
+
+        for id in self
+            .shape_index
+            .locate_in_envelope_intersecting(&envelope, |layer| !hidden_layers.contains(&layer))
+        {
+            if dragging.contains(&id) {
+                continue;
+            }
+            if let Some(shape) = self.shape_index.get(id) {
+                if !self.instance_tree.is_hidden(shape.instance)
+                    && !self.instance_tree.is_collapsed(shape.instance)
+                {
+                    shape_snap_points(&shape.geometry, Vec2::ZERO, &mut points);
+                }
+            }
+        }
+
+        points.extend(self.ports.iter().map(|&point| SnapPoint {
+            point,
+            kind: SnapKind::Port,
+        }));
+
+        points.retain(|p| p.point.cmpge(min).all() && p.point.cmple(max).all());
+        retain_on_grid(&mut points, &self.grid);
+        points
+    }
+
+    /// The snap point closest to `pos` within the snap radius.
+    pub fn snap(&self, pos: Vec2) -> Option<SnapPoint> {
+        if !self.settings.enabled {
+            return None;
+        }
+
+        let radius = Vec2::splat(self.radius());
+
+        nearest(&self.candidates(pos - radius, pos + radius), pos, radius.x)
+    }
+
+    /// `pos` snapped to a nearby shape or port, or else to the grid.
+    pub fn snap_or_grid(&self, pos: Vec2) -> Vec2 {
+        self.snap(pos)
+            .map_or_else(|| self.grid.snap_vec2(pos), |p| p.point)
+    }
+}
+
+/// Drop the snap points that aren't on the grid, like the midpoint of an edge an odd
+/// number of grid pitches long, so snapping never moves a vertex off the grid.
+pub fn retain_on_grid(points: &mut Vec<SnapPoint>, grid: &Grid) {
+    points.retain(|p| grid.is_on_grid(p.point));
+}
+
+/// The point of `candidates` closest to `pos`, if it is within `radius`.
+pub fn nearest(candidates: &[SnapPoint], pos: Vec2, radius: f32) -> Option<SnapPoint> {
+    candidates
+        .iter()
+        .map(|p| (p.point.distance(pos), p))
+        .filter(|(d, _)| *d <= radius)
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, p)| *p)
+}
+
+/// The vertices, edge midpoints and centre of a rect or polygon, or the points and
+/// segment midpoints of a path's centerline, moved by `offset`.
+pub fn shape_snap_points(geometry: &ShapeGeometry, offset: Vec2, points: &mut Vec<SnapPoint>) {
+    let to_vec2 = |x: i32, y: i32| Vec2::new(x as f32, y as f32) + offset;
+
+    let vertices = match geometry {
+        ShapeGeometry::Rect(r) => vec![
+            to_vec2(r.min().x, r.min().y),
+            to_vec2(r.max().x, r.min().y),
+            to_vec2(r.max().x, r.max().y),
+            to_vec2(r.min().x, r.max().y),
+        ],
+        ShapeGeometry::Poly(p) => {
+            let mut vertices = p
+                .exterior()
+                .coords()
+                .map(|c| to_vec2(c.x, c.y))
+                .collect::<Vec<Vec2>>();
+            // the ring is closed by repeating its first vertex
+            if vertices.len() > 1 && vertices.first() == vertices.last() {
+                vertices.pop();
+            }
+            vertices
+        }
+        ShapeGeometry::Path(p) => {
+            let vertices = p
+                .points
+                .iter()
+                .map(|p| to_vec2(p.x as i32, p.y as i32))
+                .collect::<Vec<Vec2>>();
+
+            points.extend(vertices.windows(2).map(|segment| SnapPoint {
+                point: (segment[0] + segment[1]) / 2.0,
+                kind: SnapKind::Midpoint,
+            }));
+            points.extend(vertices.into_iter().map(|point| SnapPoint {
+                point,
+                kind: SnapKind::Vertex,
+            }));
+            return;
+        }
+    };
+
+    if vertices.is_empty() {
+        return;
+    }
+
+    points.extend((0..vertices.len()).map(|i| SnapPoint {
+        point: (vertices[i] + vertices[(i + 1) % vertices.len()]) / 2.0,
+        kind: SnapKind::Midpoint,
+    }));
+
+    let (min, max) = vertices
+        .iter()
+        .fold((vertices[0], vertices[0]), |(min, max), &v| {
+            (min.min(v), max.max(v))
+        });
+
+    points.push(SnapPoint {
+        point: (min + max) / 2.0,
+        kind: SnapKind::Center,
+    });
+
+    points.extend(vertices.into_iter().map(|point| SnapPoint {
+        point,
+        kind: SnapKind::Vertex,
+    }));
+}
+
+pub fn cell_ports_system(
+    vlsir_lib: Res<VlsirLib>,
+    mut ports: ResMut<CellPorts>,
+    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    mut load_cell_event_reader: EventReader<LoadCellEvent>,
+) {
+    for _ in open_vlsir_lib_event_reader.iter() {
+        *ports = CellPorts::default();
+    }
+
+    for LoadCellEvent(idx) in load_cell_event_reader.iter() {
+        let cell = match vlsir_lib.lib.as_ref().and_then(|lib| lib.cells.get(*idx)) {
+            Some(cell) => cell.read().unwrap(),
+            None => continue,
+        };
+
+        **ports = cell
+            .abs
+            .iter()
+            .flat_map(|abs| abs.ports.iter())
+            .flat_map(|port| port.shapes.values().flatten())
+            .map(|shape| {
+                let BoundBox { p0, p1 } = shape.bbox();
+                Vec2::new((p0.x + p1.x) as f32, (p0.y + p1.y) as f32) / 2.0
+            })
+            .collect();
+
+        info!("Snapping to {} abstract port shapes", ports.len());
+    }
+}
+
+/// Snap the cursor to nearby shapes and ports while a drawing tool is active, so
+/// new shapes start and end on existing geometry.
+pub fn object_snap_cursor_system(
+    tool: Res<ActiveTool>,
+    snapper: ObjectSnapper,
+    mut cursor_world_pos: ResMut<CursorWorldPos>,
+    mut target: ResMut<SnapTarget>,
+) {
+    if !tool.draws() {
+        if tool.is_changed() && target.is_some() {
+            **target = None;
+        }
+        return;
+    }
+
+    if !(cursor_world_pos.is_changed() || snapper.settings.is_changed() || tool.is_changed()) {
+        return;
+    }
+
+    let snapped = snapper.snap(**cursor_world_pos);
+
+    if let Some(SnapPoint { point, .. }) = snapped {
+        cursor_world_pos.x = point.x;
+        cursor_world_pos.y = point.y;
+    }
+
+    if **target != snapped {
+        **target = snapped;
+    }
+}
+
+/// While shapes are dragged, move them so that the snap point of theirs closest to a
+/// snap point of another shape or port lands on it. The offset applied for the last
+/// snap is taken back out first, so the shapes keep following the cursor.
+pub fn object_snap_drag_system(
+    snapper: ObjectSnapper,
+    cursor_world_pos: Res<CursorWorldPos>,
+    mut dragging_q: Query<
+        (&mut Transform, Option<&Rect>, Option<&Poly>, Option<&Path>),
+        With<Dragging>,
+    >,
+    mut target: ResMut<SnapTarget>,
+    mut offset: Local<Vec2>,
+) {
+    if dragging_q.is_empty() {
+        *offset = Vec2::ZERO;
+        if target.is_some() {
+            **target = None;
+        }
+        return;
+    }
+
+    if !cursor_world_pos.is_changed() {
+        return;
+    }
+
+    let mut moving = vec![];
+
+    for (mut transform, rect, poly, path) in dragging_q.iter_mut() {
+        transform.translation -= offset.extend(0.0);
+        let geometry = if let Some(rect) = rect {
+            ShapeGeometry::Rect(rect.0)
+        } else if let Some(poly) = poly {
+            ShapeGeometry::Poly(poly.0.clone())
+        } else if let Some(path) = path {
+            ShapeGeometry::Path(path.0.clone())
+        } else {
+            continue;
+        };
+        shape_snap_points(&geometry, transform.translation.truncate(), &mut moving);
+    }
+
+    *offset = Vec2::ZERO;
+
+    // the offset between two points on the grid keeps the dragged shapes on it
+    retain_on_grid(&mut moving, &snapper.grid);
+
+    let snapped = if snapper.settings.enabled && !moving.is_empty() {
+        let radius = snapper.radius();
+        let (min, max) = moving.iter().fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), p| (min.min(p.point), max.max(p.point)),
+        );
+        let candidates = snapper.candidates(min - radius, max + radius);
+
+        moving
+            .iter()
+            .filter_map(|m| {
+                nearest(&candidates, m.point, radius).map(|c| (c.point.distance(m.point), m, c))
+            })
+            .min_by(|(a, ..), (b, ..)| a.total_cmp(b))
+            .map(|(_, m, c)| {
+                *offset = c.point - m.point;
+                c
+            })
+    } else {
+        None
+    };
+
+    if *offset != Vec2::ZERO {
+        for (mut transform, ..) in dragging_q.iter_mut() {
+            transform.translation += offset.extend(0.0);
+        }
+    }
+
+    if **target != snapped {
+        **target = snapped;
+    }
+}
+
+/// Mark the current snap target with a small outline whose shape shows what kind of
+/// point it is: a square for vertices, a triangle for midpoints, a circle for centres
+/// and a diamond for ports.
+pub fn draw_snap_indicator_system(
+    mut commands: Commands,
+    target: Res<SnapTarget>,
+    camera_q: Query<&OrthographicProjection, (With<Camera>, Changed<OrthographicProjection>)>,
+    indicator_q: Query<Entity, With<SnapIndicator>>,
+    mut scale: Local<f32>,
+) {
+    if let Ok(proj) = camera_q.get_single() {
+        *scale = proj.scale;
+    } else if !target.is_changed() {
+        return;
+    }
+
+    for e in indicator_q.iter() {
+        commands.entity(e).despawn();
+    }
+
+    let SnapPoint { point, kind } = match **target {
+        Some(target) => target,
+        None => return,
+    };
+
+    let s = SNAP_INDICATOR_SIZE_PX * *scale;
+
+    let draw_mode = DrawMode::Stroke(StrokeMode::new(Color::YELLOW, 1.5 * *scale));
+    let transform = Transform::from_translation(point.extend(900.0));
+
+    let bundle = match kind {
+        SnapKind::Vertex => GeometryBuilder::build_as(
+            &lyon_shapes::Rectangle {
+                extents: Vec2::splat(2.0 * s),
+                origin: lyon_shapes::RectangleOrigin::Center,
+            },
+            draw_mode,
+            transform,
+        ),
+        SnapKind::Center => GeometryBuilder::build_as(
+            &lyon_shapes::Circle {
+                radius: s,
+                center: Vec2::ZERO,
+            },
+            draw_mode,
+            transform,
+        ),
+        SnapKind::Midpoint => GeometryBuilder::build_as(
+            &lyon_shapes::Polygon {
+                points: vec![Vec2::new(-s, -s), Vec2::new(s, -s), Vec2::new(0.0, s)],
+                closed: true,
+            },
+            draw_mode,
+            transform,
+        ),
+        SnapKind::Port => GeometryBuilder::build_as(
+            &lyon_shapes::Polygon {
+                points: vec![
+                    Vec2::new(0.0, -s),
+                    Vec2::new(s, 0.0),
+                    Vec2::new(0.0, s),
+                    Vec2::new(-s, 0.0),
+                ],
+                closed: true,
+            },
+            draw_mode,
+            transform,
+        ),
+    };
+
+    commands.spawn_bundle(bundle).insert(SnapIndicator);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nearest, retain_on_grid, shape_snap_points, SnapKind, SnapPoint};
+    use crate::grid::Grid;
+    use crate::index::ShapeGeometry;
+    use crate::shapes::{GeoPolygon, GeoRect};
+    use bevy::prelude::*;
+    use geo::{coord, LineString};
+
+    #[test]
+    fn rect_snap_points() {
+        let rect = ShapeGeometry::Rect(GeoRect::new(
+            coord! { x: 0, y: 0 },
+            coord! { x: 100, y: 50 },
+        ));
+
+        let mut points = vec![];
+        shape_snap_points(&rect, Vec2::new(10.0, 20.0), &mut points);
+
+        let count = |kind| points.iter().filter(|p| p.kind == kind).count();
+        assert_eq!(count(SnapKind::Vertex), 4);
+        assert_eq!(count(SnapKind::Midpoint), 4);
+        assert_eq!(count(SnapKind::Center), 1);
+
+        let at = |pos| nearest(&points, pos, 5.0);
+        assert_eq!(
+            at(Vec2::new(112.0, 68.0)),
+            Some(SnapPoint {
+                point: Vec2::new(110.0, 70.0),
+                kind: SnapKind::Vertex
+            })
+        );
+        assert_eq!(at(Vec2::new(58.0, 21.0)).unwrap().kind, SnapKind::Midpoint);
+        assert_eq!(at(Vec2::new(60.0, 44.0)).unwrap().kind, SnapKind::Center);
+        assert_eq!(at(Vec2::new(35.0, 35.0)), None);
+    }
+
+    #[test]
+    fn polygon_snap_points() {
+        let ring = [(0, 0), (40, 0), (40, 20), (20, 40), (0, 20)]
+            .into_iter()
+            .map(|(x, y)| coord! { x: x, y: y })
+            .collect::<LineString<i32>>();
+        let poly = ShapeGeometry::Poly(GeoPolygon::new(ring, vec![]));
+
+        let mut points = vec![];
+        shape_snap_points(&poly, Vec2::ZERO, &mut points);
+
+        let count = |kind| points.iter().filter(|p| p.kind == kind).count();
+        assert_eq!(count(SnapKind::Vertex), 5);
+        assert_eq!(count(SnapKind::Midpoint), 5);
+        assert_eq!(count(SnapKind::Center), 1);
+
+        // the closing edge from (0, 20) back to (0, 0)
+        assert_eq!(
+            nearest(&points, Vec2::new(1.0, 9.0), 3.0),
+            Some(SnapPoint {
+                point: Vec2::new(0.0, 10.0),
+                kind: SnapKind::Midpoint
+            })
+        );
+    }
+
+    #[test]
+    fn off_grid_midpoints_are_not_snapped_to() {
+        // three grid pitches wide, so the midpoints of the horizontal edges are off the grid
+        let rect =
+            ShapeGeometry::Rect(GeoRect::new(coord! { x: 0, y: 0 }, coord! { x: 15, y: 10 }));
+
+        let grid = Grid::default();
+        assert_eq!(grid.step(), 5);
+
+        let mut points = vec![];
+        shape_snap_points(&rect, Vec2::ZERO, &mut points);
+        retain_on_grid(&mut points, &grid);
+
+        assert!(points.iter().all(|p| grid.is_on_grid(p.point)));
+
+        let count = |kind| points.iter().filter(|p| p.kind == kind).count();
+        assert_eq!(count(SnapKind::Vertex), 4);
+        assert_eq!(count(SnapKind::Midpoint), 2);
+        assert_eq!(count(SnapKind::Center), 0);
+
+        assert_eq!(nearest(&points, Vec2::new(7.0, 1.0), 5.0), None);
+        assert_eq!(
+            nearest(&points, Vec2::new(14.0, 6.0), 5.0),
+            Some(SnapPoint {
+                point: Vec2::new(15.0, 5.0),
+                kind: SnapKind::Midpoint
+            })
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use crate::editing::{Hovered, Selected};
+use crate::hierarchy::Highlighted;
+use crate::import::{Layer, LayerColors, Layers, VlsirLib};
+use crate::shapes::Path;
+use crate::ui::{ErrorDialogUIState, LayersUIState};
+use crate::{InLayer, ALPHA};
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use bevy_prototype_lyon::prelude::DrawMode;
+
+use layout21::raw::{self, LayerPurpose, Library};
+
+use serde::Deserialize;
+
+pub struct TechPlugin;
+
+impl Plugin for TechPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LayerMap::sky130())
+            .add_event::<LoadLayerMapEvent>()
+            .add_system(load_layer_map_system)
+            .add_system(restyle_shapes_system);
+    }
+}
+
+/// Built-in layer map for the SkyWater SKY130 process, which our designs target.
+pub const SKY130_LAYER_MAP: &str = include_str!("../tech/sky130.toml");
+
+/// How the inside of a layer's shapes is drawn. lyon has no textured fills,
+/// so the stippled patterns are drawn as a fainter translucent fill.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FillPattern {
+    Solid,
+    Hatched,
+    Dotted,
+    Hollow,
+}
+
+impl Default for FillPattern {
+    fn default() -> Self {
+        FillPattern::Solid
+    }
+}
+
+impl FillPattern {
+    /// Opacity of the fill of shapes drawn with this pattern.
+    pub fn alpha(&self) -> f32 {
+        match self {
+            FillPattern::Solid => ALPHA,
+            FillPattern::Hatched | FillPattern::Dotted => ALPHA / 2.0,
+            FillPattern::Hollow => 0.0,
+        }
+    }
+}
+
+fn default_visible() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LayerMapEntry {
+    pub name: String,
+    /// GDS layer number.
+    pub layer: i16,
+    /// GDS datatype.
+    pub datatype: i16,
+    /// Hex RGB or RGBA colour, e.g. "3366FF".
+    pub color: String,
+    #[serde(default)]
+    pub fill: FillPattern,
+    #[serde(default = "default_visible")]
+    pub visible: bool,
+    /// Draw order from 0 to 255, higher is drawn on top. Defaults to the layer number.
+    pub z: Option<f32>,
+    /// Minimum width of wires on the layer, the path tool's preset width for it.
+    pub width: Option<usize>,
+}
+
+impl LayerMapEntry {
+    pub fn color(&self) -> Color {
+        // validated when the map is parsed
+        Color::hex(&self.color).unwrap()
+    }
+}
+
+/// Names, colours, fill patterns, visibility and draw order of a technology's layers,
+/// keyed by GDS layer/datatype. Read from a TOML file of `[[layers]]` tables, see
+/// `tech/sky130.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LayerMap {
+    pub name: String,
+    #[serde(default)]
+    pub layers: Vec<LayerMapEntry>,
+}
+
+impl LayerMap {
+    pub fn from_toml(s: &str) -> Result<Self, String> {
+        let map: LayerMap = toml::from_str(s).map_err(|e| format!("invalid layer map: {e}"))?;
+
+        for entry in map.layers.iter() {
+            Color::hex(&entry.color).map_err(|e| {
+                format!(
+                    "invalid color '{}' for layer '{}' ({}/{}): {e:?}",
+                    entry.color, entry.name, entry.layer, entry.datatype
+                )
+            })?;
+        }
+
+        Ok(map)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read layer map file: {e}"))?;
+        Self::from_toml(&s)
+    }
+
+    pub fn sky130() -> Self {
+        Self::from_toml(SKY130_LAYER_MAP).expect("Built-in SKY130 layer map is invalid")
+    }
+
+    /// The entry for GDS `layer`/`datatype`. Without an exact match, or without a
+    /// datatype, the entry with the lowest datatype on `layer` is used.
+    pub fn get(&self, layer: i16, datatype: Option<i16>) -> Option<&LayerMapEntry> {
+        datatype
+            .and_then(|datatype| {
+                self.layers
+                    .iter()
+                    .find(|e| e.layer == layer && e.datatype == datatype)
+            })
+            .or_else(|| {
+                self.layers
+                    .iter()
+                    .filter(|e| e.layer == layer)
+                    .min_by_key(|e| e.datatype)
+            })
+    }
+}
+
+/// Style every layer of `lib` with its entry in `layer_map`. Shapes are only grouped by
+/// layer number, so each layer takes the style of the datatype of its drawing purpose.
+/// Layers missing from the map keep the library's name and get the next of `layer_colors`.
+pub fn style_layers(lib: &Library, layer_map: &LayerMap, layer_colors: &mut LayerColors) -> Layers {
+    let mut layers = Layers::default();
+
+    let lib_layers = &lib.layers.read().unwrap().slots;
+
+    for lib_layer in lib_layers.values() {
+        let raw::Layer { layernum, name, .. } = lib_layer;
+        let num = *layernum as u8;
+
+        let layer = match layer_map.get(*layernum, lib_layer.num(&LayerPurpose::Drawing)) {
+            Some(entry) => Layer {
+                path_width: entry.width,
+                ..Layer::new(
+                    Some(entry.name.clone()),
+                    entry.color(),
+                    entry.fill,
+                    entry.visible,
+                    entry.z.unwrap_or(num as f32).clamp(0.0, 255.0),
+                )
+            },
+            None => Layer::new(
+                name.clone(),
+                layer_colors.get_color(),
+                FillPattern::default(),
+                true,
+                num as f32,
+            ),
+        };
+
+        if layers.insert(num, layer).is_some() {
+            panic!(
+                "Library layers corrupted multiple definitions for layer number {}",
+                num
+            );
+        }
+    }
+
+    layers
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LoadLayerMapEvent {
+    pub path: String,
+}
+
+pub fn load_layer_map_system(
+    vlsir_lib: Res<VlsirLib>,
+    mut layer_map: ResMut<LayerMap>,
+    mut layer_colors: ResMut<LayerColors>,
+    mut layers: ResMut<Layers>,
+    mut layer_state: ResMut<LayersUIState>,
+    mut error_dialog_state: ResMut<ErrorDialogUIState>,
+    mut load_layer_map_event_reader: EventReader<LoadLayerMapEvent>,
+) {
+    for LoadLayerMapEvent { path } in load_layer_map_event_reader.iter() {
+        match LayerMap::load(path) {
+            Ok(map) => {
+                info!(
+                    "Loaded layer map '{}' with {} layers from '{path}'",
+                    map.name,
+                    map.layers.len()
+                );
+
+                *layer_map = map;
+
+                if let Some(lib) = vlsir_lib.lib.as_ref() {
+                    *layer_colors = LayerColors::default();
+                    *layers = style_layers(lib, &layer_map, &mut layer_colors);
+                    // rebuilt from the new layers by the layers widget
+                    layer_state.layers = vec![];
+                }
+            }
+            Err(e) => {
+                error!("Failed to load layer map '{path}': {e}");
+                error_dialog_state.error = Some((
+                    "Failed to load layer map".to_owned(),
+                    format!("{path}\n\n{e}"),
+                ));
+            }
+        }
+    }
+}
+
+/// Redraw the spawned shapes on every layer whose style changed, keeping the
+/// highlighting of hovered, selected and highlighted shapes.
+pub fn restyle_shapes_system(
+    layers: Res<Layers>,
+    mut applied: Local<HashMap<u8, Layer>>,
+    mut shape_q: Query<(
+        &InLayer,
+        &mut DrawMode,
+        &mut Transform,
+        Option<&Path>,
+        Option<&Hovered>,
+        Option<&Selected>,
+        Option<&Highlighted>,
+    )>,
+) {
+    if !layers.is_changed() {
+        return;
+    }
+
+    // newly spawned shapes are drawn with their layer's current style,
+    // so only shapes on layers edited since the last restyle need redrawing
+    let changed = layers
+        .iter()
+        .filter(|(num, layer)| applied.get(num) != Some(layer))
+        .map(|(num, _)| *num)
+        .collect::<HashSet<u8>>();
+
+    *applied = (**layers).clone();
+
+    if changed.is_empty() {
+        return;
+    }
+
+    for (layer, mut draw, mut t, path, hovered, selected, highlighted) in shape_q.iter_mut() {
+        if !changed.contains(&**layer) {
+            continue;
+        }
+
+        let layer = match layers.get(&**layer) {
+            Some(layer) => layer,
+            None => continue,
+        };
+
+        let old_outline_color = match &*draw {
+            DrawMode::Outlined { outline_mode, .. } => Some(outline_mode.color),
+            _ => None,
+        };
+
+        let mut new_draw = layer.draw_mode(path.map(|p| p.width as f32));
+
+        if let DrawMode::Outlined {
+            fill_mode,
+            outline_mode,
+        } = &mut new_draw
+        {
+            if selected.is_some() {
+                fill_mode.color.set_a(0.75);
+            } else if hovered.is_some() {
+                fill_mode.color.set_a(0.5);
+            }
+            if let (Some(_), Some(color)) = (highlighted, old_outline_color) {
+                outline_mode.color = color;
+            }
+        }
+
+        *draw = new_draw;
+        t.translation.z = layer.z;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FillPattern, LayerMap};
+
+    #[test]
+    fn sky130_layer_map() {
+        let map = LayerMap::sky130();
+        assert_eq!(map.name, "sky130");
+
+        let met1 = map.get(68, Some(20)).unwrap();
+        assert_eq!(met1.name, "met1");
+        assert_eq!(met1.fill, FillPattern::Hatched);
+        assert!(met1.visible);
+        assert_eq!(met1.width, Some(140));
+
+        assert_eq!(map.get(68, Some(44)).unwrap().name, "via");
+        assert_eq!(map.get(68, Some(44)).unwrap().width, None);
+
+        // no exact match falls back to the lowest datatype on the layer
+        assert_eq!(map.get(68, Some(16)).unwrap().name, "met1");
+        assert_eq!(map.get(68, None).unwrap().name, "met1");
+        assert!(map.get(1, None).is_none());
+    }
+
+    #[test]
+    fn layer_map_rejects_bad_colors() {
+        let map = r#"
+            name = "bad"
+
+            [[layers]]
+            name = "met1"
+            layer = 68
+            datatype = 20
+            color = "not a color"
+        "#;
+        assert!(LayerMap::from_toml(map).is_err());
+    }
+}
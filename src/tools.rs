@@ -0,0 +1,712 @@
+use crate::{
+    editing::{rubber_band_rect, AtomicAction, EditEvent, Interaction, PointerInitialPos},
+    grid::Grid,
+    import::{Layers, Net, OpenVlsirLibEvent, VlsirCell},
+    index::{IndexedShape, ShapeGeometry, ShapeIndex},
+    screen_to_world_pos,
+    shapes::{CellElem, GeoPolygon, GeoRect},
+    snap::ObjectSnapper,
+    CursorWorldPos,
+};
+
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy_egui::EguiContext;
+use bevy_prototype_lyon::entity;
+use bevy_prototype_lyon::prelude::{
+    shapes as lyon_shapes, DrawMode, FillMode, FillOptions, GeometryBuilder, StrokeMode,
+    StrokeOptions,
+};
+
+use geo::LineString;
+
+use layout21::raw::{self, LayerPurpose};
+
+pub struct ToolsPlugin;
+
+impl Plugin for ToolsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ActiveTool::default())
+            .insert_resource(ActiveLayer::default())
+            .insert_resource(SegmentConstraint::default())
+            .insert_resource(PathWidth::default())
+            .add_system_to_stage("reset_world", reset_active_layer_system)
+            .add_system(tool_key_shortcut_system)
+            .add_system_to_stage("click_and_drag", draw_rect_system)
+            .add_system_to_stage("click_and_drag", draw_polygon_system)
+            .add_system(path_width_preset_system)
+            .add_system_to_stage("click_and_drag", draw_path_system);
+    }
+}
+
+/// What clicking and dragging on the canvas does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActiveTool {
+    Select,
+    Rect,
+    Polygon,
+    Path,
+}
+
+impl Default for ActiveTool {
+    fn default() -> Self {
+        ActiveTool::Select
+    }
+}
+
+impl ActiveTool {
+    pub const ALL: [ActiveTool; 4] = [
+        ActiveTool::Select,
+        ActiveTool::Rect,
+        ActiveTool::Polygon,
+        ActiveTool::Path,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ActiveTool::Select => "Select",
+            ActiveTool::Rect => "Rect",
+            ActiveTool::Polygon => "Polygon",
+            ActiveTool::Path => "Path",
+        }
+    }
+
+    pub fn draws(&self) -> bool {
+        *self != ActiveTool::Select
+    }
+}
+
+/// Directions the segments drawn by the polygon and path tools are allowed to take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentConstraint {
+    /// Horizontal and vertical only.
+    Manhattan,
+    /// Horizontal, vertical and 45°.
+    Octilinear,
+    Any,
+}
+
+impl Default for SegmentConstraint {
+    fn default() -> Self {
+        SegmentConstraint::Manhattan
+    }
+}
+
+impl SegmentConstraint {
+    pub const ALL: [SegmentConstraint; 3] = [
+        SegmentConstraint::Manhattan,
+        SegmentConstraint::Octilinear,
+        SegmentConstraint::Any,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegmentConstraint::Manhattan => "Manhattan",
+            SegmentConstraint::Octilinear => "45°",
+            SegmentConstraint::Any => "Any angle",
+        }
+    }
+
+    /// The point closest to `to` that a segment starting at `from` may end at. With
+    /// both on a grid of `pitch`, so is the returned point.
+    pub fn constrain(&self, from: IVec2, to: IVec2, pitch: i32) -> IVec2 {
+        let d = to - from;
+        let (dx, dy) = (d.x.abs(), d.y.abs());
+
+        let manhattan = if dx >= dy {
+            IVec2::new(to.x, from.y)
+        } else {
+            IVec2::new(from.x, to.y)
+        };
+
+        match self {
+            SegmentConstraint::Manhattan => manhattan,
+            SegmentConstraint::Octilinear => {
+                // snap to the diagonal once the cursor is past 22.5° from both axes
+                if (dx.min(dy) as f32) < dx.max(dy) as f32 * std::f32::consts::FRAC_PI_8.tan() {
+                    manhattan
+                } else {
+                    let m = (dx + dy + pitch) / (2 * pitch) * pitch;
+                    from + IVec2::new(m * d.x.signum(), m * d.y.signum())
+                }
+            }
+            SegmentConstraint::Any => to,
+        }
+    }
+
+    pub fn allows(&self, from: IVec2, to: IVec2) -> bool {
+        self.constrain(from, to, 1) == to
+    }
+
+    /// Close the polygon through `points`, adding a corner before the closing
+    /// segment when it would break the constraint. `None` without at least three
+    /// distinct vertices.
+    pub fn close(&self, points: &[IVec2]) -> Option<Vec<IVec2>> {
+        let mut points = points.to_vec();
+        points.dedup();
+
+        let (first, last) = (*points.first()?, *points.last()?);
+        if points.len() > 1 && first == last {
+            points.pop();
+        } else if !self.allows(last, first) {
+            points.push(IVec2::new(first.x, last.y));
+        }
+
+        if points.len() < 3 {
+            return None;
+        }
+
+        Some(points)
+    }
+}
+
+/// Layer that new shapes are drawn on, picked in the Layers window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct ActiveLayer(pub Option<u8>);
+
+/// Preview of the shape being drawn, replaced by the real shape when drawing ends.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct DrawingPreview;
+
+pub fn reset_active_layer_system(
+    mut active_layer: ResMut<ActiveLayer>,
+    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+) {
+    for _ in open_vlsir_lib_event_reader.iter() {
+        *active_layer = ActiveLayer(None);
+    }
+}
+
+pub fn tool_key_shortcut_system(
+    keyboard: Res<Input<KeyCode>>,
+    mut tool: ResMut<ActiveTool>,
+    mut egui_ctx: ResMut<EguiContext>,
+) {
+    if egui_ctx.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::Escape) {
+        *tool = ActiveTool::Select;
+    } else if keyboard.just_pressed(KeyCode::R) && !keyboard.pressed(KeyCode::LControl) {
+        *tool = ActiveTool::Rect;
+    } else if keyboard.just_pressed(KeyCode::P) {
+        *tool = ActiveTool::Polygon;
+    } else if keyboard.just_pressed(KeyCode::L) {
+        *tool = ActiveTool::Path;
+    }
+}
+
+/// How shapes on `layer` are drawn while they are being drawn.
+pub fn preview_draw_mode(layers: &Layers, layer: u8) -> DrawMode {
+    let color = layers.get(&layer).map(|l| l.color).unwrap_or(Color::WHITE);
+
+    DrawMode::Outlined {
+        fill_mode: FillMode {
+            color: *color.clone().set_a(0.5),
+            options: FillOptions::default(),
+        },
+        outline_mode: StrokeMode {
+            options: StrokeOptions::default().with_line_width(crate::WIDTH),
+            color: *color.clone().set_a(1.0),
+        },
+    }
+}
+
+/// Add a shape drawn with one of the tools to the loaded cell.
+pub fn create_shape(
+    description: &str,
+    shape: IndexedShape,
+    shape_index: &mut ShapeIndex,
+    edit_ev: &mut EventWriter<EditEvent>,
+) {
+    edit_ev.send(EditEvent {
+        description: description.to_owned(),
+        action: AtomicAction::Create {
+            shapes: vec![(shape_index.next_id(), shape)],
+        },
+    });
+}
+
+/// Vertex under the cursor, rounded to the integer grid.
+pub fn cursor_vertex(cursor_world_pos: &CursorWorldPos) -> IVec2 {
+    IVec2::new(
+        cursor_world_pos.x.round() as i32,
+        cursor_world_pos.y.round() as i32,
+    )
+}
+
+/// Drag out a rectangle on the active layer with the rect tool. The new rectangle
+/// goes into the shape index, from which it is streamed in like any other shape of
+/// the cell and saved into the cell's elements.
+pub fn draw_rect_system(
+    mut commands: Commands,
+    tool: Res<ActiveTool>,
+    active_layer: Res<ActiveLayer>,
+    layers: Res<Layers>,
+    windows: Res<Windows>,
+    camera_q: Query<(&Transform, &Camera)>,
+    pointer_initial_pos: Res<PointerInitialPos>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    snapper: ObjectSnapper,
+    preview_q: Query<Entity, With<DrawingPreview>>,
+    mut shape_index: ResMut<ShapeIndex>,
+    vlsir_cell: Res<VlsirCell>,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut interaction_ev: EventReader<Interaction>,
+    mut drawing: Local<Option<(u8, Vec2)>>,
+) {
+    for ev in interaction_ev.iter() {
+        match ev {
+            Interaction::DragStart => {
+                if *tool != ActiveTool::Rect || vlsir_cell.index.is_none() {
+                    continue;
+                }
+                if let (Some(layer), Some(pos)) = (**active_layer, **pointer_initial_pos) {
+                    let start = snapper.snap_or_grid(screen_to_world_pos(&windows, &camera_q, pos));
+                    let (_, preview) =
+                        rubber_band_rect(start, start, preview_draw_mode(&layers, layer));
+                    commands.spawn_bundle(preview).insert(DrawingPreview);
+                    *drawing = Some((layer, start));
+                }
+            }
+            Interaction::DragEnd => {
+                let (layer, start) = match drawing.take() {
+                    Some(drawing) => drawing,
+                    None => continue,
+                };
+
+                for e in preview_q.iter() {
+                    commands.entity(e).despawn();
+                }
+
+                let end = **cursor_world_pos;
+                let rect = GeoRect::new(
+                    (start.x.round() as i32, start.y.round() as i32),
+                    (end.x.round() as i32, end.y.round() as i32),
+                );
+
+                if rect.width() == 0 || rect.height() == 0 {
+                    info!("Skipping empty rect on layer {layer}");
+                    continue;
+                }
+
+                let shape = IndexedShape {
+                    layer,
+                    net: Net(None),
+                    geometry: ShapeGeometry::Rect(rect),
+                    elem: Some(CellElem {
+                        purpose: LayerPurpose::Drawing,
+                    }),
+                    instance: None,
+                };
+
+                create_shape("Draw rect", shape, &mut shape_index, &mut edit_ev);
+
+                info!("Drew rect {rect:?} on layer {layer}");
+            }
+            Interaction::Click => (),
+        }
+    }
+
+    if let (Some((layer, start)), Ok(e)) = (*drawing, preview_q.get_single()) {
+        let (_, preview) =
+            rubber_band_rect(start, **cursor_world_pos, preview_draw_mode(&layers, layer));
+        commands.entity(e).insert_bundle(preview);
+    }
+}
+
+/// Seconds within which a second click finishes the polygon or path being drawn.
+pub const DOUBLE_CLICK_SECS: f64 = 0.4;
+
+/// Width of wires drawn with the path tool on layers without a width preset.
+pub const DEFAULT_PATH_WIDTH: usize = 100;
+
+/// Width of the wires drawn with the path tool, reset to the active layer's
+/// preset from the layer map whenever the active layer changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct PathWidth(pub usize);
+
+impl Default for PathWidth {
+    fn default() -> Self {
+        PathWidth(DEFAULT_PATH_WIDTH)
+    }
+}
+
+/// Vertices placed so far by the polygon or path tool.
+#[derive(Debug, Default, Clone)]
+pub struct VertexDrawing {
+    pub layer: u8,
+    pub points: Vec<IVec2>,
+    pub last_click: f64,
+    /// Grid that the vertices are placed on.
+    pub pitch: i32,
+}
+
+impl VertexDrawing {
+    fn new(layer: u8, cursor: IVec2, now: f64, pitch: i32) -> Self {
+        Self {
+            layer,
+            points: vec![cursor],
+            last_click: now,
+            pitch,
+        }
+    }
+
+    /// Place the next vertex towards `cursor`, or return true when the click is the
+    /// second of a double-click, which finishes the drawing.
+    fn click(&mut self, cursor: IVec2, now: f64, constraint: &SegmentConstraint) -> bool {
+        let double_click = now - self.last_click < DOUBLE_CLICK_SECS;
+        if !double_click {
+            let next = self.next_vertex(cursor, constraint);
+            self.points.push(next);
+        }
+        self.last_click = now;
+        double_click
+    }
+
+    fn next_vertex(&self, cursor: IVec2, constraint: &SegmentConstraint) -> IVec2 {
+        constraint.constrain(*self.points.last().unwrap(), cursor, self.pitch)
+    }
+
+    /// The placed vertices followed by the one the cursor would place next.
+    fn preview_points(&self, cursor: IVec2, constraint: &SegmentConstraint) -> Vec<Vec2> {
+        self.points
+            .iter()
+            .copied()
+            .chain(std::iter::once(self.next_vertex(cursor, constraint)))
+            .map(|p| p.as_vec2())
+            .collect()
+    }
+}
+
+fn spawn_vertex_preview(commands: &mut Commands, points: Vec<Vec2>, draw_mode: DrawMode) {
+    commands
+        .spawn_bundle(vertex_preview(points, draw_mode))
+        .insert(DrawingPreview);
+}
+
+fn vertex_preview(points: Vec<Vec2>, draw_mode: DrawMode) -> entity::ShapeBundle {
+    let lyon_poly = lyon_shapes::Polygon {
+        points,
+        closed: false,
+    };
+
+    GeometryBuilder::build_as(
+        &lyon_poly,
+        draw_mode,
+        Transform::from_translation(Vec3::new(0.0, 0.0, 800.0)),
+    )
+}
+
+/// Place the vertices of a polygon on the active layer with clicks, each segment
+/// held to the `SegmentConstraint`, and close it with a double-click or Enter.
+pub fn draw_polygon_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    keyboard: Res<Input<KeyCode>>,
+    tool: Res<ActiveTool>,
+    constraint: Res<SegmentConstraint>,
+    active_layer: Res<ActiveLayer>,
+    layers: Res<Layers>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    grid: Res<Grid>,
+    preview_q: Query<Entity, With<DrawingPreview>>,
+    mut shape_index: ResMut<ShapeIndex>,
+    vlsir_cell: Res<VlsirCell>,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut egui_ctx: ResMut<EguiContext>,
+    mut interaction_ev: EventReader<Interaction>,
+    mut drawing: Local<Option<VertexDrawing>>,
+) {
+    if *tool != ActiveTool::Polygon || vlsir_cell.index.is_none() {
+        if drawing.take().is_some() {
+            for e in preview_q.iter() {
+                commands.entity(e).despawn();
+            }
+        }
+        interaction_ev.iter().for_each(drop);
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+    let cursor = cursor_vertex(&cursor_world_pos);
+    // Enter in a text field isn't meant for the polygon
+    let keyboard_free = !egui_ctx.ctx_mut().wants_keyboard_input();
+    let mut close = keyboard_free && keyboard.just_pressed(KeyCode::Return);
+
+    for ev in interaction_ev.iter() {
+        if *ev != Interaction::Click {
+            continue;
+        }
+
+        match drawing.as_mut() {
+            Some(poly) => close |= poly.click(cursor, now, &constraint),
+            None => {
+                if let Some(layer) = **active_layer {
+                    let poly = VertexDrawing::new(layer, cursor, now, grid.step());
+                    spawn_vertex_preview(
+                        &mut commands,
+                        poly.preview_points(cursor, &constraint),
+                        preview_draw_mode(&layers, layer),
+                    );
+                    *drawing = Some(poly);
+                }
+            }
+        }
+    }
+
+    if close {
+        if let Some(VertexDrawing { layer, points, .. }) = drawing.take() {
+            for e in preview_q.iter() {
+                commands.entity(e).despawn();
+            }
+
+            match constraint.close(&points) {
+                Some(points) => {
+                    let poly = GeoPolygon::new(
+                        LineString::from(points.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>()),
+                        vec![],
+                    );
+
+                    let shape = IndexedShape {
+                        layer,
+                        net: Net(None),
+                        geometry: ShapeGeometry::Poly(poly),
+                        elem: Some(CellElem {
+                            purpose: LayerPurpose::Drawing,
+                        }),
+                        instance: None,
+                    };
+
+                    create_shape("Draw polygon", shape, &mut shape_index, &mut edit_ev);
+
+                    info!(
+                        "Drew polygon with {} vertices on layer {layer}",
+                        points.len()
+                    );
+                }
+                None => info!("Skipping polygon with fewer than 3 vertices on layer {layer}"),
+            }
+        }
+    }
+
+    if let (Some(poly), Ok(e)) = (drawing.as_ref(), preview_q.get_single()) {
+        commands.entity(e).insert_bundle(vertex_preview(
+            poly.preview_points(cursor, &constraint),
+            preview_draw_mode(&layers, poly.layer),
+        ));
+    }
+}
+
+pub fn path_width_preset_system(
+    active_layer: Res<ActiveLayer>,
+    layers: Res<Layers>,
+    mut path_width: ResMut<PathWidth>,
+) {
+    if !active_layer.is_changed() {
+        return;
+    }
+
+    if let Some(width) = active_layer
+        .and_then(|layer| layers.get(&layer))
+        .and_then(|layer| layer.path_width)
+    {
+        **path_width = width;
+    }
+}
+
+/// Lay down a wire of `PathWidth` on the active layer, clicking to add each bend and
+/// finishing with a double-click or Enter. Backspace takes back the last bend.
+pub fn draw_path_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    keyboard: Res<Input<KeyCode>>,
+    tool: Res<ActiveTool>,
+    constraint: Res<SegmentConstraint>,
+    active_layer: Res<ActiveLayer>,
+    path_width: Res<PathWidth>,
+    layers: Res<Layers>,
+    cursor_world_pos: Res<CursorWorldPos>,
+    grid: Res<Grid>,
+    preview_q: Query<Entity, With<DrawingPreview>>,
+    mut shape_index: ResMut<ShapeIndex>,
+    vlsir_cell: Res<VlsirCell>,
+    mut edit_ev: EventWriter<EditEvent>,
+    mut egui_ctx: ResMut<EguiContext>,
+    mut interaction_ev: EventReader<Interaction>,
+    mut drawing: Local<Option<VertexDrawing>>,
+) {
+    if *tool != ActiveTool::Path || vlsir_cell.index.is_none() {
+        if drawing.take().is_some() {
+            for e in preview_q.iter() {
+                commands.entity(e).despawn();
+            }
+        }
+        interaction_ev.iter().for_each(drop);
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+    let cursor = cursor_vertex(&cursor_world_pos);
+    let keyboard_free = !egui_ctx.ctx_mut().wants_keyboard_input();
+    let mut finish = keyboard_free && keyboard.just_pressed(KeyCode::Return);
+
+    // the preview is drawn like the finished path, so it shows the wire's real width
+    let draw_mode = |layer: u8| match layers.get(&layer) {
+        Some(layer) => layer.draw_mode(Some(**path_width as f32)),
+        None => preview_draw_mode(&layers, layer),
+    };
+
+    for ev in interaction_ev.iter() {
+        if *ev != Interaction::Click {
+            continue;
+        }
+
+        match drawing.as_mut() {
+            Some(path) => finish |= path.click(cursor, now, &constraint),
+            None => {
+                if let Some(layer) = **active_layer {
+                    let path = VertexDrawing::new(layer, cursor, now, grid.step());
+                    spawn_vertex_preview(
+                        &mut commands,
+                        path.preview_points(cursor, &constraint),
+                        draw_mode(layer),
+                    );
+                    *drawing = Some(path);
+                }
+            }
+        }
+    }
+
+    if keyboard_free && keyboard.just_pressed(KeyCode::Back) {
+        if let Some(path) = drawing.as_mut() {
+            path.points.pop();
+            if path.points.is_empty() {
+                *drawing = None;
+                for e in preview_q.iter() {
+                    commands.entity(e).despawn();
+                }
+            }
+        }
+    }
+
+    if finish {
+        if let Some(VertexDrawing {
+            layer, mut points, ..
+        }) = drawing.take()
+        {
+            for e in preview_q.iter() {
+                commands.entity(e).despawn();
+            }
+
+            points.dedup();
+
+            if points.len() < 2 {
+                info!("Skipping path with fewer than 2 points on layer {layer}");
+            } else {
+                let path = raw::Path {
+                    points: points
+                        .iter()
+                        .map(|p| raw::Point::new(p.x as isize, p.y as isize))
+                        .collect(),
+                    width: **path_width,
+                };
+
+                let shape = IndexedShape {
+                    layer,
+                    net: Net(None),
+                    geometry: ShapeGeometry::Path(path),
+                    elem: Some(CellElem {
+                        purpose: LayerPurpose::Drawing,
+                    }),
+                    instance: None,
+                };
+
+                create_shape("Draw path", shape, &mut shape_index, &mut edit_ev);
+
+                info!(
+                    "Drew path with {} points and width {} on layer {layer}",
+                    points.len(),
+                    **path_width
+                );
+            }
+        }
+    }
+
+    if let (Some(path), Ok(e)) = (drawing.as_ref(), preview_q.get_single()) {
+        commands.entity(e).insert_bundle(vertex_preview(
+            path.preview_points(cursor, &constraint),
+            draw_mode(path.layer),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SegmentConstraint;
+    use bevy::math::IVec2;
+
+    #[test]
+    fn segments_follow_constraint() {
+        let from = IVec2::new(0, 0);
+
+        let manhattan = SegmentConstraint::Manhattan;
+        assert_eq!(
+            manhattan.constrain(from, IVec2::new(10, 3), 1),
+            IVec2::new(10, 0)
+        );
+        assert_eq!(
+            manhattan.constrain(from, IVec2::new(-2, -7), 1),
+            IVec2::new(0, -7)
+        );
+
+        let octilinear = SegmentConstraint::Octilinear;
+        assert_eq!(
+            octilinear.constrain(from, IVec2::new(10, 3), 1),
+            IVec2::new(10, 0)
+        );
+        assert_eq!(
+            octilinear.constrain(from, IVec2::new(10, 8), 1),
+            IVec2::new(9, 9)
+        );
+        assert_eq!(
+            octilinear.constrain(from, IVec2::new(-6, 6), 1),
+            IVec2::new(-6, 6)
+        );
+
+        // diagonals stay on the grid
+        assert_eq!(
+            octilinear.constrain(from, IVec2::new(10, 5), 5),
+            IVec2::new(10, 10)
+        );
+
+        assert_eq!(
+            SegmentConstraint::Any.constrain(from, IVec2::new(10, 3), 1),
+            IVec2::new(10, 3)
+        );
+    }
+
+    #[test]
+    fn closing_adds_a_corner_when_needed() {
+        let points = [IVec2::new(0, 0), IVec2::new(10, 0), IVec2::new(10, 10)];
+
+        assert_eq!(
+            SegmentConstraint::Manhattan.close(&points).unwrap(),
+            vec![
+                IVec2::new(0, 0),
+                IVec2::new(10, 0),
+                IVec2::new(10, 10),
+                IVec2::new(0, 10)
+            ]
+        );
+        // the 45° closing segment is allowed as is
+        assert_eq!(
+            SegmentConstraint::Octilinear.close(&points).unwrap(),
+            points.to_vec()
+        );
+        assert!(SegmentConstraint::Manhattan
+            .close(&[IVec2::new(0, 0), IVec2::new(10, 0), IVec2::new(10, 0)])
+            .is_none());
+    }
+}
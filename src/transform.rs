@@ -0,0 +1,132 @@
+use layout21::raw::Point;
+
+/// 2D affine transform of layout coordinates, `p' = a * p + b`.
+///
+/// Instances place their cell by first reflecting it about the x-axis (if `reflect_vert`),
+/// then rotating it counter-clockwise by `angle` degrees, then translating it to `loc`,
+/// following the GDSII convention. Nested instances are placed by cascading the transforms
+/// of every instance on the way down the hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayoutTransform {
+    pub a: [[f64; 2]; 2],
+    pub b: [f64; 2],
+}
+
+impl Default for LayoutTransform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl LayoutTransform {
+    pub fn identity() -> Self {
+        Self {
+            a: [[1.0, 0.0], [0.0, 1.0]],
+            b: [0.0, 0.0],
+        }
+    }
+
+    pub fn translate(x: f64, y: f64) -> Self {
+        Self {
+            a: [[1.0, 0.0], [0.0, 1.0]],
+            b: [x, y],
+        }
+    }
+
+    /// Counter-clockwise rotation by `angle` degrees about the origin.
+    pub fn rotate(angle: f64) -> Self {
+        let (sin, cos) = sin_cos_degrees(angle);
+        Self {
+            a: [[cos, -sin], [sin, cos]],
+            b: [0.0, 0.0],
+        }
+    }
+
+    /// Reflection about the x-axis, i.e. `y -> -y`.
+    pub fn reflect_vert() -> Self {
+        Self {
+            a: [[1.0, 0.0], [0.0, -1.0]],
+            b: [0.0, 0.0],
+        }
+    }
+
+    /// Reflection about the y-axis, i.e. `x -> -x`.
+    pub fn reflect_horiz() -> Self {
+        Self {
+            a: [[-1.0, 0.0], [0.0, 1.0]],
+            b: [0.0, 0.0],
+        }
+    }
+
+    /// The transform that places an instance's cell into its parent's coordinates.
+    pub fn from_instance(loc: &Point, reflect_vert: bool, angle: Option<f64>) -> Self {
+        let mut trans = Self::identity();
+        if reflect_vert {
+            trans = Self::reflect_vert();
+        }
+        if let Some(angle) = angle {
+            trans = Self::rotate(angle).cascade(&trans);
+        }
+        Self::translate(loc.x as f64, loc.y as f64).cascade(&trans)
+    }
+
+    /// The transform equivalent to applying `child` first, then `self`.
+    pub fn cascade(&self, child: &LayoutTransform) -> LayoutTransform {
+        let p = &self.a;
+        let c = &child.a;
+        LayoutTransform {
+            a: [
+                [
+                    p[0][0] * c[0][0] + p[0][1] * c[1][0],
+                    p[0][0] * c[0][1] + p[0][1] * c[1][1],
+                ],
+                [
+                    p[1][0] * c[0][0] + p[1][1] * c[1][0],
+                    p[1][0] * c[0][1] + p[1][1] * c[1][1],
+                ],
+            ],
+            b: [
+                p[0][0] * child.b[0] + p[0][1] * child.b[1] + self.b[0],
+                p[1][0] * child.b[0] + p[1][1] * child.b[1] + self.b[1],
+            ],
+        }
+    }
+
+    /// Apply the transform to an (x, y) coordinate, rounding to the integer database grid.
+    pub fn apply_xy(&self, x: f64, y: f64) -> (i64, i64) {
+        let a = &self.a;
+        (
+            (a[0][0] * x + a[0][1] * y + self.b[0]).round() as i64,
+            (a[1][0] * x + a[1][1] * y + self.b[1]).round() as i64,
+        )
+    }
+
+    pub fn apply(&self, p: &Point) -> Point {
+        let (x, y) = self.apply_xy(p.x as f64, p.y as f64);
+        Point::new(x as isize, y as isize)
+    }
+
+    /// Whether the transform maps axis aligned rectangles to axis aligned rectangles,
+    /// i.e. only rotates by multiples of 90 degrees.
+    pub fn is_manhattan(&self) -> bool {
+        let a = &self.a;
+        (a[0][1] == 0.0 && a[1][0] == 0.0) || (a[0][0] == 0.0 && a[1][1] == 0.0)
+    }
+}
+
+/// Sine and cosine of an angle in degrees, exact for multiples of 90 degrees so that
+/// Manhattan instances stay on the integer grid and are recognised by `is_manhattan`.
+fn sin_cos_degrees(angle: f64) -> (f64, f64) {
+    let angle = angle.rem_euclid(360.0);
+    if angle == 0.0 {
+        (0.0, 1.0)
+    } else if angle == 90.0 {
+        (1.0, 0.0)
+    } else if angle == 180.0 {
+        (0.0, -1.0)
+    } else if angle == 270.0 {
+        (-1.0, 0.0)
+    } else {
+        angle.to_radians().sin_cos()
+    }
+}
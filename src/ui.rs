@@ -1,16 +1,36 @@
 use crate::{
-    editing::Selected,
+    array::{ArrayParams, ArraySelectionEvent},
+    clipboard::{Clipboard, ClipboardEvent},
+    editing::{
+        shape_count_description, AtomicAction, ChangeLayerEvent, EditEvent, Selected,
+        SelectedShapes, UndoRedoEvent, UndoRedoHistory,
+    },
+    export::SaveVlsirLibEvent,
+    grid::Grid,
+    hierarchy::{DisplayDepth, HierarchyEvent, InstanceId, InstanceTree},
     import::{
-        ImportLibCompleteEvent, Layer, Layers, LoadCellEvent, Net, OpenVlsirLibEvent, VlsirCell,
-        VlsirLib,
+        lib_file_stem, CellContentsInfo, ImportLibCompleteEvent, Layer, Layers, LoadCellEvent, Net,
+        NewVlsirLibEvent, OpenVlsirLibFailedEvent, ReadVlsirLibEvent, StreamedShapes, VlsirCell,
+        VlsirLib, GDS_EXTENSIONS, PROTO_EXTENSIONS,
     },
+    index::{IndexedShape, ShapeGeometry, ShapeId},
+    inspector::ShapeFields,
+    nudge::{MoveSelectionEvent, NudgeStep},
+    orient::{OrientSelectionEvent, Orientation, Pivot},
     shapes::{Path, Poly, Rect},
+    snap::ObjectSnap,
+    tech::LoadLayerMapEvent,
+    tools::{ActiveLayer, ActiveTool, PathWidth, SegmentConstraint},
     CursorWorldPos, InLayer,
 };
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContext, EguiPlugin};
 use geo::prelude::BoundingRect;
-use layout21::raw::BoundBoxTrait;
+use layout21::raw::{BoundBox, BoundBoxTrait};
 use rfd::FileDialog;
 
 pub struct UIPlugin;
@@ -29,9 +49,84 @@ pub struct LayersUIState {
     pub layers: Vec<(bool, u8, String)>,
 }
 
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CellSortKey {
+    Name,
+    Elems,
+    Insts,
+    Annotations,
+    Ports,
+    Blockages,
+    Area,
+}
+
+impl Default for CellSortKey {
+    fn default() -> Self {
+        CellSortKey::Name
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CellBrowserUIState {
+    pub search: String,
+    pub sort_key: CellSortKey,
+    pub descending: bool,
+    /// Show the cells as a tree of which cells instantiate which instead of a list.
+    pub tree_view: bool,
+    pub clicked: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct LibInfoUILoadingState {
     pub loading: bool,
+    /// The library being read, the loaded one stays loaded until it has been read.
+    pub path: Option<String>,
+}
+
+/// The display depth as it is being dragged, only applied once the drag is released.
+#[derive(Debug, Clone)]
+pub struct DisplayDepthUIState {
+    pub depth: usize,
+}
+
+impl Default for DisplayDepthUIState {
+    fn default() -> Self {
+        Self { depth: 1 }
+    }
+}
+
+/// An error to show the user in a modal-ish dialog until they dismiss it.
+#[derive(Debug, Default, Clone)]
+pub struct ErrorDialogUIState {
+    pub error: Option<(String, String)>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HistoryUIState {
+    pub open: bool,
+}
+
+/// The shape shown in the properties inspector and its fields as they are being edited.
+#[derive(Debug, Default, Clone)]
+pub struct InspectorUIState {
+    pub shape: Option<(ShapeId, IndexedShape)>,
+    pub fields: ShapeFields,
+}
+
+/// The move dialog's inputs, in nm so they don't depend on the library's units.
+#[derive(Debug, Default, Clone)]
+pub struct MoveUIState {
+    pub open: bool,
+    /// Move to `point` rather than by `offset`.
+    pub absolute: bool,
+    pub offset: [f64; 2],
+    pub point: [f64; 2],
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ArrayUIState {
+    pub open: bool,
+    pub params: ArrayParams,
 }
 
 impl Plugin for UIPlugin {
@@ -39,17 +134,461 @@ impl Plugin for UIPlugin {
         app.add_plugin(EguiPlugin)
             .insert_resource(LibInfoUIDropdownState::default())
             .insert_resource(LibInfoUILoadingState::default())
+            .insert_resource(DisplayDepthUIState::default())
             .insert_resource(LayersUIState::default())
+            .insert_resource(CellBrowserUIState::default())
+            .insert_resource(ErrorDialogUIState::default())
+            .insert_resource(HistoryUIState::default())
+            .insert_resource(InspectorUIState::default())
+            .insert_resource(MoveUIState::default())
+            .insert_resource(ArrayUIState::default())
             .init_resource::<NonSendMarker>()
             .add_system(file_menu_system)
             // .add_system(debug_cursor_ui_or_world_system)
             .add_system(lib_info_cell_picker_system)
+            .add_system(cell_browser_system)
             .add_system(load_dropdown_selected_cell_system)
             .add_system(layer_visibility_widget_system)
             .add_system(set_layer_visibility_system)
             .add_system(layer_zindex_stepthru_system)
             .add_system(display_cursor_pos_system)
-            .add_system(display_current_selection_info);
+            .add_system(display_current_selection_info)
+            .add_system(error_dialog_system)
+            .add_system(hierarchy_panel_system)
+            .add_system(history_panel_system)
+            .add_system(move_dialog_system)
+            .add_system(array_dialog_system)
+            .add_system(instance_placeholder_labels_system);
+    }
+}
+
+/// The File menu: creating, opening and saving libraries and loading layer maps.
+#[derive(SystemParam)]
+pub struct FileMenu<'w, 's> {
+    vlsir_lib: Res<'w, VlsirLib>,
+    new_vlsir_lib_event_writer: EventWriter<'w, 's, NewVlsirLibEvent>,
+    read_vlsir_lib_event_writer: EventWriter<'w, 's, ReadVlsirLibEvent>,
+    save_vlsir_lib_event_writer: EventWriter<'w, 's, SaveVlsirLibEvent>,
+    load_layer_map_event_writer: EventWriter<'w, 's, LoadLayerMapEvent>,
+}
+
+impl<'w, 's> FileMenu<'w, 's> {
+    pub fn ui(self, ui: &mut egui::Ui) {
+        let FileMenu {
+            vlsir_lib,
+            mut new_vlsir_lib_event_writer,
+            mut read_vlsir_lib_event_writer,
+            mut save_vlsir_lib_event_writer,
+            mut load_layer_map_event_writer,
+        } = self;
+
+        egui::menu::menu_button(ui, egui::RichText::new("File").size(17.0), |ui| {
+            ui.spacing_mut().button_padding = (8.0, 8.0).into();
+            if ui.button(egui::RichText::new("New").size(16.0)).clicked() {
+                ui.close_menu();
+                new_vlsir_lib_event_writer.send(NewVlsirLibEvent);
+            }
+            if ui.button(egui::RichText::new("Load").size(16.0)).clicked() {
+                ui.close_menu();
+                let all_extensions = PROTO_EXTENSIONS
+                    .iter()
+                    .chain(GDS_EXTENSIONS.iter())
+                    .copied()
+                    .collect::<Vec<&str>>();
+                let path = FileDialog::new()
+                    .add_filter("layout libraries", &all_extensions[..])
+                    .add_filter("protos", &PROTO_EXTENSIONS)
+                    .add_filter("gdsii", &GDS_EXTENSIONS)
+                    .pick_file();
+                // handle file picking cancellation by only sending event if a file was selected
+                if let Some(path) = path {
+                    read_vlsir_lib_event_writer.send(ReadVlsirLibEvent {
+                        path: path.to_str().unwrap().to_owned(),
+                    });
+                }
+            }
+            let lib_loaded = vlsir_lib.lib.is_some();
+            // a new library has no file to save to until it is saved as one
+            if ui
+                .add_enabled(
+                    lib_loaded && vlsir_lib.path.is_some(),
+                    egui::Button::new(egui::RichText::new("Save").size(16.0)),
+                )
+                .clicked()
+            {
+                ui.close_menu();
+                if let Some(path) = vlsir_lib.path.as_ref() {
+                    save_vlsir_lib_event_writer.send(SaveVlsirLibEvent { path: path.clone() });
+                }
+            }
+            if ui
+                .add_enabled(
+                    lib_loaded,
+                    egui::Button::new(egui::RichText::new("Save As").size(16.0)),
+                )
+                .clicked()
+            {
+                ui.close_menu();
+                let file_name = vlsir_lib
+                    .path
+                    .as_ref()
+                    .map(|p| lib_file_stem(p))
+                    .unwrap_or_default();
+                let path = FileDialog::new()
+                    .add_filter("protos", &PROTO_EXTENSIONS)
+                    .add_filter("gdsii", &GDS_EXTENSIONS)
+                    .set_file_name(&file_name)
+                    .save_file();
+                // handle file picking cancellation by only sending event if a file was selected
+                if let Some(path) = path {
+                    save_vlsir_lib_event_writer.send(SaveVlsirLibEvent {
+                        path: path.to_str().unwrap().to_owned(),
+                    });
+                }
+            }
+            if ui
+                .button(egui::RichText::new("Load Layer Map").size(16.0))
+                .clicked()
+            {
+                ui.close_menu();
+                let path = FileDialog::new()
+                    .add_filter("layer maps", &["toml"])
+                    .pick_file();
+                // handle file picking cancellation by only sending event if a file was selected
+                if let Some(path) = path {
+                    load_layer_map_event_writer.send(LoadLayerMapEvent {
+                        path: path.to_str().unwrap().to_owned(),
+                    });
+                }
+            }
+            if ui.button(egui::RichText::new("Quit").size(16.0)).clicked() {
+                std::process::exit(0);
+            }
+        });
+    }
+}
+
+/// The Edit menu: undo and redo, the clipboard and changing the selection's layer.
+#[derive(SystemParam)]
+pub struct EditMenu<'w, 's> {
+    history: Res<'w, UndoRedoHistory>,
+    history_ui_state: ResMut<'w, HistoryUIState>,
+    undo_redo_event_writer: EventWriter<'w, 's, UndoRedoEvent>,
+    clipboard: Res<'w, Clipboard>,
+    clipboard_event_writer: EventWriter<'w, 's, ClipboardEvent>,
+    layer_state: Res<'w, LayersUIState>,
+    change_layer_event_writer: EventWriter<'w, 's, ChangeLayerEvent>,
+    selected_q: Query<'w, 's, (), With<Selected>>,
+}
+
+impl<'w, 's> EditMenu<'w, 's> {
+    pub fn ui(self, ui: &mut egui::Ui) {
+        let EditMenu {
+            history,
+            mut history_ui_state,
+            mut undo_redo_event_writer,
+            clipboard,
+            mut clipboard_event_writer,
+            layer_state,
+            mut change_layer_event_writer,
+            selected_q,
+        } = self;
+
+        egui::menu::menu_button(ui, egui::RichText::new("Edit").size(17.0), |ui| {
+            ui.spacing_mut().button_padding = (8.0, 8.0).into();
+            let undo = history
+                .next_undo()
+                .map(|entry| format!("Undo {}", entry.description));
+            if ui
+                .add_enabled(
+                    undo.is_some(),
+                    egui::Button::new(
+                        egui::RichText::new(undo.as_deref().unwrap_or("Undo")).size(16.0),
+                    ),
+                )
+                .on_hover_text("Ctrl+Z")
+                .clicked()
+            {
+                ui.close_menu();
+                undo_redo_event_writer.send(UndoRedoEvent::Undo);
+            }
+            let redo = history
+                .next_redo()
+                .map(|entry| format!("Redo {}", entry.description));
+            if ui
+                .add_enabled(
+                    redo.is_some(),
+                    egui::Button::new(
+                        egui::RichText::new(redo.as_deref().unwrap_or("Redo")).size(16.0),
+                    ),
+                )
+                .on_hover_text("Ctrl+Shift+Z")
+                .clicked()
+            {
+                ui.close_menu();
+                undo_redo_event_writer.send(UndoRedoEvent::Redo);
+            }
+            ui.separator();
+            for (ev, name, shortcut, enabled) in [
+                (ClipboardEvent::Cut, "Cut", "Ctrl+X", !selected_q.is_empty()),
+                (
+                    ClipboardEvent::Copy,
+                    "Copy",
+                    "Ctrl+C",
+                    !selected_q.is_empty(),
+                ),
+                (
+                    ClipboardEvent::Paste,
+                    "Paste",
+                    "Ctrl+V",
+                    !clipboard.is_empty(),
+                ),
+                (
+                    ClipboardEvent::Duplicate,
+                    "Duplicate",
+                    "Ctrl+D",
+                    !selected_q.is_empty(),
+                ),
+            ] {
+                if ui
+                    .add_enabled(
+                        enabled,
+                        egui::Button::new(egui::RichText::new(name).size(16.0)),
+                    )
+                    .on_hover_text(shortcut)
+                    .clicked()
+                {
+                    ui.close_menu();
+                    clipboard_event_writer.send(ev);
+                }
+            }
+            ui.add_enabled_ui(!selected_q.is_empty(), |ui| {
+                ui.menu_button(egui::RichText::new("Change Layer").size(16.0), |ui| {
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            for (_, num, label) in layer_state.layers.iter() {
+                                if ui.button(label.as_str()).clicked() {
+                                    ui.close_menu();
+                                    change_layer_event_writer.send(ChangeLayerEvent(*num));
+                                }
+                            }
+                        });
+                });
+            });
+            ui.separator();
+            if ui
+                .checkbox(
+                    &mut history_ui_state.open,
+                    egui::RichText::new("History").size(16.0),
+                )
+                .clicked()
+            {
+                ui.close_menu();
+            }
+        });
+    }
+}
+
+/// The Arrange menu: orienting, moving and arraying the selection.
+#[derive(SystemParam)]
+pub struct ArrangeMenu<'w, 's> {
+    pivot: ResMut<'w, Pivot>,
+    orient_selection_event_writer: EventWriter<'w, 's, OrientSelectionEvent>,
+    move_ui_state: ResMut<'w, MoveUIState>,
+    array_ui_state: ResMut<'w, ArrayUIState>,
+    selected_q: Query<'w, 's, (), With<Selected>>,
+}
+
+impl<'w, 's> ArrangeMenu<'w, 's> {
+    pub fn ui(self, ui: &mut egui::Ui) {
+        let ArrangeMenu {
+            mut pivot,
+            mut orient_selection_event_writer,
+            mut move_ui_state,
+            mut array_ui_state,
+            selected_q,
+        } = self;
+
+        egui::menu::menu_button(ui, egui::RichText::new("Arrange").size(17.0), |ui| {
+            ui.spacing_mut().button_padding = (8.0, 8.0).into();
+            for o in Orientation::ALL {
+                if ui
+                    .add_enabled(
+                        !selected_q.is_empty(),
+                        egui::Button::new(egui::RichText::new(o.name()).size(16.0)),
+                    )
+                    .on_hover_text(o.shortcut())
+                    .clicked()
+                {
+                    ui.close_menu();
+                    orient_selection_event_writer.send(OrientSelectionEvent(o));
+                }
+            }
+            ui.separator();
+            if ui
+                .radio(pivot.is_none(), "About the selection centre")
+                .clicked()
+            {
+                **pivot = None;
+            }
+            let about_pivot = match **pivot {
+                Some(p) => format!("About the pivot at ({}, {})", p.x, p.y),
+                None => "About a pivot, middle click to place".to_owned(),
+            };
+            ui.add_enabled(
+                pivot.is_some(),
+                egui::RadioButton::new(pivot.is_some(), about_pivot),
+            );
+            ui.separator();
+            if ui
+                .button(egui::RichText::new("Move...").size(16.0))
+                .on_hover_text("Arrow keys nudge, shift for a major grid step")
+                .clicked()
+            {
+                ui.close_menu();
+                move_ui_state.open = true;
+            }
+            if ui
+                .button(egui::RichText::new("Array...").size(16.0))
+                .clicked()
+            {
+                ui.close_menu();
+                array_ui_state.open = true;
+            }
+        });
+    }
+}
+
+/// The Grid menu: the grid and snapping settings.
+#[derive(SystemParam)]
+pub struct GridMenu<'w, 's> {
+    grid: ResMut<'w, Grid>,
+    object_snap: ResMut<'w, ObjectSnap>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> GridMenu<'w, 's> {
+    pub fn ui(self, ui: &mut egui::Ui) {
+        let GridMenu {
+            mut grid,
+            mut object_snap,
+            ..
+        } = self;
+
+        egui::menu::menu_button(ui, egui::RichText::new("Grid").size(17.0), |ui| {
+            ui.spacing_mut().button_padding = (8.0, 8.0).into();
+            // edit a copy so the grid is only marked changed when a setting changes
+            let mut settings = *grid;
+            ui.checkbox(
+                &mut settings.show,
+                egui::RichText::new("Show grid").size(16.0),
+            );
+            ui.checkbox(
+                &mut settings.snap,
+                egui::RichText::new("Snap to grid").size(16.0),
+            );
+            ui.horizontal(|ui| {
+                ui.label(egui::RichText::new("Manufacturing grid").size(16.0));
+                ui.add(
+                    egui::DragValue::new(&mut settings.manufacturing_grid_nm)
+                        .speed(0.5)
+                        .clamp_range(0.1..=10_000.0)
+                        .suffix(" nm"),
+                );
+            });
+            ui.label(format!("= {} database units", settings.pitch()));
+            if settings != *grid {
+                *grid = settings;
+            }
+
+            ui.separator();
+            let mut snap = *object_snap;
+            ui.checkbox(
+                &mut snap.enabled,
+                egui::RichText::new("Snap to shapes and ports").size(16.0),
+            );
+            if snap != *object_snap {
+                *object_snap = snap;
+            }
+        });
+    }
+}
+
+/// The tools and the settings of the active one, next to the menus.
+#[derive(SystemParam)]
+pub struct ToolBar<'w, 's> {
+    tool: ResMut<'w, ActiveTool>,
+    constraint: ResMut<'w, SegmentConstraint>,
+    path_width: ResMut<'w, PathWidth>,
+    active_layer: Res<'w, ActiveLayer>,
+    layers: Res<'w, Layers>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> ToolBar<'w, 's> {
+    pub fn ui(self, ui: &mut egui::Ui) {
+        let ToolBar {
+            mut tool,
+            mut constraint,
+            mut path_width,
+            active_layer,
+            layers,
+            ..
+        } = self;
+
+        for t in ActiveTool::ALL {
+            if ui
+                .selectable_label(*tool == t, egui::RichText::new(t.name()).size(16.0))
+                .clicked()
+                && *tool != t
+            {
+                *tool = t;
+            }
+        }
+
+        if *tool == ActiveTool::Path {
+            let mut width = **path_width;
+            ui.label("width");
+            ui.add(egui::DragValue::new(&mut width).clamp_range(1..=100_000));
+
+            let preset = active_layer
+                .and_then(|layer| layers.get(&layer))
+                .and_then(|layer| layer.path_width);
+            if let Some(preset) = preset {
+                if ui
+                    .small_button("min")
+                    .on_hover_text(format!("Minimum width of the active layer, {preset}"))
+                    .clicked()
+                {
+                    width = preset;
+                }
+            }
+
+            if width != **path_width {
+                **path_width = width;
+            }
+        }
+
+        if *tool == ActiveTool::Polygon || *tool == ActiveTool::Path {
+            egui::ComboBox::from_id_source("segment_constraint")
+                .selected_text(constraint.name())
+                .show_ui(ui, |ui| {
+                    for c in SegmentConstraint::ALL {
+                        if ui.selectable_label(*constraint == c, c.name()).clicked()
+                            && *constraint != c
+                        {
+                            *constraint = c;
+                        }
+                    }
+                });
+        }
+
+        if tool.draws() && active_layer.is_none() {
+            ui.label("select an active layer in the Layers window");
+        }
     }
 }
 
@@ -60,29 +599,23 @@ pub fn file_menu_system(
     // 'Not Responding'/spinning beachball state
     _marker: NonSend<NonSendMarker>,
     mut egui_ctx: ResMut<EguiContext>,
-    mut open_vlsir_lib_event_writer: EventWriter<OpenVlsirLibEvent>,
+    file_menu: FileMenu,
+    edit_menu: EditMenu,
+    arrange_menu: ArrangeMenu,
+    grid_menu: GridMenu,
+    tool_bar: ToolBar,
 ) {
     egui::TopBottomPanel::top("top_panel").show(egui_ctx.ctx_mut(), |ui| {
         // The top panel is often a good place for a menu bar:
         egui::menu::bar(ui, |ui| {
-            egui::menu::menu_button(ui, egui::RichText::new("File").size(17.0), |ui| {
-                ui.spacing_mut().button_padding = (8.0, 8.0).into();
-                if ui.button(egui::RichText::new("Load").size(16.0)).clicked() {
-                    ui.close_menu();
-                    let path = FileDialog::new()
-                        .add_filter("protos", &["proto"])
-                        .pick_file();
-                    // handle file picking cancellation by only sending event if a file was selected
-                    if let Some(path) = path {
-                        open_vlsir_lib_event_writer.send(OpenVlsirLibEvent {
-                            path: path.to_str().unwrap().to_owned(),
-                        });
-                    }
-                }
-                if ui.button(egui::RichText::new("Quit").size(16.0)).clicked() {
-                    std::process::exit(0);
-                }
-            });
+            file_menu.ui(ui);
+            edit_menu.ui(ui);
+            arrange_menu.ui(ui);
+            grid_menu.ui(ui);
+
+            ui.separator();
+
+            tool_bar.ui(ui);
         });
     });
 }
@@ -91,87 +624,333 @@ pub fn lib_info_cell_picker_system(
     mut egui_ctx: ResMut<EguiContext>,
     vlsir_lib: Res<VlsirLib>,
     vlsir_cell: Res<VlsirCell>,
-    mut open_vlsir_lib_event_reader: EventReader<OpenVlsirLibEvent>,
+    streamed_shapes: Res<StreamedShapes>,
+    display_depth: Res<DisplayDepth>,
+    mut depth_state: ResMut<DisplayDepthUIState>,
+    mut hierarchy_event_writer: EventWriter<HierarchyEvent>,
+    mut read_vlsir_lib_event_reader: EventReader<ReadVlsirLibEvent>,
     mut import_lib_complete_event_reader: EventReader<ImportLibCompleteEvent>,
-    mut dropdown_state: ResMut<LibInfoUIDropdownState>,
+    mut open_vlsir_lib_failed_event_reader: EventReader<OpenVlsirLibFailedEvent>,
     mut loading_state: ResMut<LibInfoUILoadingState>,
+    mut error_dialog_state: ResMut<ErrorDialogUIState>,
 ) {
-    let mut temp = dropdown_state.selected;
-
-    for _ in open_vlsir_lib_event_reader.iter() {
+    for ReadVlsirLibEvent { path } in read_vlsir_lib_event_reader.iter() {
         loading_state.loading = true;
+        loading_state.path = Some(path.clone());
     }
 
     for _ in import_lib_complete_event_reader.iter() {
         loading_state.loading = false;
     }
 
+    for OpenVlsirLibFailedEvent { path, error } in open_vlsir_lib_failed_event_reader.iter() {
+        loading_state.loading = false;
+        error_dialog_state.error = Some((
+            format!("Failed to open library '{}'", lib_file_stem(path)),
+            format!("{path}\n\n{error}"),
+        ));
+    }
+
     egui::Window::new("Library Info")
         .resizable(true)
         .default_pos([5.0, 32.0])
         .show(egui_ctx.ctx_mut(), |ui| {
-            if vlsir_lib.path.is_none() {
-                ui.label(format!("Current Library:"));
-            } else if loading_state.loading {
+            if let (true, Some(path)) = (loading_state.loading, loading_state.path.as_ref()) {
                 ui.horizontal(|ui| {
                     ui.label(format!("Current Library:"));
                     ui.add_space(4.0);
-                    ui.label(format!(
-                        "Loading {}...",
-                        vlsir_lib
-                            .path
-                            .as_ref()
-                            .unwrap()
-                            .split("/")
-                            .last()
-                            .unwrap()
-                            .strip_suffix(".proto")
-                            .unwrap()
-                    ));
+                    ui.label(format!("Loading {}...", lib_file_stem(path)));
                     ui.add(egui::Spinner::new());
                 });
-            } else if vlsir_lib.path.is_some() && vlsir_lib.lib.is_some() {
-                ui.label(format!(
-                    "Current Library: {}",
-                    vlsir_lib.lib.as_ref().unwrap().name
-                ));
+            } else if let Some(lib) = vlsir_lib.lib.as_ref() {
+                ui.label(format!("Current Library: {}", lib.name));
+            } else {
+                ui.label(format!("Current Library:"));
             }
 
             ui.add_space(5.0);
 
-            if let Some(names) = &vlsir_lib.cell_names {
-                let len = names.len();
-                ui.horizontal(|ui| {
-                    ui.spacing_mut().slider_width = 300.0;
-                    ui.add(egui::Label::new("Cells:"));
-                    // ui.add_space(1.0);
-                    ui.add_enabled_ui(true, |ui| {
-                        egui::ComboBox::from_label("")
-                            .show_index(ui, &mut temp, len, |i| names[i].to_owned())
-                    });
-                });
-            } else {
+            let cell_name = vlsir_lib
+                .cell_names
+                .as_ref()
+                .zip(vlsir_cell.index)
+                .map(|(names, idx)| names[idx].as_str())
+                .unwrap_or_default();
+            ui.label(format!("Current Cell: {cell_name}"));
+
+            if let Some(num_shapes) = vlsir_cell.num_shapes.as_ref() {
+                ui.add_space(5.0);
+                ui.label(format!("No. shapes: {num_shapes}"));
+                ui.label(format!("Shapes in view: {}", streamed_shapes.spawned.len()));
+
+                ui.add_space(5.0);
                 ui.horizontal(|ui| {
-                    ui.add(egui::Label::new("Cells:"));
-                    // ui.add_space(1.0);
-                    ui.add_enabled_ui(false, |ui| {
-                        egui::ComboBox::from_label("")
-                            .width(100.0)
-                            .show_ui(ui, |_| {})
-                            .response
-                    });
+                    let mut limited = display_depth.is_some();
+                    let limit_toggled = ui
+                        .checkbox(&mut limited, "Display depth:")
+                        .on_hover_text("Draw instances any deeper as their bounding box")
+                        .changed();
+                    let response = ui.add_enabled(
+                        limited,
+                        egui::DragValue::new(&mut depth_state.depth)
+                            .speed(0.05)
+                            .clamp_range(0..=64),
+                    );
+
+                    // every step of a drag would flatten or collapse the whole tree again
+                    let committed = response.drag_released() || response.lost_focus();
+
+                    let new_depth = if limited {
+                        Some(depth_state.depth)
+                    } else {
+                        None
+                    };
+                    if (limit_toggled || committed) && **display_depth != new_depth {
+                        hierarchy_event_writer.send(HierarchyEvent::SetDisplayDepth(new_depth));
+                    }
                 });
             }
+        });
+}
+
+fn bbox_area(bbox: &BoundBox) -> i128 {
+    if bbox.is_empty() {
+        0
+    } else {
+        (bbox.p1.x - bbox.p0.x) as i128 * (bbox.p1.y - bbox.p0.y) as i128
+    }
+}
+
+fn cell_sort_value(info: &CellContentsInfo, key: CellSortKey) -> i128 {
+    let layout = info.layout.as_ref();
+    let abstrakt = info.abstrakt.as_ref();
+    match key {
+        CellSortKey::Name => 0,
+        CellSortKey::Elems => layout.map_or(0, |l| l.elems as i128),
+        CellSortKey::Insts => layout.map_or(0, |l| l.insts as i128),
+        CellSortKey::Annotations => layout.map_or(0, |l| l.annotations as i128),
+        CellSortKey::Ports => abstrakt.map_or(0, |a| a.ports as i128),
+        CellSortKey::Blockages => abstrakt.map_or(0, |a| a.blockages as i128),
+        CellSortKey::Area => layout.map_or(0, |l| bbox_area(&l.bbox)),
+    }
+}
+
+fn format_bbox(bbox: &BoundBox) -> String {
+    if bbox.is_empty() {
+        "-".to_owned()
+    } else {
+        format!("{} x {}", bbox.p1.x - bbox.p0.x, bbox.p1.y - bbox.p0.y)
+    }
+}
+
+pub fn cell_browser_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    vlsir_lib: Res<VlsirLib>,
+    vlsir_cell: Res<VlsirCell>,
+    mut state: ResMut<CellBrowserUIState>,
+    mut dropdown_state: ResMut<LibInfoUIDropdownState>,
+) {
+    let mut load = None;
 
-            if dropdown_state.selected != temp {
-                dropdown_state.selected = temp;
+    egui::Window::new("Cells")
+        .resizable(true)
+        .default_pos([5.0, 250.0])
+        .default_height(400.0)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            let cells = match vlsir_lib.cell_contents.as_ref() {
+                Some(cells) => cells,
+                None => {
+                    ui.label("No library loaded");
+                    return;
+                }
+            };
+
+            let state = &mut *state;
+
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut state.tree_view, false, "List");
+                ui.selectable_value(&mut state.tree_view, true, "Hierarchy");
+            });
+
+            ui.separator();
+
+            if state.tree_view {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    // cells that aren't instantiated anywhere are the tops of the tree
+                    for (idx, _) in cells
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, c)| c.parents.is_empty())
+                    {
+                        cell_tree_ui(ui, cells, &[], idx, vlsir_cell.index, state, &mut load);
+                    }
+                });
+                return;
             }
 
-            if let Some(num_shapes) = vlsir_cell.num_shapes.as_ref() {
-                ui.add_space(5.0);
-                ui.label(format!("No. shapes: {num_shapes}"));
+            ui.horizontal(|ui| {
+                ui.label("Search:");
+                ui.text_edit_singleline(&mut state.search);
+            });
+
+            let search = state.search.to_lowercase();
+            let mut rows = cells
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.cell_name.to_lowercase().contains(&search))
+                .collect::<Vec<(usize, &CellContentsInfo)>>();
+
+            rows.sort_by(|(_, a), (_, b)| {
+                let ord = match state.sort_key {
+                    CellSortKey::Name => a.cell_name.cmp(&b.cell_name),
+                    key => cell_sort_value(a, key)
+                        .cmp(&cell_sort_value(b, key))
+                        .then_with(|| a.cell_name.cmp(&b.cell_name)),
+                };
+                if state.descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+
+            ui.label(format!("{} of {} cells", rows.len(), cells.len()));
+
+            let columns = [
+                ("Name", CellSortKey::Name),
+                ("Elems", CellSortKey::Elems),
+                ("Insts", CellSortKey::Insts),
+                ("Annots", CellSortKey::Annotations),
+                ("Ports", CellSortKey::Ports),
+                ("Blockages", CellSortKey::Blockages),
+                ("BBox", CellSortKey::Area),
+            ];
+
+            let row_height = ui.text_style_height(&egui::TextStyle::Body);
+
+            egui::ScrollArea::vertical().show_rows(ui, row_height, rows.len(), |ui, range| {
+                egui::Grid::new("cell_browser_grid")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for (label, key) in columns {
+                            let text = if state.sort_key == key {
+                                format!("{label} {}", if state.descending { "⏷" } else { "⏶" })
+                            } else {
+                                label.to_owned()
+                            };
+                            if ui.selectable_label(state.sort_key == key, text).clicked() {
+                                if state.sort_key == key {
+                                    state.descending = !state.descending;
+                                } else {
+                                    state.sort_key = key;
+                                    state.descending = false;
+                                }
+                            }
+                        }
+                        ui.end_row();
+
+                        for (idx, info) in rows[range].iter() {
+                            let selected =
+                                state.clicked == Some(*idx) || vlsir_cell.index == Some(*idx);
+                            let response = ui
+                                .selectable_label(selected, info.cell_name.as_str())
+                                .on_hover_text("Double click to load");
+                            if response.clicked() {
+                                state.clicked = Some(*idx);
+                            }
+                            if response.double_clicked() {
+                                load = Some(*idx);
+                            }
+
+                            match info.layout.as_ref() {
+                                Some(layout) => {
+                                    ui.label(layout.elems.to_string());
+                                    ui.label(layout.insts.to_string());
+                                    ui.label(layout.annotations.to_string());
+                                }
+                                None => {
+                                    ui.label("-");
+                                    ui.label("-");
+                                    ui.label("-");
+                                }
+                            }
+
+                            match info.abstrakt.as_ref() {
+                                Some(abstrakt) => {
+                                    ui.label(abstrakt.ports.to_string());
+                                    ui.label(abstrakt.blockages.to_string());
+                                }
+                                None => {
+                                    ui.label("-");
+                                    ui.label("-");
+                                }
+                            }
+
+                            let bbox = info
+                                .layout
+                                .as_ref()
+                                .map(|l| &l.bbox)
+                                .or_else(|| info.abstrakt.as_ref().map(|a| &a.outline));
+                            ui.label(bbox.map(format_bbox).unwrap_or_else(|| "-".to_owned()));
+
+                            ui.end_row();
+                        }
+                    });
+            });
+        });
+
+    if let Some(idx) = load {
+        if dropdown_state.selected != idx || vlsir_cell.index != Some(idx) {
+            dropdown_state.selected = idx;
+        }
+    }
+}
+
+/// `ancestors` are the cells above `idx` in the tree, a cell instantiated by several
+/// cells is shown under each of them and opened and closed separately there.
+fn cell_tree_ui(
+    ui: &mut egui::Ui,
+    cells: &[CellContentsInfo],
+    ancestors: &[usize],
+    idx: usize,
+    loaded: Option<usize>,
+    state: &mut CellBrowserUIState,
+    load: &mut Option<usize>,
+) {
+    let info = &cells[idx];
+
+    let mut header = |ui: &mut egui::Ui| {
+        let selected = state.clicked == Some(idx) || loaded == Some(idx);
+        let response = ui
+            .selectable_label(selected, info.cell_name.as_str())
+            .on_hover_text("Double click to load");
+        if response.clicked() {
+            state.clicked = Some(idx);
+        }
+        if response.double_clicked() {
+            *load = Some(idx);
+        }
+    };
+
+    if info.children.is_empty() {
+        ui.horizontal(|ui| {
+            ui.add_space(ui.spacing().indent);
+            header(ui);
+        });
+    } else {
+        egui::collapsing_header::CollapsingState::load_with_default_open(
+            ui.ctx(),
+            ui.make_persistent_id(("cell", ancestors, idx)),
+            false,
+        )
+        .show_header(ui, header)
+        .body(|ui| {
+            let path = [ancestors, &[idx]].concat();
+            for child in info.children.iter() {
+                cell_tree_ui(ui, cells, &path, *child, loaded, state, load);
             }
         });
+    }
 }
 
 pub fn load_dropdown_selected_cell_system(
@@ -186,16 +965,17 @@ pub fn load_dropdown_selected_cell_system(
 
 pub fn layer_visibility_widget_system(
     mut egui_ctx: ResMut<EguiContext>,
-    layers: Res<Layers>,
+    mut layers: ResMut<Layers>,
     mut state: ResMut<LayersUIState>,
+    mut active_layer: ResMut<ActiveLayer>,
 ) {
     let mut temp = state.layers.clone();
 
     if temp.is_empty() {
         let mut layers = layers
             .iter()
-            .map(|(num, Layer { name, color })| (*num, name.clone(), *color))
-            .collect::<Vec<(u8, Option<String>, Color)>>();
+            .map(|(num, Layer { name, visible, .. })| (*num, name.clone(), *visible))
+            .collect::<Vec<(u8, Option<String>, bool)>>();
 
         layers.sort_by(|a, b| a.0.cmp(&b.0));
 
@@ -205,21 +985,87 @@ pub fn layer_visibility_widget_system(
             } else {
                 format!("{}", layer.0)
             };
-            temp.push((true, layer.0, label));
+            temp.push((layer.2, layer.0, label));
         }
     }
 
+    // edit copies so the layers are only marked changed, and every shape on them
+    // restyled, when a style was actually edited
+    let mut styles = temp
+        .iter()
+        .filter_map(|(_, num, _)| layers.get(num).map(|layer| (*num, layer.clone())))
+        .collect::<HashMap<u8, Layer>>();
+
     egui::Window::new("Layers")
         .resizable(true)
         .default_pos([5.0, 532.0])
         .show(egui_ctx.ctx_mut(), |ui| {
-            ui.vertical(|ui| {
-                for layer in temp.iter_mut() {
-                    ui.add(egui::Checkbox::new(&mut layer.0, &layer.2));
-                }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("layers_grid").striped(true).show(ui, |ui| {
+                    ui.label("")
+                        .on_hover_text("Active layer, new shapes are drawn on it");
+                    ui.label("Layer");
+                    ui.label("Colour");
+                    ui.label("Fill α");
+                    ui.label("Outline");
+                    ui.label("");
+                    ui.end_row();
+
+                    for (visible, num, label) in temp.iter_mut() {
+                        if ui.radio(**active_layer == Some(*num), "").clicked()
+                            && **active_layer != Some(*num)
+                        {
+                            **active_layer = Some(*num);
+                        }
+
+                        ui.add(egui::Checkbox::new(visible, label.as_str()));
+
+                        let style = match styles.get_mut(num) {
+                            Some(style) => style,
+                            None => {
+                                ui.end_row();
+                                continue;
+                            }
+                        };
+
+                        let mut srgb = [style.color.r(), style.color.g(), style.color.b()]
+                            .map(|c| (c * 255.0).round() as u8);
+                        if ui.color_edit_button_srgb(&mut srgb).changed() {
+                            style.color = Color::rgb_u8(srgb[0], srgb[1], srgb[2]);
+                        }
+
+                        ui.add_enabled(
+                            style.show_fill,
+                            egui::DragValue::new(&mut style.fill_alpha)
+                                .speed(0.01)
+                                .clamp_range(0.0..=1.0),
+                        );
+
+                        ui.add_enabled(
+                            style.show_outline,
+                            egui::DragValue::new(&mut style.outline_width)
+                                .speed(0.5)
+                                .clamp_range(0.0..=1000.0),
+                        )
+                        .on_hover_text("Outline width, paths are drawn at their own width");
+
+                        ui.horizontal(|ui| {
+                            ui.toggle_value(&mut style.show_fill, "fill");
+                            ui.toggle_value(&mut style.show_outline, "outline");
+                        });
+
+                        ui.end_row();
+                    }
+                });
             });
         });
 
+    for (num, style) in styles {
+        if layers.get(&num) != Some(&style) {
+            layers.insert(num, style);
+        }
+    }
+
     if state.layers != temp {
         state.layers = temp;
     }
@@ -285,7 +1131,29 @@ pub fn display_current_selection_info(
     rect_q: Query<&Rect>,
     poly_q: Query<&Poly>,
     path_q: Query<&Path>,
+    selected: SelectedShapes,
+    layer_state: Res<LayersUIState>,
+    mut inspector: ResMut<InspectorUIState>,
+    mut edit_ev: EventWriter<EditEvent>,
 ) {
+    // a single selected shape of the loaded cell can be edited, shapes of instances belong
+    // to other cells
+    let inspected = match selected.shapes() {
+        (shapes, 0) if shapes.len() == 1 => shapes.into_iter().next(),
+        _ => None,
+    };
+
+    // start over from the shape whenever it changes, e.g. when it is dragged or undone
+    if inspected != inspector.shape {
+        inspector.fields = inspected
+            .as_ref()
+            .map(|(_, shape)| ShapeFields::new(shape))
+            .unwrap_or_default();
+        inspector.shape = inspected;
+    }
+
+    let mut apply = None;
+
     // egui::Window::new("Layers")
     //     .resizable(true)
     //     .default_pos([5.0, 532.0])
@@ -301,7 +1169,12 @@ pub fn display_current_selection_info(
         .default_pos([5.0, 220.0])
         .show(egui_ctx.ctx_mut(), |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
-                if selected_q.is_empty() {
+                let InspectorUIState { shape, fields } = &mut *inspector;
+                if let Some((id, shape)) = shape {
+                    if let Some(edited) = shape_inspector_ui(ui, shape, fields, &layer_state) {
+                        apply = Some((*id, shape.clone(), edited));
+                    }
+                } else if selected_q.is_empty() {
                     ui.label(format!("No shape is currently selected"));
                 } else {
                     //     let mut layers = layers
@@ -362,6 +1235,474 @@ pub fn display_current_selection_info(
                 }
             })
         });
+
+    if let Some((id, before, after)) = apply {
+        edit_ev.send(EditEvent {
+            description: shape_count_description("Edit", 1),
+            action: AtomicAction::Modify {
+                shapes: vec![(id, before, after)],
+            },
+        });
+    }
+}
+
+/// Editable layer, net and coordinates of `shape`, returns the edited shape when the
+/// edits are applied.
+fn shape_inspector_ui(
+    ui: &mut egui::Ui,
+    shape: &IndexedShape,
+    fields: &mut ShapeFields,
+    layer_state: &LayersUIState,
+) -> Option<IndexedShape> {
+    let (kind, point_name) = match shape.geometry {
+        ShapeGeometry::Rect(_) => ("Rect", "Corner"),
+        ShapeGeometry::Poly(_) => ("Poly", "Vertex"),
+        ShapeGeometry::Path(_) => ("Path", "Point"),
+    };
+    let resizable = !matches!(shape.geometry, ShapeGeometry::Rect(_));
+    let mut removed = None;
+
+    ui.heading(kind);
+    egui::Grid::new("inspector_grid").show(ui, |ui| {
+        ui.label("Layer");
+        layer_combo_box(ui, "inspector_layer", &mut fields.layer, layer_state);
+        ui.end_row();
+        ui.label("Net");
+        ui.text_edit_singleline(&mut fields.net)
+            .on_hover_text("Leave empty for no net");
+        ui.end_row();
+        if let ShapeGeometry::Path(_) = shape.geometry {
+            ui.label("Width");
+            ui.add(egui::TextEdit::singleline(&mut fields.width).desired_width(60.0));
+            ui.end_row();
+        }
+        let can_remove = fields.points.len() > ShapeFields::min_points(shape);
+        for (i, [x, y]) in fields.points.iter_mut().enumerate() {
+            ui.label(format!("{point_name} {}", i + 1));
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(x).desired_width(60.0));
+                ui.add(egui::TextEdit::singleline(y).desired_width(60.0));
+                if resizable
+                    && ui
+                        .add_enabled(can_remove, egui::Button::new("-").small())
+                        .on_hover_text(format!("Remove {}", point_name.to_lowercase()))
+                        .clicked()
+                {
+                    removed = Some(i);
+                }
+            });
+            ui.end_row();
+        }
+    });
+
+    if let Some(i) = removed {
+        fields.points.remove(i);
+    }
+    if resizable
+        && ui
+            .small_button(format!("Add {}", point_name.to_lowercase()))
+            .clicked()
+    {
+        let last = fields.points.last().cloned().unwrap_or_default();
+        fields.points.push(last);
+    }
+
+    // checked as it is typed, so the shape can only be changed into a valid one
+    let edited = fields.to_shape(shape);
+    ui.add_space(5.0);
+    ui.horizontal(|ui| {
+        let changed = edited.as_ref().map_or(false, |edited| edited != shape);
+        let applied = ui
+            .add_enabled(changed, egui::Button::new("Apply"))
+            .clicked();
+        if ui.button("Revert").clicked() {
+            *fields = ShapeFields::new(shape);
+        }
+        match &edited {
+            Err(error) => {
+                ui.colored_label(egui::Color32::RED, error.as_str());
+                None
+            }
+            Ok(edited) => applied.then(|| edited.clone()),
+        }
+    })
+    .inner
+}
+
+/// Pick one of the loaded library's layers.
+fn layer_combo_box(
+    ui: &mut egui::Ui,
+    id_source: impl std::hash::Hash,
+    layer: &mut u8,
+    layer_state: &LayersUIState,
+) {
+    let label = |num: u8| {
+        layer_state
+            .layers
+            .iter()
+            .find(|(_, n, _)| *n == num)
+            .map_or_else(|| num.to_string(), |(_, _, label)| label.clone())
+    };
+
+    egui::ComboBox::from_id_source(id_source)
+        .selected_text(label(*layer))
+        .show_ui(ui, |ui| {
+            for (_, num, name) in layer_state.layers.iter() {
+                ui.selectable_value(layer, *num, name.as_str());
+            }
+        });
+}
+
+pub fn error_dialog_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    mut error_dialog_state: ResMut<ErrorDialogUIState>,
+) {
+    let mut dismissed = false;
+
+    if let Some((title, message)) = error_dialog_state.error.as_ref() {
+        egui::Window::new("Error")
+            .collapsible(false)
+            .resizable(true)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(egui_ctx.ctx_mut(), |ui| {
+                ui.heading(title);
+                ui.add_space(5.0);
+                ui.label(egui::RichText::new(message).monospace());
+                ui.add_space(5.0);
+                if ui.button("OK").clicked() {
+                    dismissed = true;
+                }
+            });
+    }
+
+    if dismissed {
+        error_dialog_state.error = None;
+    }
+}
+
+pub fn hierarchy_panel_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    instance_tree: Res<InstanceTree>,
+    mut hierarchy_event_writer: EventWriter<HierarchyEvent>,
+) {
+    let mut events = vec![];
+
+    egui::Window::new("Hierarchy")
+        .resizable(true)
+        .default_pos([1500.0, 32.0])
+        .show(egui_ctx.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                if instance_tree.roots.is_empty() {
+                    ui.label("This cell has no instances");
+                }
+                for root in instance_tree.roots.iter() {
+                    instance_tree_ui(ui, &instance_tree, *root, &mut events);
+                }
+            });
+        });
+
+    for ev in events {
+        hierarchy_event_writer.send(ev);
+    }
+}
+
+/// Every action in the undo/redo history, click one to undo or redo up to it.
+pub fn history_panel_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    history: Res<UndoRedoHistory>,
+    mut state: ResMut<HistoryUIState>,
+    mut undo_redo_event_writer: EventWriter<UndoRedoEvent>,
+) {
+    let mut jump_to = None;
+
+    egui::Window::new("History")
+        .open(&mut state.open)
+        .resizable(true)
+        .default_pos([1500.0, 532.0])
+        .show(egui_ctx.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                if ui
+                    .selectable_label(history.offset == 0, "Cell loaded")
+                    .clicked()
+                {
+                    jump_to = Some(0);
+                }
+
+                for (i, entry) in history.actions.iter().enumerate() {
+                    let offset = i + 1;
+                    // undone actions are greyed out until they are redone or replaced
+                    let text = if offset > history.offset {
+                        egui::RichText::new(&entry.description).weak()
+                    } else {
+                        egui::RichText::new(&entry.description)
+                    };
+                    if ui
+                        .selectable_label(history.offset == offset, text)
+                        .clicked()
+                    {
+                        jump_to = Some(offset);
+                    }
+                }
+            });
+        });
+
+    if let Some(offset) = jump_to {
+        if offset != history.offset {
+            undo_redo_event_writer.send(UndoRedoEvent::JumpTo(offset));
+        }
+    }
+}
+
+/// Move the selection by an exact offset or to an exact position, in database units.
+pub fn move_dialog_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    mut state: ResMut<MoveUIState>,
+    mut nudge_step: ResMut<NudgeStep>,
+    grid: Res<Grid>,
+    selected_q: Query<(), With<Selected>>,
+    mut move_selection_event_writer: EventWriter<MoveSelectionEvent>,
+) {
+    let MoveUIState {
+        open,
+        absolute,
+        offset,
+        point,
+    } = &mut *state;
+    let mut apply = false;
+    let mut units = None;
+    // edit a copy so the step is only marked changed when it changes
+    let mut step = **nudge_step;
+
+    egui::Window::new("Move")
+        .open(open)
+        .collapsible(false)
+        .resizable(false)
+        .default_pos([600.0, 200.0])
+        .show(egui_ctx.ctx_mut(), |ui| {
+            ui.radio_value(absolute, false, "By offset");
+            ui.radio_value(absolute, true, "Lower left corner to");
+            let v = if *absolute { point } else { offset };
+            egui::Grid::new("move_params").show(ui, |ui| {
+                for (axis, nm) in ["x", "y"].iter().zip(v.iter_mut()) {
+                    ui.label(*axis);
+                    ui.add(egui::DragValue::new(nm).suffix(" nm"));
+                    match grid.nm_to_units(*nm) {
+                        Some(units) => ui.label(format!("= {units} units")),
+                        None => ui.colored_label(
+                            egui::Color32::RED,
+                            format!("not a whole number of {} nm units", grid.nm_per_unit),
+                        ),
+                    };
+                    ui.end_row();
+                }
+            });
+            // only moved by whole database units
+            units = grid
+                .nm_to_units(v[0])
+                .zip(grid.nm_to_units(v[1]))
+                .map(|(x, y)| IVec2::new(x, y));
+            ui.add_space(5.0);
+            if ui
+                .add_enabled(
+                    units.is_some() && !selected_q.is_empty(),
+                    egui::Button::new("Move"),
+                )
+                .clicked()
+            {
+                apply = true;
+            }
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Arrow key step");
+                ui.add(egui::DragValue::new(&mut step).clamp_range(1..=10_000));
+                ui.label(format!(
+                    "× {} units ({} nm)",
+                    grid.step(),
+                    grid.step() as f64 * grid.nm_per_unit
+                ));
+            });
+        });
+
+    if step != **nudge_step {
+        **nudge_step = step;
+    }
+
+    if let (true, Some(units)) = (apply, units) {
+        move_selection_event_writer.send(if state.absolute {
+            MoveSelectionEvent::To(units)
+        } else {
+            MoveSelectionEvent::By(units)
+        });
+    }
+}
+
+/// Step and repeat the selection into columns and rows of copies.
+pub fn array_dialog_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    mut state: ResMut<ArrayUIState>,
+    selected_q: Query<(), With<Selected>>,
+    mut array_selection_event_writer: EventWriter<ArraySelectionEvent>,
+) {
+    let ArrayUIState { open, params } = &mut *state;
+    let mut apply = false;
+
+    egui::Window::new("Array")
+        .open(open)
+        .collapsible(false)
+        .resizable(false)
+        .default_pos([600.0, 200.0])
+        .show(egui_ctx.ctx_mut(), |ui| {
+            egui::Grid::new("array_params").show(ui, |ui| {
+                ui.label("Columns");
+                ui.add(egui::DragValue::new(&mut params.nx).clamp_range(1..=1000));
+                ui.end_row();
+                ui.label("Rows");
+                ui.add(egui::DragValue::new(&mut params.ny).clamp_range(1..=1000));
+                ui.end_row();
+                ui.label("Pitch x");
+                ui.add_enabled(
+                    !params.abut,
+                    egui::DragValue::new(&mut params.pitch.x).suffix(" units"),
+                );
+                ui.end_row();
+                ui.label("Pitch y");
+                ui.add_enabled(
+                    !params.abut,
+                    egui::DragValue::new(&mut params.pitch.y).suffix(" units"),
+                );
+                ui.end_row();
+            });
+            ui.checkbox(
+                &mut params.abut,
+                "Abut, the pitch is the size of the selection",
+            );
+            ui.add_space(5.0);
+            if ui
+                .add_enabled(!selected_q.is_empty(), egui::Button::new("Create array"))
+                .clicked()
+            {
+                apply = true;
+            }
+        });
+
+    if apply {
+        array_selection_event_writer.send(ArraySelectionEvent(state.params));
+    }
+}
+
+fn instance_tree_ui(
+    ui: &mut egui::Ui,
+    instance_tree: &InstanceTree,
+    id: InstanceId,
+    events: &mut Vec<HierarchyEvent>,
+) {
+    let node = instance_tree.get(id);
+
+    let mut header = |ui: &mut egui::Ui| {
+        ui.label(format!("{} ({})", node.inst_name, node.cell_name));
+
+        let mut visible = !instance_tree.hidden.contains(&id);
+        if ui
+            .checkbox(&mut visible, "")
+            .on_hover_text("Show/hide this instance")
+            .changed()
+        {
+            events.push(HierarchyEvent::SetHidden(id, !visible));
+        }
+
+        let mut highlighted = instance_tree.highlighted.contains(&id);
+        if ui.toggle_value(&mut highlighted, "highlight").changed() {
+            events.push(HierarchyEvent::SetHighlighted(id, highlighted));
+        }
+
+        if ui.small_button("select").clicked() {
+            events.push(HierarchyEvent::Select(id));
+        }
+
+        if instance_tree.collapsed.contains(&id) {
+            if ui.small_button("expand").clicked() {
+                events.push(HierarchyEvent::Expand(id));
+            }
+        } else if ui.small_button("collapse").clicked() {
+            events.push(HierarchyEvent::Collapse(id));
+        }
+    };
+
+    if node.children.is_empty() {
+        ui.horizontal(|ui| {
+            // line leaf instances up with the labels of the collapsible ones
+            ui.add_space(ui.spacing().indent);
+            header(ui);
+        });
+    } else {
+        egui::collapsing_header::CollapsingState::load_with_default_open(
+            ui.ctx(),
+            ui.make_persistent_id(("instance", id.0)),
+            false,
+        )
+        .show_header(ui, header)
+        .body(|ui| {
+            for child in node.children.iter() {
+                instance_tree_ui(ui, instance_tree, *child, events);
+            }
+        });
+    }
+}
+
+/// Placeholders narrower than this on screen are left unlabelled to keep the view readable.
+const MIN_LABELLED_PLACEHOLDER_WIDTH: f32 = 60.0;
+
+/// Label the placeholder outline of each collapsed instance with its instance and cell name.
+pub fn instance_placeholder_labels_system(
+    mut egui_ctx: ResMut<EguiContext>,
+    windows: Res<Windows>,
+    instance_tree: Res<InstanceTree>,
+    camera_q: Query<(&Transform, &Camera)>,
+) {
+    let (cam_t, cam) = match camera_q.get_single() {
+        Ok(cam) => cam,
+        Err(_) => return,
+    };
+
+    let window = windows.primary();
+    let window_size = Vec2::new(window.width(), window.height());
+
+    let world_to_ndc = cam.projection_matrix * cam_t.compute_matrix().inverse();
+    // egui's origin is the top left of the window, bevy's the bottom left
+    let world_to_screen = |x: i64, y: i64| {
+        let ndc = world_to_ndc.project_point3(Vec3::new(x as f32, y as f32, 0.0));
+        let screen = (ndc.truncate() + Vec2::ONE) / 2.0 * window_size;
+        egui::pos2(screen.x, window_size.y - screen.y)
+    };
+
+    let painter = egui_ctx
+        .ctx_mut()
+        .layer_painter(egui::LayerId::background());
+
+    for (_, node) in instance_tree.placeholders() {
+        let bbox = match node.bbox {
+            Some(bbox) => bbox,
+            None => continue,
+        };
+
+        let [x_min, _] = bbox.lower();
+        let [x_max, y_max] = bbox.upper();
+
+        let top_left = world_to_screen(x_min, y_max);
+        let top_right = world_to_screen(x_max, y_max);
+
+        if top_right.x - top_left.x < MIN_LABELLED_PLACEHOLDER_WIDTH {
+            continue;
+        }
+
+        painter.text(
+            top_left + egui::vec2(4.0, 4.0),
+            egui::Align2::LEFT_TOP,
+            format!("{} ({})", node.inst_name, node.cell_name),
+            egui::FontId::proportional(14.0),
+            egui::Color32::LIGHT_GRAY,
+        );
+    }
 }
 
 // figure out if cursor is hovering over UI or over bevy 'app world'